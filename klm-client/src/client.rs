@@ -0,0 +1,240 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::error::{ClientError, ClientResult};
+use crate::request::Request;
+use crate::response::{self, Capabilities, Event, KeyboardState, STATUS_EVENT};
+
+use klm_proto::frame::{decode_header, encode_header, FRAME_HEADER_SIZE, MAX_PAYLOAD_SIZE};
+use klm_proto::Mode;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+pub const DEFAULT_SOCKET_PATH: &str = "/var/run/klmd.sock";
+
+fn write_frame(stream: &mut UnixStream, request_id: u32, payload: &[u8]) -> ClientResult<()> {
+    if payload.len() > MAX_PAYLOAD_SIZE as usize {
+        return Err(ClientError::Protocol(format!("request of {} bytes is too big", payload.len())));
+    }
+    let mut frame = encode_header(request_id, payload.len() as u32);
+    frame.extend(payload);
+    stream.write_all(&frame)?;
+    Ok(())
+}
+
+//Reads response frame. Returns None if klmd closed connection
+//between frames.
+fn read_frame(stream: &mut UnixStream) -> ClientResult<Option<(u32, Vec<u8>)>> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    match stream.read_exact(&mut header) {
+        Ok(()) => {},
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(ClientError::Io(e)),
+    }
+    let header = decode_header(&header)
+        .ok_or_else(|| ClientError::Protocol("response does not start with frame magic".to_string()))?;
+    let mut payload = vec![0; header.length as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(Some((header.request_id, payload)))
+}
+
+//Connection to klmd. Requests are sent in v2 frames, so responses
+//are not limited in size and failures carry error details.
+pub struct Client {
+    stream: UnixStream,
+    next_request_id: u32,
+}
+
+impl Client {
+    pub fn connect() -> ClientResult<Client> {
+        Client::connect_to(DEFAULT_SOCKET_PATH)
+    }
+
+    pub fn connect_to<P: AsRef<Path>>(path: P) -> ClientResult<Client> {
+        Ok(Client::from_stream(UnixStream::connect(path)?))
+    }
+
+    pub fn from_stream(stream: UnixStream) -> Client {
+        Client {
+            stream,
+            next_request_id: 1,
+        }
+    }
+
+    //Sends request and waits for its response. Returns status and
+    //data of response.
+    fn exchange(&mut self, request: &Request) -> ClientResult<(u8, Vec<u8>)> {
+        if request.is_empty() {
+            return Err(ClientError::Protocol("request has no commands".to_string()));
+        }
+        let payload = request.encode()?;
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        write_frame(&mut self.stream, request_id, &payload)?;
+        let (response_id, payload) = read_frame(&mut self.stream)?
+            .ok_or_else(|| ClientError::Protocol("klmd closed connection".to_string()))?;
+        if response_id != request_id {
+            return Err(ClientError::Protocol(format!("response to request {} received instead of {}",
+                                                     response_id, request_id)));
+        }
+        let (status, data) = response::decode_payload(&payload)?;
+        Ok((status, data.to_vec()))
+    }
+
+    //Sends request and returns data of its response, which is
+    //empty unless request contained queries
+    pub fn send(&mut self, request: &Request) -> ClientResult<Vec<u8>> {
+        Ok(self.exchange(request)?.1)
+    }
+
+    pub fn modes(&mut self) -> ClientResult<Vec<Mode>> {
+        response::decode_modes(&self.send(&Request::new().modes())?)
+    }
+
+    pub fn capabilities(&mut self) -> ClientResult<Capabilities> {
+        response::decode_capabilities(&self.send(&Request::new().capabilities())?)
+    }
+
+    pub fn state(&mut self) -> ClientResult<KeyboardState> {
+        response::decode_state(&self.send(&Request::new().state())?)
+    }
+
+    pub fn profiles(&mut self) -> ClientResult<Vec<String>> {
+        response::decode_profiles(&self.send(&Request::new().profiles())?)
+    }
+
+    pub fn profile(&mut self, name: &str) -> ClientResult<KeyboardState> {
+        response::decode_state(&self.send(&Request::new().profile(name))?)
+    }
+
+    //Turns connection into stream of keyboard events
+    pub fn subscribe(mut self) -> ClientResult<Events> {
+        self.exchange(&Request::new().command(klm_proto::Command::Subscribe))?;
+        Ok(Events { stream: self.stream })
+    }
+}
+
+pub struct Events {
+    stream: UnixStream,
+}
+
+impl Iterator for Events {
+    type Item = ClientResult<Event>;
+
+    //Blocks until next event. Ends when klmd closes connection.
+    fn next(&mut self) -> Option<ClientResult<Event>> {
+        let payload = match read_frame(&mut self.stream) {
+            Ok(Some((_, payload))) => payload,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(response::decode_payload(&payload).and_then(|(status, data)| {
+            if status != STATUS_EVENT {
+                return Err(ClientError::Protocol(format!("expected event, got status {}", status)));
+            }
+            response::decode_event(data)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Client;
+    use crate::error::{ClientError, ErrorCode};
+    use crate::request::Request;
+    use crate::response::Event;
+    use klm_proto::frame::{encode_header, FRAME_HEADER_SIZE};
+    use klm_proto::{Color, Mode};
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    fn frame(request_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = encode_header(request_id, payload.len() as u32);
+        frame.extend(payload);
+        frame
+    }
+
+    //Fake klmd: reads requests and answers them with given payloads
+    fn serve(responses: Vec<Vec<u8>>) -> (Client, thread::JoinHandle<Vec<Vec<u8>>>) {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || {
+            let mut requests = vec![];
+            for response in responses {
+                let mut header = [0u8; FRAME_HEADER_SIZE];
+                server.read_exact(&mut header).unwrap();
+                let length = u32::from_be_bytes([header[9], header[10], header[11], header[12]]);
+                let mut request = header.to_vec();
+                let mut payload = vec![0; length as usize];
+                server.read_exact(&mut payload).unwrap();
+                request.extend(payload);
+                let request_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+                server.write_all(&frame(request_id, &response)).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+        (Client::from_stream(client), handle)
+    }
+
+    #[test]
+    fn request_is_sent_in_v2_frame() {
+        let (mut client, server) = serve(vec![vec![0x0], vec![0x0]]);
+        let data = client.send(&Request::new().set_color(Color::new(1, 2, 3)).mode(Mode::Steady)).unwrap();
+        assert!(data.is_empty());
+        client.send(&Request::new().toggle()).unwrap();
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0], frame(1, &[0x01, 1, 2, 3, 0x05, 0x01]));
+        assert_eq!(requests[1], frame(2, &[0x08]));
+    }
+
+    #[test]
+    fn queries_are_decoded() {
+        let (mut client, server) = serve(vec![vec![0x3, 1, 2], vec![0x3, 0x01, 1, 5, 0, 0, 1, 1, 2, 3]]);
+        assert_eq!(client.modes().unwrap(), vec![Mode::Steady, Mode::Breathing]);
+        let state = client.state().unwrap();
+        assert_eq!((state.mode, state.brightness), (Mode::Steady, 5));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn failure_is_returned_as_error() {
+        let (mut client, server) = serve(vec![vec![0x2, 0x04, 0, 0, 0, 8, 0, 0]]);
+        let e = client.send(&Request::new().add_color(Color::new(1, 1, 1))).unwrap_err();
+        assert!(matches!(e, ClientError::BadRequest { code: ErrorCode::TooManyColors, offset: 8, .. }));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn mismatched_response_is_protocol_error() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let mut client = Client::from_stream(client);
+        server.write_all(&frame(7, &[0x0])).unwrap();
+        assert!(matches!(client.send(&Request::new().toggle()), Err(ClientError::Protocol(_))));
+    }
+
+    #[test]
+    fn events_are_streamed_after_subscribe() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || {
+            let mut request = [0u8; FRAME_HEADER_SIZE + 1];
+            server.read_exact(&mut request).unwrap();
+            assert_eq!(request[FRAME_HEADER_SIZE], 0x0C);
+            server.write_all(&frame(1, &[0x0])).unwrap();
+            server.write_all(&frame(1, &[0x4, 0x2])).unwrap();
+        });
+        let mut events = Client::from_stream(client).subscribe().unwrap();
+        assert_eq!(events.next().unwrap().unwrap(), Event::DeviceRemoved);
+        assert!(events.next().is_none());
+        handle.join().unwrap();
+    }
+}
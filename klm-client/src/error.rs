@@ -0,0 +1,92 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use std::fmt;
+use std::io;
+
+//Error codes sent by klmd along with failed responses
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ErrorCode {
+    UnknownCommand,
+    Truncated,
+    BadArgument,
+    TooManyColors,
+    NotSupported,
+    DeviceFailure,
+    Internal,
+    BadFrame,
+    //Code added by newer klmd
+    Other(u8),
+}
+
+impl ErrorCode {
+    pub fn from_u8(byte: u8) -> ErrorCode {
+        match byte {
+            0x01 => ErrorCode::UnknownCommand,
+            0x02 => ErrorCode::Truncated,
+            0x03 => ErrorCode::BadArgument,
+            0x04 => ErrorCode::TooManyColors,
+            0x05 => ErrorCode::NotSupported,
+            0x06 => ErrorCode::DeviceFailure,
+            0x07 => ErrorCode::Internal,
+            0x08 => ErrorCode::BadFrame,
+            _ => ErrorCode::Other(byte),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ErrorCode::UnknownCommand => "unknown_command",
+            ErrorCode::Truncated => "truncated",
+            ErrorCode::BadArgument => "bad_argument",
+            ErrorCode::TooManyColors => "too_many_colors",
+            ErrorCode::NotSupported => "not_supported",
+            ErrorCode::DeviceFailure => "device_failure",
+            ErrorCode::Internal => "internal",
+            ErrorCode::BadFrame => "bad_frame",
+            ErrorCode::Other(_) => "other",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    //klmd could not apply a valid request
+    Failed { code: ErrorCode, offset: u32, message: String },
+    //klmd rejected request as malformed or unsupported by keyboard
+    BadRequest { code: ErrorCode, offset: u32, message: String },
+    //Response does not follow the protocol
+    Protocol(String),
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "I/O error: {}", e),
+            ClientError::Failed { code, offset, message } =>
+                write!(f, "request failed: {:?} at {}: {}", code, offset, message),
+            ClientError::BadRequest { code, offset, message } =>
+                write!(f, "bad request: {:?} at {}: {}", code, offset, message),
+            ClientError::Protocol(message) => write!(f, "protocol error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> ClientError {
+        ClientError::Io(e)
+    }
+}
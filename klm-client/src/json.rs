@@ -0,0 +1,98 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+//JSON form of klmd responses, as printed by klmctl and sent
+//by JSON socket of klmd
+
+use crate::names::{format_color, mode_name, mode_names};
+use crate::response::{Capabilities, Event, KeyboardState, Layout};
+
+use klm_proto::Mode;
+use serde_json::{json, Value};
+
+pub fn modes_json(modes: &[Mode]) -> Value {
+    json!(mode_names(modes))
+}
+
+fn layout_json(layout: &Layout) -> Value {
+    match *layout {
+        Layout::Zones(zones) => json!({ "type": "zones", "zones": zones }),
+        Layout::PerKey { rows, columns } => json!({ "type": "per-key", "rows": rows, "columns": columns }),
+    }
+}
+
+pub fn capabilities_json(capabilities: &Capabilities) -> Value {
+    json!({
+        "name": capabilities.name,
+        "vendor_id": format!("{:04x}", capabilities.vendor_id),
+        "product_id": format!("{:04x}", capabilities.product_id),
+        "modes": mode_names(&capabilities.modes),
+        "brightness": [capabilities.brightness_range.0, capabilities.brightness_range.1],
+        "speed": [capabilities.speed_range.0, capabilities.speed_range.1],
+        "max_colors": capabilities.max_colors,
+        "can_power_on": capabilities.can_power_on,
+        "layout": layout_json(&capabilities.layout),
+    })
+}
+
+pub fn state_json(state: &KeyboardState) -> Value {
+    json!({
+        "mode": mode_name(state.mode),
+        "power": state.power,
+        "brightness": state.brightness,
+        "speed": state.speed,
+        "sync_locked": state.sync_locked,
+        "colors": state.colors.iter().map(format_color).collect::<Vec<String>>(),
+    })
+}
+
+pub fn profiles_json(names: &[String]) -> Value {
+    json!(names)
+}
+
+pub fn event_json(event: &Event) -> Value {
+    match event {
+        Event::StateChanged(state) => json!({ "event": "state", "state": state_json(state) }),
+        Event::DeviceConnected(capabilities) =>
+            json!({ "event": "connected", "capabilities": capabilities_json(capabilities) }),
+        Event::DeviceRemoved => json!({ "event": "removed" }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{event_json, state_json};
+    use crate::response::{Event, KeyboardState};
+    use klm_proto::{Color, Mode};
+
+    fn state() -> KeyboardState {
+        KeyboardState {
+            mode: Mode::Breathing,
+            power: true,
+            brightness: 6,
+            speed: 1,
+            sync_locked: false,
+            colors: vec![Color::new(255, 136, 0), Color::new(0, 0, 1)],
+        }
+    }
+
+    #[test]
+    fn state_is_converted() {
+        assert_eq!(state_json(&state()).to_string(),
+                   r##"{"brightness":6,"colors":["#ff8800","#000001"],"mode":"breathing","power":true,"speed":1,"sync_locked":false}"##);
+    }
+
+    #[test]
+    fn events_are_converted() {
+        assert_eq!(event_json(&Event::DeviceRemoved).to_string(), r#"{"event":"removed"}"#);
+        assert_eq!(event_json(&Event::StateChanged(state()))["state"]["mode"], "breathing");
+    }
+}
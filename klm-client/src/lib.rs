@@ -0,0 +1,26 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+//Client of klmd. Requests are built with Request and sent
+//over Client, responses are decoded into types of response module.
+
+pub mod client;
+pub mod error;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod request;
+pub mod response;
+
+pub use client::{Client, Events, DEFAULT_SOCKET_PATH};
+pub use error::{ClientError, ClientResult, ErrorCode};
+pub use klm_proto::{names, Color, Command, Mode};
+pub use request::Request;
+pub use response::{Capabilities, Event, KeyboardState, Layout};
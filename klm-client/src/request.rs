@@ -0,0 +1,147 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::error::{ClientError, ClientResult};
+use klm_proto::{Color, Command, Mode};
+
+//Builds list of commands applied by klmd as one transaction:
+//either all of them take effect or none does.
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct Request {
+    commands: Vec<Command>,
+}
+
+impl Request {
+    pub fn new() -> Request {
+        Request::default()
+    }
+
+    pub fn command(mut self, command: Command) -> Request {
+        self.commands.push(command);
+        self
+    }
+
+    //Replaces stored colors with given ones
+    pub fn colors(self, colors: &[Color]) -> Request {
+        self.command(Command::Colors(colors.to_vec()))
+    }
+
+    //Sets primary color, resetting stored colors
+    pub fn set_color(self, color: Color) -> Request {
+        self.command(Command::SetColor(color))
+    }
+
+    pub fn add_color(self, color: Color) -> Request {
+        self.command(Command::AddColor(color))
+    }
+
+    pub fn brightness(self, brightness: u8) -> Request {
+        self.command(Command::Brightness(brightness))
+    }
+
+    pub fn speed(self, speed: u8) -> Request {
+        self.command(Command::Speed(speed))
+    }
+
+    pub fn mode(self, mode: Mode) -> Request {
+        self.command(Command::Mode(mode))
+    }
+
+    pub fn power(self, power: bool) -> Request {
+        self.command(Command::Power(power))
+    }
+
+    pub fn toggle(self) -> Request {
+        self.command(Command::Toggle)
+    }
+
+    pub fn modes(self) -> Request {
+        self.command(Command::RequestModes)
+    }
+
+    pub fn capabilities(self) -> Request {
+        self.command(Command::RequestCapabilities)
+    }
+
+    pub fn state(self) -> Request {
+        self.command(Command::RequestState)
+    }
+
+    //Saves state keyboard has at this point of request as named profile
+    pub fn save_profile(self, name: &str) -> Request {
+        self.command(Command::SaveProfile(name.to_string()))
+    }
+
+    //Applies mode, colors, brightness and speed stored in profile
+    pub fn apply_profile(self, name: &str) -> Request {
+        self.command(Command::ApplyProfile(name.to_string()))
+    }
+
+    pub fn delete_profile(self, name: &str) -> Request {
+        self.command(Command::DeleteProfile(name.to_string()))
+    }
+
+    pub fn profiles(self) -> Request {
+        self.command(Command::RequestProfiles)
+    }
+
+    //State stored in profile, power included
+    pub fn profile(self, name: &str) -> Request {
+        self.command(Command::RequestProfile(name.to_string()))
+    }
+
+    //Stores given settings as profile, keyboard is left as it is
+    pub fn write_profile(self, name: &str, mode: Mode, brightness: u8, speed: u8, colors: &[Color]) -> Request {
+        self.command(Command::WriteProfile {
+            name: name.to_string(),
+            mode,
+            brightness,
+            speed,
+            colors: colors.to_vec(),
+        })
+    }
+
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    //Fails if a command does not fit wire format, e.g. has over 255 colors
+    pub fn encode(&self) -> ClientResult<Vec<u8>> {
+        klm_proto::encode(&self.commands).map_err(|e| ClientError::Protocol(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Request;
+    use klm_proto::{Color, Mode};
+
+    #[test]
+    fn builder_encodes_commands_in_order() {
+        let request = Request::new()
+            .set_color(Color::new(1, 2, 3))
+            .add_color(Color::new(4, 5, 6))
+            .brightness(5)
+            .speed(1)
+            .mode(Mode::Breathing)
+            .power(true);
+        assert_eq!(request.encode().unwrap(), vec![0x01, 1, 2, 3, 0x02, 4, 5, 6, 0x03, 5, 0x04, 1, 0x05, 0x02, 0x07, 0x01]);
+        assert_eq!(Request::new().colors(&[Color::new(7, 8, 9)]).toggle().modes().encode().unwrap(),
+                   vec![0x00, 1, 7, 8, 9, 0x08, 0x09]);
+        assert_eq!(Request::new().apply_profile("a").delete_profile("b").profiles().encode().unwrap(),
+                   vec![0x0E, 1, b'a', 0x0F, 1, b'b', 0x10]);
+        assert!(Request::new().colors(&vec![Color::new(1, 2, 3); 256]).encode().is_err());
+    }
+}
@@ -0,0 +1,290 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::error::{ClientError, ClientResult, ErrorCode};
+use klm_proto::{Color, Mode};
+
+pub const STATUS_OK: u8 = 0x0;
+pub const STATUS_ERROR: u8 = 0x1;
+pub const STATUS_BAD_REQUEST: u8 = 0x2;
+pub const STATUS_DATA: u8 = 0x3;
+pub const STATUS_EVENT: u8 = 0x4;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Layout {
+    Zones(u8),
+    PerKey { rows: u8, columns: u8 },
+}
+
+//Keyboard description and limits of values accepted by its driver
+#[derive(PartialEq, Clone, Debug)]
+pub struct Capabilities {
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub modes: Vec<Mode>,
+    pub brightness_range: (u8, u8),
+    pub speed_range: (u8, u8),
+    pub max_colors: u8,
+    pub can_power_on: bool,
+    pub layout: Layout,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct KeyboardState {
+    pub mode: Mode,
+    pub power: bool,
+    pub brightness: u8,
+    pub speed: u8,
+    pub sync_locked: bool,
+    pub colors: Vec<Color>,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum Event {
+    StateChanged(KeyboardState),
+    DeviceConnected(Capabilities),
+    DeviceRemoved,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    ptr: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, ptr: 0 }
+    }
+
+    fn read(&mut self, size: usize, what: &str) -> ClientResult<&'a [u8]> {
+        if self.data.len() - self.ptr < size {
+            return Err(ClientError::Protocol(format!("{} is truncated", what)));
+        }
+        let bytes = &self.data[self.ptr..self.ptr + size];
+        self.ptr += size;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self, what: &str) -> ClientResult<u8> {
+        Ok(self.read(1, what)?[0])
+    }
+
+    fn read_u16(&mut self, what: &str) -> ClientResult<u16> {
+        let bytes = self.read(2, what)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self, what: &str) -> ClientResult<u32> {
+        let bytes = self.read(4, what)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_mode(&mut self) -> ClientResult<Mode> {
+        let byte = self.read_u8("mode")?;
+        Mode::from_u8(byte).ok_or_else(|| ClientError::Protocol(format!("unknown mode {}", byte)))
+    }
+
+    fn read_color(&mut self) -> ClientResult<Color> {
+        let color = self.read(3, "color")?;
+        Ok(Color::new(color[0], color[1], color[2]))
+    }
+
+    fn finish(&self, what: &str) -> ClientResult<()> {
+        if self.ptr != self.data.len() {
+            return Err(ClientError::Protocol(format!("{} has {} unexpected trailing bytes", what,
+                                                     self.data.len() - self.ptr)));
+        }
+        Ok(())
+    }
+}
+
+pub fn decode_modes(data: &[u8]) -> ClientResult<Vec<Mode>> {
+    let mut reader = Reader::new(data);
+    let mut modes = vec![];
+    while reader.ptr < data.len() {
+        modes.push(reader.read_mode()?);
+    }
+    Ok(modes)
+}
+
+fn read_capabilities(reader: &mut Reader) -> ClientResult<Capabilities> {
+    let name_len = reader.read_u8("name length")? as usize;
+    let name = String::from_utf8_lossy(reader.read(name_len, "name")?).to_string();
+    let vendor_id = reader.read_u16("vendor id")?;
+    let product_id = reader.read_u16("product id")?;
+    let n_modes = reader.read_u8("number of modes")?;
+    let mut modes = vec![];
+    for _ in 0..n_modes {
+        modes.push(reader.read_mode()?);
+    }
+    let limits = reader.read(6, "capabilities")?;
+    let layout = reader.read(3, "layout")?;
+    let layout = match layout[0] {
+        0x0 => Layout::Zones(layout[1]),
+        0x1 => Layout::PerKey { rows: layout[1], columns: layout[2] },
+        kind => return Err(ClientError::Protocol(format!("unknown layout {}", kind))),
+    };
+    Ok(Capabilities {
+        name,
+        vendor_id,
+        product_id,
+        modes,
+        brightness_range: (limits[0], limits[1]),
+        speed_range: (limits[2], limits[3]),
+        max_colors: limits[4],
+        can_power_on: limits[5] != 0,
+        layout,
+    })
+}
+
+fn read_state(reader: &mut Reader) -> ClientResult<KeyboardState> {
+    let mode = reader.read_mode()?;
+    let fields = reader.read(5, "state")?;
+    let mut colors = vec![];
+    for _ in 0..fields[4] {
+        colors.push(reader.read_color()?);
+    }
+    Ok(KeyboardState {
+        mode,
+        power: fields[0] != 0,
+        brightness: fields[1],
+        speed: fields[2],
+        sync_locked: fields[3] != 0,
+        colors,
+    })
+}
+
+//Names of profiles stored by klmd
+pub fn decode_profiles(data: &[u8]) -> ClientResult<Vec<String>> {
+    let mut reader = Reader::new(data);
+    let count = reader.read_u8("number of profiles")?;
+    let mut names = vec![];
+    for _ in 0..count {
+        let length = reader.read_u8("profile name length")? as usize;
+        names.push(String::from_utf8_lossy(reader.read(length, "profile name")?).to_string());
+    }
+    reader.finish("profiles")?;
+    Ok(names)
+}
+
+pub fn decode_capabilities(data: &[u8]) -> ClientResult<Capabilities> {
+    let mut reader = Reader::new(data);
+    let capabilities = read_capabilities(&mut reader)?;
+    reader.finish("capabilities")?;
+    Ok(capabilities)
+}
+
+pub fn decode_state(data: &[u8]) -> ClientResult<KeyboardState> {
+    let mut reader = Reader::new(data);
+    let state = read_state(&mut reader)?;
+    reader.finish("state")?;
+    Ok(state)
+}
+
+pub fn decode_event(data: &[u8]) -> ClientResult<Event> {
+    let mut reader = Reader::new(data);
+    let event = match reader.read_u8("event type")? {
+        0x0 => Event::StateChanged(read_state(&mut reader)?),
+        0x1 => Event::DeviceConnected(read_capabilities(&mut reader)?),
+        0x2 => Event::DeviceRemoved,
+        kind => return Err(ClientError::Protocol(format!("unknown event {}", kind))),
+    };
+    reader.finish("event")?;
+    Ok(event)
+}
+
+//Turns payload of response frame into its data, or error if
+//klmd failed to serve request
+pub fn decode_payload(payload: &[u8]) -> ClientResult<(u8, &[u8])> {
+    let mut reader = Reader::new(payload);
+    let status = reader.read_u8("status")?;
+    let data = &payload[1..];
+    match status {
+        STATUS_OK | STATUS_DATA | STATUS_EVENT => Ok((status, data)),
+        STATUS_ERROR | STATUS_BAD_REQUEST => {
+            //Error details are optional, older versions send only status
+            let (code, offset, message) = if data.is_empty() {
+                (ErrorCode::Other(0), 0, String::new())
+            } else {
+                let code = ErrorCode::from_u8(reader.read_u8("error code")?);
+                let offset = reader.read_u32("error offset")?;
+                let length = reader.read_u16("error message length")? as usize;
+                let message = String::from_utf8_lossy(reader.read(length, "error message")?).to_string();
+                (code, offset, message)
+            };
+            if status == STATUS_ERROR {
+                Err(ClientError::Failed { code, offset, message })
+            } else {
+                Err(ClientError::BadRequest { code, offset, message })
+            }
+        },
+        _ => Err(ClientError::Protocol(format!("unknown status {}", status))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_capabilities, decode_event, decode_modes, decode_payload, decode_profiles, decode_state, Event,
+                Layout};
+    use crate::error::{ClientError, ErrorCode};
+    use klm_proto::{Color, Mode};
+
+    //Capabilities of MS1563 as sent by klmd
+    const MS1563_CAPABILITIES: [u8; 24] = [6, b'M', b'S', b'1', b'5', b'6', b'3', 0x14, 0x62, 0x15, 0x63,
+                                           3, 1, 2, 3, 0, 10, 0, 2, 7, 0, 0, 1, 0];
+
+    #[test]
+    fn capabilities_are_decoded() {
+        let capabilities = decode_capabilities(&MS1563_CAPABILITIES).unwrap();
+        assert_eq!(capabilities.name, "MS1563");
+        assert_eq!((capabilities.vendor_id, capabilities.product_id), (0x1462, 0x1563));
+        assert_eq!(capabilities.modes, vec![Mode::Steady, Mode::Breathing, Mode::ColorShift]);
+        assert_eq!((capabilities.brightness_range, capabilities.speed_range), ((0, 10), (0, 2)));
+        assert_eq!(capabilities.max_colors, 7);
+        assert!(!capabilities.can_power_on);
+        assert_eq!(capabilities.layout, Layout::Zones(1));
+        assert!(decode_capabilities(&MS1563_CAPABILITIES[..20]).is_err());
+    }
+
+    #[test]
+    fn state_and_modes_are_decoded() {
+        let state = decode_state(&[0x02, 1, 7, 2, 0, 2, 1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(state.mode, Mode::Breathing);
+        assert!(state.power && !state.sync_locked);
+        assert_eq!((state.brightness, state.speed), (7, 2));
+        assert_eq!(state.colors, vec![Color::new(1, 2, 3), Color::new(4, 5, 6)]);
+        assert!(decode_state(&[0x02, 1, 7, 2, 0, 2, 1, 2, 3]).is_err());
+        assert_eq!(decode_modes(&[1, 3]).unwrap(), vec![Mode::Steady, Mode::ColorShift]);
+        assert_eq!(decode_profiles(&[2, 1, b'a', 2, b'b', b'c']).unwrap(), vec!["a", "bc"]);
+        assert!(decode_profiles(&[2, 1, b'a']).is_err());
+    }
+
+    #[test]
+    fn events_are_decoded() {
+        assert_eq!(decode_event(&[0x2]).unwrap(), Event::DeviceRemoved);
+        assert!(matches!(decode_event(&[0x0, 0x00, 0, 0, 0, 0, 1, 9, 9, 9]).unwrap(),
+                         Event::StateChanged(state) if state.colors == vec![Color::new(9, 9, 9)]));
+        let mut connected = vec![0x1];
+        connected.extend(MS1563_CAPABILITIES);
+        assert!(matches!(decode_event(&connected).unwrap(), Event::DeviceConnected(_)));
+    }
+
+    #[test]
+    fn error_details_are_decoded() {
+        let e = decode_payload(&[0x2, 0x03, 0, 0, 0, 4, 0, 3, b'b', b'a', b'd']).unwrap_err();
+        assert!(matches!(e, ClientError::BadRequest { code: ErrorCode::BadArgument, offset: 4, message }
+                         if message == "bad"));
+        let e = decode_payload(&[0x1]).unwrap_err();
+        assert!(matches!(e, ClientError::Failed { .. }));
+        assert_eq!(decode_payload(&[0x3, 1, 2]).unwrap(), (0x3, &[1u8, 2][..]));
+    }
+}
@@ -0,0 +1,422 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::error::{DecodeError, DecodeErrorKind, EncodeError};
+
+pub const CMD_COLORS: u8 = 0x00;
+pub const CMD_SET_COLOR: u8 = 0x01;
+pub const CMD_ADD_COLOR: u8 = 0x02;
+pub const CMD_BRIGHTNESS: u8 = 0x03;
+pub const CMD_SPEED: u8 = 0x04;
+pub const CMD_MODE: u8 = 0x05;
+pub const CMD_SYNC_STATE: u8 = 0x06;
+pub const CMD_POWER: u8 = 0x07;
+pub const CMD_TOGGLE: u8 = 0x08;
+pub const CMD_REQ_MODES: u8 = 0x09;
+pub const CMD_REQ_CAPABILITIES: u8 = 0x0A;
+pub const CMD_REQ_STATE: u8 = 0x0B;
+pub const CMD_SUBSCRIBE: u8 = 0x0C;
+pub const CMD_SAVE_PROFILE: u8 = 0x0D;
+pub const CMD_APPLY_PROFILE: u8 = 0x0E;
+pub const CMD_DELETE_PROFILE: u8 = 0x0F;
+pub const CMD_REQ_PROFILES: u8 = 0x10;
+pub const CMD_REQ_PROFILE: u8 = 0x11;
+pub const CMD_WRITE_PROFILE: u8 = 0x12;
+
+//Longest profile name accepted by klmd
+pub const MAX_PROFILE_NAME: usize = 32;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+//Profile names become file names, so they are limited to
+//letters, digits, '-' and '_'
+pub fn is_valid_profile_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_PROFILE_NAME &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl Color {
+    pub fn new(r: u8, g: u8, b: u8) -> Color {
+        Color { r, g, b }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Mode {
+    Off,
+    Steady,
+    Breathing,
+    ColorShift,
+}
+
+impl Mode {
+    pub fn from_u8(byte: u8) -> Option<Mode> {
+        match byte {
+            0x0 => Some(Mode::Off),
+            0x1 => Some(Mode::Steady),
+            0x2 => Some(Mode::Breathing),
+            0x3 => Some(Mode::ColorShift),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            Mode::Off => 0x0,
+            Mode::Steady => 0x1,
+            Mode::Breathing => 0x2,
+            Mode::ColorShift => 0x3,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum Command {
+    //Replaces stored colors, at least one color is required
+    Colors(Vec<Color>),
+    SetColor(Color),
+    AddColor(Color),
+    Brightness(u8),
+    Speed(u8),
+    Mode(Mode),
+    SyncState(bool),
+    Power(bool),
+    Toggle,
+    RequestModes,
+    RequestCapabilities,
+    RequestState,
+    Subscribe,
+    //Profiles are named copies of keyboard state stored by klmd
+    SaveProfile(String),
+    ApplyProfile(String),
+    DeleteProfile(String),
+    RequestProfiles,
+    //State stored in profile, in the form of state response
+    RequestProfile(String),
+    //Stores given settings as profile without applying them to keyboard
+    WriteProfile { name: String, mode: Mode, brightness: u8, speed: u8, colors: Vec<Color> },
+}
+
+struct Reader<'a> {
+    buffer: &'a [u8],
+    start: usize,
+    ptr: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read(&mut self, size: usize, expected: &str) -> Result<&'a [u8], DecodeError> {
+        if self.buffer.len() - self.ptr < size {
+            return Err(DecodeError::truncated(self.start, expected));
+        }
+        let bytes = &self.buffer[self.ptr..self.ptr + size];
+        self.ptr += size;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self, expected: &str) -> Result<u8, DecodeError> {
+        Ok(self.read(1, expected)?[0])
+    }
+
+    fn read_color(&mut self) -> Result<Color, DecodeError> {
+        let color = self.read(3, "color specification")?;
+        Ok(Color::new(color[0], color[1], color[2]))
+    }
+
+    fn read_mode(&mut self) -> Result<Mode, DecodeError> {
+        let byte = self.read_u8("mode specification")?;
+        Mode::from_u8(byte).ok_or_else(|| DecodeError::new(DecodeErrorKind::BadArgument, self.start,
+                                                           &format!("bad mode specifier {}", byte)))
+    }
+
+    //Color vector is prefixed with number of colors, at least one is required
+    fn read_colors(&mut self) -> Result<Vec<Color>, DecodeError> {
+        let n_colors = self.read_u8("number of colors")?;
+        if n_colors == 0 {
+            return Err(DecodeError::new(DecodeErrorKind::BadArgument, self.start,
+                                        "ambiguous request: set color array to size of 0 colors"));
+        }
+        let mut colors = Vec::with_capacity(n_colors as usize);
+        for _ in 0..n_colors {
+            colors.push(self.read_color()?);
+        }
+        Ok(colors)
+    }
+
+    //Strings are prefixed with their length
+    fn read_string(&mut self, expected: &str) -> Result<String, DecodeError> {
+        let length = self.read_u8(expected)? as usize;
+        let bytes = self.read(length, expected)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::new(DecodeErrorKind::BadArgument, self.start,
+                                                                        &format!("{} is not UTF-8", expected)))
+    }
+}
+
+//Decodes command starting at offset. Returns command and
+//offset of the next one.
+pub fn decode_command(buffer: &[u8], offset: usize) -> Result<(Command, usize), DecodeError> {
+    let mut reader = Reader { buffer, start: offset, ptr: offset };
+    let cmd = reader.read_u8("command")?;
+    let command = match cmd {
+        CMD_COLORS => Command::Colors(reader.read_colors()?),
+        CMD_SET_COLOR => Command::SetColor(reader.read_color()?),
+        CMD_ADD_COLOR => Command::AddColor(reader.read_color()?),
+        CMD_BRIGHTNESS => Command::Brightness(reader.read_u8("brightness specification")?),
+        CMD_SPEED => Command::Speed(reader.read_u8("speed specification")?),
+        CMD_MODE => Command::Mode(reader.read_mode()?),
+        CMD_SYNC_STATE => Command::SyncState(reader.read_u8("lock specification")? != 0),
+        CMD_POWER => Command::Power(reader.read_u8("power specification")? != 0),
+        CMD_TOGGLE => Command::Toggle,
+        CMD_REQ_MODES => Command::RequestModes,
+        CMD_REQ_CAPABILITIES => Command::RequestCapabilities,
+        CMD_REQ_STATE => Command::RequestState,
+        CMD_SUBSCRIBE => Command::Subscribe,
+        CMD_SAVE_PROFILE => Command::SaveProfile(reader.read_string("profile name")?),
+        CMD_APPLY_PROFILE => Command::ApplyProfile(reader.read_string("profile name")?),
+        CMD_DELETE_PROFILE => Command::DeleteProfile(reader.read_string("profile name")?),
+        CMD_REQ_PROFILES => Command::RequestProfiles,
+        CMD_REQ_PROFILE => Command::RequestProfile(reader.read_string("profile name")?),
+        CMD_WRITE_PROFILE => Command::WriteProfile {
+            name: reader.read_string("profile name")?,
+            mode: reader.read_mode()?,
+            brightness: reader.read_u8("brightness specification")?,
+            speed: reader.read_u8("speed specification")?,
+            colors: reader.read_colors()?,
+        },
+        _ => return Err(DecodeError::new(DecodeErrorKind::UnknownCommand, offset,
+                                         &format!("unknown command {}", cmd))),
+    };
+    Ok((command, reader.ptr))
+}
+
+//Decodes whole request, failing if any of its commands is malformed
+pub fn decode(buffer: &[u8]) -> Result<Vec<Command>, DecodeError> {
+    let mut commands = vec![];
+    let mut offset = 0;
+    while offset < buffer.len() {
+        let (command, next) = decode_command(buffer, offset)?;
+        commands.push(command);
+        offset = next;
+    }
+    Ok(commands)
+}
+
+//Strings are prefixed with their length in bytes, so at most 255 bytes fit
+fn encode_string(buffer: &mut Vec<u8>, value: &str) -> Result<(), EncodeError> {
+    let length = u8::try_from(value.len())
+        .map_err(|_| EncodeError::new(&format!("string of {} bytes is longer than 255", value.len())))?;
+    buffer.push(length);
+    buffer.extend(value.as_bytes());
+    Ok(())
+}
+
+fn encode_colors(buffer: &mut Vec<u8>, colors: &[Color]) -> Result<(), EncodeError> {
+    let count = u8::try_from(colors.len())
+        .map_err(|_| EncodeError::new(&format!("{} colors given, at most 255 fit", colors.len())))?;
+    buffer.push(count);
+    for color in colors {
+        buffer.extend([color.r, color.g, color.b]);
+    }
+    Ok(())
+}
+
+impl Command {
+    //Buffer is left untouched if command can not be encoded
+    pub fn encode_to(&self, buffer: &mut Vec<u8>) -> Result<(), EncodeError> {
+        let start = buffer.len();
+        let result = self.encode_unchecked(buffer);
+        if result.is_err() {
+            buffer.truncate(start);
+        }
+        result
+    }
+
+    fn encode_unchecked(&self, buffer: &mut Vec<u8>) -> Result<(), EncodeError> {
+        match self {
+            Command::Colors(colors) => {
+                buffer.push(CMD_COLORS);
+                encode_colors(buffer, colors)?;
+            },
+            Command::SetColor(color) => buffer.extend([CMD_SET_COLOR, color.r, color.g, color.b]),
+            Command::AddColor(color) => buffer.extend([CMD_ADD_COLOR, color.r, color.g, color.b]),
+            Command::Brightness(brightness) => buffer.extend([CMD_BRIGHTNESS, *brightness]),
+            Command::Speed(speed) => buffer.extend([CMD_SPEED, *speed]),
+            Command::Mode(mode) => buffer.extend([CMD_MODE, mode.to_u8()]),
+            Command::SyncState(locked) => buffer.extend([CMD_SYNC_STATE, *locked as u8]),
+            Command::Power(power) => buffer.extend([CMD_POWER, *power as u8]),
+            Command::Toggle => buffer.push(CMD_TOGGLE),
+            Command::RequestModes => buffer.push(CMD_REQ_MODES),
+            Command::RequestCapabilities => buffer.push(CMD_REQ_CAPABILITIES),
+            Command::RequestState => buffer.push(CMD_REQ_STATE),
+            Command::Subscribe => buffer.push(CMD_SUBSCRIBE),
+            Command::SaveProfile(name) => {
+                buffer.push(CMD_SAVE_PROFILE);
+                encode_string(buffer, name)?;
+            },
+            Command::ApplyProfile(name) => {
+                buffer.push(CMD_APPLY_PROFILE);
+                encode_string(buffer, name)?;
+            },
+            Command::DeleteProfile(name) => {
+                buffer.push(CMD_DELETE_PROFILE);
+                encode_string(buffer, name)?;
+            },
+            Command::RequestProfiles => buffer.push(CMD_REQ_PROFILES),
+            Command::RequestProfile(name) => {
+                buffer.push(CMD_REQ_PROFILE);
+                encode_string(buffer, name)?;
+            },
+            Command::WriteProfile { name, mode, brightness, speed, colors } => {
+                buffer.push(CMD_WRITE_PROFILE);
+                encode_string(buffer, name)?;
+                buffer.extend([mode.to_u8(), *brightness, *speed]);
+                encode_colors(buffer, colors)?;
+            },
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut buffer = vec![];
+        self.encode_to(&mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+//Encodes commands into request payload. Color vectors are limited
+//to 255 colors and strings to 255 bytes by the wire format.
+pub fn encode(commands: &[Command]) -> Result<Vec<u8>, EncodeError> {
+    let mut buffer = vec![];
+    for command in commands {
+        command.encode_to(&mut buffer)?;
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, decode_command, encode, is_valid_profile_name, Color, Command, Mode};
+    use crate::error::DecodeErrorKind;
+
+    fn all_commands() -> Vec<Command> {
+        vec![
+            Command::Colors(vec![Color::new(1, 2, 3), Color::new(4, 5, 6), Color::new(7, 8, 9)]),
+            Command::SetColor(Color::new(10, 11, 12)),
+            Command::AddColor(Color::new(13, 14, 15)),
+            Command::Brightness(5),
+            Command::Speed(2),
+            Command::Mode(Mode::Off),
+            Command::Mode(Mode::Steady),
+            Command::Mode(Mode::Breathing),
+            Command::Mode(Mode::ColorShift),
+            Command::SyncState(true),
+            Command::Power(false),
+            Command::Power(true),
+            Command::Toggle,
+            Command::RequestModes,
+            Command::RequestCapabilities,
+            Command::RequestState,
+            Command::Subscribe,
+            Command::SaveProfile("work".to_string()),
+            Command::ApplyProfile("night".to_string()),
+            Command::DeleteProfile("gaming".to_string()),
+            Command::RequestProfiles,
+            Command::RequestProfile("work".to_string()),
+            Command::WriteProfile { name: "night".to_string(), mode: Mode::Breathing, brightness: 3, speed: 1,
+                                    colors: vec![Color::new(16, 17, 18), Color::new(19, 20, 21)] },
+        ]
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = all_commands();
+        assert_eq!(decode(&encode(&commands).unwrap()).unwrap(), commands);
+        for command in commands {
+            assert_eq!(decode(&command.encode().unwrap()).unwrap(), vec![command]);
+        }
+    }
+
+    #[test]
+    fn encoding_matches_wire_format() {
+        assert_eq!(Command::Colors(vec![Color::new(1, 2, 3), Color::new(4, 5, 6)]).encode().unwrap(),
+                   vec![0x00, 2, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(encode(&[Command::SetColor(Color::new(1, 2, 3)), Command::Mode(Mode::Breathing),
+                            Command::Power(true), Command::RequestState]).unwrap(),
+                   vec![0x01, 1, 2, 3, 0x05, 0x02, 0x07, 0x01, 0x0B]);
+        assert_eq!(Command::SaveProfile("work".to_string()).encode().unwrap(), vec![0x0D, 4, b'w', b'o', b'r', b'k']);
+        let write = Command::WriteProfile { name: "a".to_string(), mode: Mode::Steady, brightness: 7, speed: 0,
+                                            colors: vec![Color::new(1, 2, 3)] };
+        assert_eq!(write.encode().unwrap(), vec![0x12, 1, b'a', 0x01, 7, 0, 1, 1, 2, 3]);
+    }
+
+    #[test]
+    fn unrepresentable_commands_are_not_encoded() {
+        let colors = vec![Color::new(1, 2, 3); 255];
+        assert_eq!(Command::Colors(colors.clone()).encode().unwrap().len(), 2 + 255 * 3);
+        let colors = vec![Color::new(1, 2, 3); 256];
+        assert!(Command::Colors(colors.clone()).encode().unwrap_err().message.contains("256 colors"));
+        let mut buffer = vec![0x0B];
+        assert!(Command::Colors(colors).encode_to(&mut buffer).is_err());
+        assert_eq!(buffer, vec![0x0B]);
+        assert!(Command::ApplyProfile("é".repeat(127)).encode().is_ok());
+        assert!(Command::ApplyProfile("é".repeat(128)).encode().is_err());
+        assert!(encode(&[Command::Toggle, Command::SaveProfile("x".repeat(256))]).is_err());
+    }
+
+    #[test]
+    fn profile_names_are_checked() {
+        assert!(is_valid_profile_name("night_2-b"));
+        assert!(!is_valid_profile_name(""));
+        assert!(!is_valid_profile_name("../state"));
+        assert!(!is_valid_profile_name(&"a".repeat(33)));
+        let e = decode(&[0x0E, 2, 0xff, 0xfe]).unwrap_err();
+        assert_eq!((e.kind, e.offset), (DecodeErrorKind::BadArgument, 0));
+    }
+
+    #[test]
+    fn all_colors_of_vector_are_decoded() {
+        let (command, next) = decode_command(&[0x00, 2, 1, 2, 3, 4, 5, 6, 0x08], 0).unwrap();
+        assert_eq!(command, Command::Colors(vec![Color::new(1, 2, 3), Color::new(4, 5, 6)]));
+        assert_eq!(next, 8);
+    }
+
+    #[test]
+    fn malformed_commands_are_rejected_with_offset() {
+        let e = decode(&[0x03, 5, 0x42]).unwrap_err();
+        assert_eq!((e.kind, e.offset), (DecodeErrorKind::UnknownCommand, 2));
+        let e = decode(&[0x08, 0x01, 1, 2]).unwrap_err();
+        assert_eq!((e.kind, e.offset), (DecodeErrorKind::Truncated, 1));
+        let e = decode(&[0x00, 2, 1, 2, 3, 4]).unwrap_err();
+        assert_eq!((e.kind, e.offset), (DecodeErrorKind::Truncated, 0));
+        let e = decode(&[0x00]).unwrap_err();
+        assert_eq!(e.kind, DecodeErrorKind::Truncated);
+        let e = decode(&[0x00, 0]).unwrap_err();
+        assert_eq!(e.kind, DecodeErrorKind::BadArgument);
+        let e = decode(&[0x01, 1, 2, 3, 0x05, 9]).unwrap_err();
+        assert_eq!((e.kind, e.offset), (DecodeErrorKind::BadArgument, 4));
+    }
+
+    #[test]
+    fn every_truncation_is_an_error() {
+        let buffer = encode(&all_commands()).unwrap();
+        let commands = decode(&buffer).unwrap();
+        for size in 0..buffer.len() {
+            match decode(&buffer[..size]) {
+                Ok(decoded) => assert_eq!(decoded[..], commands[..decoded.len()]),
+                Err(e) => assert_eq!(e.kind, DecodeErrorKind::Truncated),
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use std::fmt;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum DecodeErrorKind {
+    UnknownCommand,
+    //Buffer ended in the middle of a command
+    Truncated,
+    BadArgument,
+}
+
+//Reason why buffer is not a valid request. Offset points to
+//the command which could not be decoded.
+#[derive(PartialEq, Debug)]
+pub struct DecodeError {
+    pub kind: DecodeErrorKind,
+    pub offset: usize,
+    pub message: String,
+}
+
+impl DecodeError {
+    pub fn new(kind: DecodeErrorKind, offset: usize, message: &str) -> DecodeError {
+        DecodeError {
+            kind,
+            offset,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn truncated(offset: usize, expected: &str) -> DecodeError {
+        DecodeError::new(DecodeErrorKind::Truncated, offset, &format!("expected {}, got end of message", expected))
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} at {}: {}", self.kind, self.offset, self.message)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+//Command which can not be represented in wire format, e.g. too long string
+#[derive(PartialEq, Debug)]
+pub struct EncodeError {
+    pub message: String,
+}
+
+impl EncodeError {
+    pub fn new(message: &str) -> EncodeError {
+        EncodeError { message: message.to_string() }
+    }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "can not encode command: {}", self.message)
+    }
+}
+
+impl std::error::Error for EncodeError {}
@@ -0,0 +1,69 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+//v1 packets start with non-zero size byte, so zero byte
+//followed by "KLM" introduces versioned frame
+pub const FRAME_MAGIC: [u8; 4] = [0x00, b'K', b'L', b'M'];
+pub const PROTO_VERSION: u8 = 2;
+//Magic, version, request id and payload length
+pub const FRAME_HEADER_SIZE: usize = 13;
+pub const MAX_PAYLOAD_SIZE: u32 = 64 * 1024;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub request_id: u32,
+    pub length: u32,
+}
+
+fn read_u32(buffer: &[u8]) -> u32 {
+    u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]])
+}
+
+pub fn encode_header(request_id: u32, length: u32) -> Vec<u8> {
+    let mut header = FRAME_MAGIC.to_vec();
+    header.push(PROTO_VERSION);
+    header.extend(request_id.to_be_bytes());
+    header.extend(length.to_be_bytes());
+    header
+}
+
+//Returns None if header does not start with frame magic.
+//Version is not checked, so peer can report unsupported one.
+pub fn decode_header(header: &[u8; FRAME_HEADER_SIZE]) -> Option<FrameHeader> {
+    if header[..FRAME_MAGIC.len()] != FRAME_MAGIC {
+        return None;
+    }
+    Some(FrameHeader {
+        version: header[4],
+        request_id: read_u32(&header[5..9]),
+        length: read_u32(&header[9..13]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_header, encode_header, FrameHeader, FRAME_HEADER_SIZE};
+
+    #[test]
+    fn header_round_trips() {
+        let header: [u8; FRAME_HEADER_SIZE] = encode_header(0x01020304, 300).try_into().unwrap();
+        assert_eq!(header, [0x00, b'K', b'L', b'M', 2, 1, 2, 3, 4, 0, 0, 1, 44]);
+        assert_eq!(decode_header(&header), Some(FrameHeader { version: 2, request_id: 0x01020304, length: 300 }));
+    }
+
+    #[test]
+    fn bad_magic_is_not_a_header() {
+        let mut header: [u8; FRAME_HEADER_SIZE] = encode_header(1, 1).try_into().unwrap();
+        header[1] = b'X';
+        assert_eq!(decode_header(&header), None);
+    }
+}
@@ -0,0 +1,23 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+//Requests understood by klmd and frames carrying them, shared
+//by daemon and its clients.
+//Decoding is pure: it only checks wire format, limits of a particular
+//keyboard are checked by klmd when request is applied.
+
+pub mod command;
+pub mod error;
+pub mod frame;
+pub mod names;
+
+pub use command::{decode, decode_command, encode, is_valid_profile_name, Color, Command, Mode};
+pub use error::{DecodeError, DecodeErrorKind, EncodeError};
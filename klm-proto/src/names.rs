@@ -0,0 +1,103 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+//Names of modes, colors and profiles used by klmctl arguments, JSON
+//requests and klmd configuration
+
+use crate::command::{is_valid_profile_name, Color, Mode, MAX_PROFILE_NAME};
+
+pub fn parse_mode(value: &str) -> Result<Mode, String> {
+    match value.to_lowercase().as_str() {
+        "off" => Ok(Mode::Off),
+        "steady" => Ok(Mode::Steady),
+        "breathing" | "breathe" => Ok(Mode::Breathing),
+        "colorshift" | "shift" => Ok(Mode::ColorShift),
+        _ => Err(format!("unknown mode '{}', expected off, steady, breathing or colorshift", value)),
+    }
+}
+
+pub fn mode_name(mode: Mode) -> &'static str {
+    match mode {
+        Mode::Off => "off",
+        Mode::Steady => "steady",
+        Mode::Breathing => "breathing",
+        Mode::ColorShift => "colorshift",
+    }
+}
+
+pub fn mode_names(modes: &[Mode]) -> Vec<&'static str> {
+    modes.iter().map(|mode| mode_name(*mode)).collect()
+}
+
+fn parse_hex(value: &str) -> Option<Color> {
+    if !value.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let digits: Vec<u8> = value.chars().map(|c| c.to_digit(16).unwrap() as u8).collect();
+    match digits.len() {
+        3 => Some(Color::new(digits[0] * 17, digits[1] * 17, digits[2] * 17)),
+        6 => Some(Color::new(digits[0] * 16 + digits[1], digits[2] * 16 + digits[3], digits[4] * 16 + digits[5])),
+        _ => None,
+    }
+}
+
+pub fn parse_color(value: &str) -> Result<Color, String> {
+    let error = || format!("bad color '{}', expected #rrggbb, #rgb or r,g,b", value);
+    if value.contains(',') {
+        let parts: Vec<&str> = value.split(',').map(|part| part.trim()).collect();
+        if parts.len() != 3 {
+            return Err(error());
+        }
+        let mut rgb = [0u8; 3];
+        for (i, part) in parts.iter().enumerate() {
+            rgb[i] = part.parse::<u8>().map_err(|_| error())?;
+        }
+        return Ok(Color::new(rgb[0], rgb[1], rgb[2]));
+    }
+    parse_hex(value.strip_prefix('#').unwrap_or(value)).ok_or_else(error)
+}
+
+pub fn format_color(color: &Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+}
+
+pub fn parse_profile_name(value: &str) -> Result<String, String> {
+    if !is_valid_profile_name(value) {
+        return Err(format!("bad profile name '{}', expected up to {} letters, digits, '-' or '_'",
+                           value, MAX_PROFILE_NAME));
+    }
+    Ok(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_color, mode_name, parse_color, parse_mode};
+    use crate::command::{Color, Mode};
+
+    #[test]
+    fn colors_are_parsed() {
+        assert_eq!(parse_color("#ff8800"), Ok(Color::new(255, 136, 0)));
+        assert_eq!(parse_color("FF8800"), Ok(Color::new(255, 136, 0)));
+        assert_eq!(parse_color("#f80"), Ok(Color::new(255, 136, 0)));
+        assert_eq!(parse_color("255, 136,0"), Ok(Color::new(255, 136, 0)));
+        assert!(parse_color("#ff880").is_err());
+        assert!(parse_color("256,0,0").is_err());
+        assert!(parse_color("orange").is_err());
+        assert_eq!(format_color(&Color::new(255, 136, 0)), "#ff8800");
+    }
+
+    #[test]
+    fn modes_are_named() {
+        assert_eq!(parse_mode("ColorShift"), Ok(Mode::ColorShift));
+        assert_eq!(parse_mode(mode_name(Mode::Breathing)), Ok(Mode::Breathing));
+        assert!(parse_mode("disco").is_err());
+    }
+}
@@ -0,0 +1,206 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use clap::{Parser, Subcommand};
+use klm_client::names::{parse_color, parse_mode, parse_profile_name};
+use crate::profile_file::Format;
+use klm_client::{Color, Mode, DEFAULT_SOCKET_PATH};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(name = "klmctl", version, about = "Controls keyboard lightning through klmd")]
+pub struct Cli {
+    #[arg(long, global = true, default_value = DEFAULT_SOCKET_PATH, help = "Path of klmd socket")]
+    pub socket: String,
+    #[command(subcommand)]
+    pub command: CliCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CliCommand {
+    #[command(about = "Set mode: off, steady, breathing or colorshift")]
+    Mode {
+        #[arg(value_parser = parse_mode)]
+        mode: Mode,
+    },
+    #[command(about = "Set primary color, or add colors with --add. Colors are #rrggbb, #rgb or r,g,b")]
+    Color {
+        #[arg(required = true, value_parser = parse_color)]
+        colors: Vec<Color>,
+        #[arg(long, help = "Add colors to stored ones instead of replacing them")]
+        add: bool,
+    },
+    #[command(about = "Replace stored colors with given ones")]
+    Colors {
+        #[arg(required = true, value_parser = parse_color)]
+        colors: Vec<Color>,
+    },
+    #[command(about = "Set brightness, either raw value or percentage of keyboard range, e.g. 60%")]
+    Brightness {
+        #[arg(value_parser = parse_level)]
+        level: Level,
+    },
+    #[command(about = "Set speed of breathing and color shift, raw value or percentage")]
+    Speed {
+        #[arg(value_parser = parse_level)]
+        level: Level,
+    },
+    #[command(about = "Switch lightning on or off")]
+    Power {
+        #[arg(value_parser = parse_switch)]
+        power: bool,
+    },
+    #[command(about = "Toggle lightning power")]
+    Toggle,
+    #[command(about = "List modes supported by keyboard")]
+    Modes {
+        #[arg(long, help = "Print JSON")]
+        json: bool,
+    },
+    #[command(about = "Describe keyboard and limits of its values")]
+    Capabilities {
+        #[arg(long, help = "Print JSON")]
+        json: bool,
+    },
+    #[command(about = "Print current keyboard state")]
+    State {
+        #[arg(long, help = "Print JSON")]
+        json: bool,
+    },
+    #[command(about = "Save, apply or delete named profile")]
+    Profile {
+        #[command(subcommand)]
+        action: ProfileAction,
+    },
+    #[command(about = "List profiles stored by klmd")]
+    Profiles {
+        #[arg(long, help = "Print JSON")]
+        json: bool,
+    },
+    #[command(about = "Write current state, or state stored in profile, as TOML or JSON")]
+    Export {
+        #[arg(long, value_parser = parse_profile_name, help = "Export profile instead of current state")]
+        profile: Option<String>,
+        #[arg(long, value_enum, help = "Format of file, by default guessed from its extension")]
+        format: Option<Format>,
+        #[arg(help = "File to write, standard output by default")]
+        file: Option<PathBuf>,
+    },
+    #[command(about = "Apply TOML or JSON file written by export")]
+    Import {
+        file: PathBuf,
+        #[arg(long, value_parser = parse_profile_name, help = "Save file as profile, leaving current lighting as it is")]
+        profile: Option<String>,
+        #[arg(long, value_enum, help = "Format of file, by default guessed from its extension")]
+        format: Option<Format>,
+    },
+    #[command(about = "Print keyboard events until interrupted")]
+    Watch {
+        #[arg(long, help = "Print one JSON object per line")]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ProfileAction {
+    #[command(about = "Save current mode, colors, brightness and speed as profile")]
+    Save {
+        #[arg(value_parser = parse_profile_name)]
+        name: String,
+    },
+    #[command(about = "Apply profile, power is left as it is")]
+    Apply {
+        #[arg(value_parser = parse_profile_name)]
+        name: String,
+    },
+    #[command(about = "Delete profile")]
+    Delete {
+        #[arg(value_parser = parse_profile_name)]
+        name: String,
+    },
+}
+
+//Brightness or speed, either as sent to keyboard or relative to its range
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Level {
+    Raw(u8),
+    Percent(u8),
+}
+
+impl Level {
+    pub fn is_relative(&self) -> bool {
+        matches!(self, Level::Percent(_))
+    }
+
+    pub fn resolve(&self, range: (u8, u8)) -> u8 {
+        match *self {
+            Level::Raw(value) => value,
+            Level::Percent(percent) => {
+                let span = range.1.saturating_sub(range.0) as u32;
+                range.0 + ((span * percent as u32 + 50) / 100) as u8
+            },
+        }
+    }
+}
+
+pub fn parse_level(value: &str) -> Result<Level, String> {
+    if let Some(percent) = value.strip_suffix('%') {
+        match percent.trim().parse::<u8>() {
+            Ok(percent) if percent <= 100 => Ok(Level::Percent(percent)),
+            _ => Err(format!("bad percentage '{}', expected 0% to 100%", value)),
+        }
+    } else {
+        value.parse::<u8>().map(Level::Raw).map_err(|_| format!("bad value '{}', expected 0 to 255 or percentage", value))
+    }
+}
+
+pub fn parse_switch(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "on" | "1" | "true" => Ok(true),
+        "off" | "0" | "false" => Ok(false),
+        _ => Err(format!("bad power '{}', expected on or off", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_level, Cli, CliCommand, Level, ProfileAction};
+    use clap::Parser;
+    use klm_client::Mode;
+
+    #[test]
+    fn levels_are_scaled_to_range() {
+        assert_eq!(parse_level("60%"), Ok(Level::Percent(60)));
+        assert_eq!(parse_level("7"), Ok(Level::Raw(7)));
+        assert!(parse_level("101%").is_err());
+        assert!(parse_level("-1").is_err());
+        assert_eq!(Level::Percent(60).resolve((0, 10)), 6);
+        assert_eq!(Level::Percent(100).resolve((1, 3)), 3);
+        assert_eq!(Level::Percent(0).resolve((1, 3)), 1);
+        assert_eq!(Level::Raw(9).resolve((0, 2)), 9);
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        let cli = Cli::try_parse_from(["klmctl", "mode", "breathing"]).unwrap();
+        assert!(matches!(cli.command, CliCommand::Mode { mode: Mode::Breathing }));
+        let cli = Cli::try_parse_from(["klmctl", "--socket", "/tmp/k.sock", "color", "#ff8800", "--add"]).unwrap();
+        assert_eq!(cli.socket, "/tmp/k.sock");
+        assert!(matches!(cli.command, CliCommand::Color { add: true, .. }));
+        assert!(Cli::try_parse_from(["klmctl", "mode", "disco"]).is_err());
+        assert!(Cli::try_parse_from(["klmctl", "color"]).is_err());
+        let cli = Cli::try_parse_from(["klmctl", "profile", "apply", "night"]).unwrap();
+        assert!(matches!(cli.command, CliCommand::Profile { action: ProfileAction::Apply { name } } if name == "night"));
+        assert!(Cli::try_parse_from(["klmctl", "profile", "save", "a/b"]).is_err());
+        let cli = Cli::try_parse_from(["klmctl", "import", "night.json", "--profile", "night"]).unwrap();
+        assert!(matches!(cli.command, CliCommand::Import { profile: Some(_), format: None, .. }));
+    }
+}
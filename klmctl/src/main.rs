@@ -0,0 +1,211 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+mod args;
+mod output;
+mod profile_file;
+
+use args::{Cli, CliCommand, Level, ProfileAction};
+use clap::Parser;
+use klm_client::json::{capabilities_json, event_json, modes_json, profiles_json, state_json};
+use klm_client::{Client, ClientError, ClientResult, Request};
+use profile_file::{Format, ProfileFile};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+//Exit codes, so scripts can tell why klmctl failed.
+//Usage errors exit with 2, as reported by clap.
+const EXIT_FAILED: u8 = 1;
+const EXIT_REJECTED: u8 = 3;
+const EXIT_UNAVAILABLE: u8 = 4;
+const EXIT_PROTOCOL: u8 = 5;
+const EXIT_FILE: u8 = 6;
+
+//Failure of request, or of profile file given to klmctl
+enum Failure {
+    Client(ClientError),
+    File(String),
+}
+
+impl From<ClientError> for Failure {
+    fn from(e: ClientError) -> Failure {
+        Failure::Client(e)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Client(e) => write!(f, "{}", e),
+            Failure::File(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+fn exit_code(e: &Failure) -> u8 {
+    match e {
+        Failure::Client(ClientError::Failed { .. }) => EXIT_FAILED,
+        Failure::Client(ClientError::BadRequest { .. }) => EXIT_REJECTED,
+        Failure::Client(ClientError::Io(_)) => EXIT_UNAVAILABLE,
+        Failure::Client(ClientError::Protocol(_)) => EXIT_PROTOCOL,
+        Failure::File(_) => EXIT_FILE,
+    }
+}
+
+fn print_json(value: serde_json::Value, json: bool, text: String) {
+    if json {
+        println!("{}", value);
+    } else {
+        println!("{}", text);
+    }
+}
+
+//Percentages are relative to range reported by keyboard,
+//so capabilities are only requested when needed
+fn resolve_level(client: &mut Client, level: Level, brightness: bool) -> ClientResult<u8> {
+    if !level.is_relative() {
+        return Ok(level.resolve((0, 255)));
+    }
+    let capabilities = client.capabilities()?;
+    if brightness {
+        Ok(level.resolve(capabilities.brightness_range))
+    } else {
+        Ok(level.resolve(capabilities.speed_range))
+    }
+}
+
+fn export(client: &mut Client, profile: Option<String>, format: Option<Format>,
+          file: Option<PathBuf>) -> Result<(), Failure> {
+    let state = match &profile {
+        Some(name) => client.profile(name)?,
+        None => client.state()?,
+    };
+    let capabilities = client.capabilities()?;
+    let format = format.or_else(|| file.as_deref().map(Format::of_path)).unwrap_or(Format::Toml);
+    let text = ProfileFile::from_state(&state, &capabilities).to_text(format).map_err(Failure::File)?;
+    match file {
+        Some(path) => fs::write(&path, text)
+            .map_err(|e| Failure::File(format!("can not write {}: {}", path.display(), e))),
+        None => {
+            print!("{}", text);
+            Ok(())
+        },
+    }
+}
+
+//File is applied as a single request. When it is saved as profile,
+//it is stored with write_profile and keyboard is left alone.
+fn import(client: &mut Client, file: PathBuf, profile: Option<String>,
+          format: Option<Format>) -> Result<(), Failure> {
+    let text = fs::read_to_string(&file)
+        .map_err(|e| Failure::File(format!("can not read {}: {}", file.display(), e)))?;
+    let capabilities = client.capabilities()?;
+    let lighting = ProfileFile::parse(&text, format.unwrap_or_else(|| Format::of_path(&file)))
+        .and_then(|profile| profile.resolve(&capabilities))
+        .map_err(|e| Failure::File(format!("{}: {}", file.display(), e)))?;
+    let request = match profile {
+        Some(name) => lighting.write_to(Request::new(), &name),
+        None => lighting.add_to(Request::new()),
+    };
+    client.send(&request)?;
+    Ok(())
+}
+
+fn run(cli: Cli) -> Result<(), Failure> {
+    let mut client = Client::connect_to(&cli.socket)?;
+    match cli.command {
+        CliCommand::Mode { mode } => {
+            client.send(&Request::new().mode(mode))?;
+        },
+        CliCommand::Color { colors, add } => {
+            let mut request = Request::new();
+            for (i, color) in colors.into_iter().enumerate() {
+                request = if add || i > 0 {
+                    request.add_color(color)
+                } else {
+                    request.set_color(color)
+                };
+            }
+            client.send(&request)?;
+        },
+        CliCommand::Colors { colors } => {
+            client.send(&Request::new().colors(&colors))?;
+        },
+        CliCommand::Brightness { level } => {
+            let brightness = resolve_level(&mut client, level, true)?;
+            client.send(&Request::new().brightness(brightness))?;
+        },
+        CliCommand::Speed { level } => {
+            let speed = resolve_level(&mut client, level, false)?;
+            client.send(&Request::new().speed(speed))?;
+        },
+        CliCommand::Power { power } => {
+            client.send(&Request::new().power(power))?;
+        },
+        CliCommand::Toggle => {
+            client.send(&Request::new().toggle())?;
+        },
+        CliCommand::Modes { json } => {
+            let modes = client.modes()?;
+            print_json(modes_json(&modes), json, output::modes_text(&modes));
+        },
+        CliCommand::Capabilities { json } => {
+            let capabilities = client.capabilities()?;
+            print_json(capabilities_json(&capabilities), json, output::capabilities_text(&capabilities));
+        },
+        CliCommand::State { json } => {
+            let state = client.state()?;
+            print_json(state_json(&state), json, output::state_text(&state));
+        },
+        CliCommand::Profile { action } => {
+            let request = match action {
+                ProfileAction::Save { name } => Request::new().save_profile(&name),
+                ProfileAction::Apply { name } => Request::new().apply_profile(&name),
+                ProfileAction::Delete { name } => Request::new().delete_profile(&name),
+            };
+            client.send(&request)?;
+        },
+        CliCommand::Profiles { json } => {
+            let profiles = client.profiles()?;
+            print_json(profiles_json(&profiles), json, profiles.join("\n"));
+        },
+        CliCommand::Export { profile, format, file } => export(&mut client, profile, format, file)?,
+        CliCommand::Import { file, profile, format } => import(&mut client, file, profile, format)?,
+        CliCommand::Watch { json } => {
+            for event in client.subscribe()? {
+                let event = event?;
+                print_json(event_json(&event), json, output::event_text(&event));
+            }
+        },
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let socket = cli.socket.clone();
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            match &e {
+                Failure::Client(ClientError::Io(io)) =>
+                    eprintln!("klmctl: can not talk to klmd at {}: {}", socket, io),
+                Failure::Client(ClientError::Failed { message, .. }) |
+                Failure::Client(ClientError::BadRequest { message, .. })
+                    if !message.is_empty() => eprintln!("klmctl: {}", message),
+                _ => eprintln!("klmctl: {}", e),
+            }
+            ExitCode::from(exit_code(&e))
+        },
+    }
+}
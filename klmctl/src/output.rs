@@ -0,0 +1,88 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use klm_client::names::{format_color, mode_name, mode_names};
+use klm_client::{Capabilities, Event, KeyboardState, Layout, Mode};
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+pub fn modes_text(modes: &[Mode]) -> String {
+    mode_names(modes).join("\n")
+}
+
+pub fn capabilities_text(capabilities: &Capabilities) -> String {
+    let layout = match capabilities.layout {
+        Layout::Zones(zones) => format!("{} zones", zones),
+        Layout::PerKey { rows, columns } => format!("per-key, {}x{}", rows, columns),
+    };
+    [
+        format!("keyboard:   {} ({:04x}:{:04x})", capabilities.name, capabilities.vendor_id, capabilities.product_id),
+        format!("modes:      {}", mode_names(&capabilities.modes).join(", ")),
+        format!("brightness: {}..{}", capabilities.brightness_range.0, capabilities.brightness_range.1),
+        format!("speed:      {}..{}", capabilities.speed_range.0, capabilities.speed_range.1),
+        format!("max colors: {}", capabilities.max_colors),
+        format!("layout:     {}", layout),
+    ].join("\n")
+}
+
+pub fn state_text(state: &KeyboardState) -> String {
+    [
+        format!("mode:       {}", mode_name(state.mode)),
+        format!("power:      {}", on_off(state.power)),
+        format!("brightness: {}", state.brightness),
+        format!("speed:      {}", state.speed),
+        format!("colors:     {}", state.colors.iter().map(format_color).collect::<Vec<String>>().join(" ")),
+    ].join("\n")
+}
+
+pub fn event_text(event: &Event) -> String {
+    match event {
+        Event::StateChanged(state) => format!("state: {}, power {}, brightness {}, speed {}, colors {}",
+                                              mode_name(state.mode), on_off(state.power), state.brightness,
+                                              state.speed,
+                                              state.colors.iter().map(format_color).collect::<Vec<String>>().join(" ")),
+        Event::DeviceConnected(capabilities) => format!("connected: {}", capabilities.name),
+        Event::DeviceRemoved => "removed".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{event_text, state_text};
+    use klm_client::{Color, Event, KeyboardState, Mode};
+
+    fn state() -> KeyboardState {
+        KeyboardState {
+            mode: Mode::Breathing,
+            power: true,
+            brightness: 6,
+            speed: 1,
+            sync_locked: false,
+            colors: vec![Color::new(255, 136, 0), Color::new(0, 0, 1)],
+        }
+    }
+
+    #[test]
+    fn state_is_printed() {
+        assert!(state_text(&state()).contains("colors:     #ff8800 #000001"));
+    }
+
+    #[test]
+    fn events_are_printed() {
+        assert!(event_text(&Event::StateChanged(state())).starts_with("state: breathing, power on"));
+    }
+}
@@ -0,0 +1,194 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::args::Level;
+use clap::ValueEnum;
+use klm_client::names::{format_color, mode_name, parse_color, parse_mode};
+use klm_client::{Capabilities, Color, KeyboardState, Mode, Request};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(PartialEq, Clone, Copy, Debug, ValueEnum)]
+pub enum Format {
+    Toml,
+    Json,
+}
+
+impl Format {
+    //Files are TOML unless their extension says otherwise
+    pub fn of_path(path: &Path) -> Format {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("json") => Format::Json,
+            _ => Format::Toml,
+        }
+    }
+}
+
+//Lighting setup in human-editable form, e.g. for dotfiles. Brightness
+//is a percentage of keyboard range, so file suits other keyboards too.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileFile {
+    pub mode: String,
+    pub colors: Vec<String>,
+    pub brightness: String,
+    pub speed: u8,
+}
+
+//Settings of profile file checked against keyboard
+#[derive(PartialEq, Clone, Debug)]
+pub struct Lighting {
+    pub mode: Mode,
+    pub colors: Vec<Color>,
+    pub brightness: u8,
+    pub speed: u8,
+}
+
+impl From<&KeyboardState> for Lighting {
+    fn from(state: &KeyboardState) -> Lighting {
+        Lighting {
+            mode: state.mode,
+            colors: state.colors.clone(),
+            brightness: state.brightness,
+            speed: state.speed,
+        }
+    }
+}
+
+impl Lighting {
+    //Adds commands setting this lighting to request. Power is left as it is.
+    pub fn add_to(&self, request: Request) -> Request {
+        request.colors(&self.colors)
+            .brightness(self.brightness)
+            .speed(self.speed)
+            .mode(self.mode)
+    }
+
+    //Adds command storing this lighting as profile, without applying it
+    pub fn write_to(&self, request: Request, name: &str) -> Request {
+        request.write_profile(name, self.mode, self.brightness, self.speed, &self.colors)
+    }
+}
+
+fn percent_of_range(value: u8, range: (u8, u8)) -> u8 {
+    let span = range.1.saturating_sub(range.0) as u32;
+    if span == 0 {
+        return 100;
+    }
+    let value = value.clamp(range.0, range.1) - range.0;
+    ((value as u32 * 100 + span / 2) / span) as u8
+}
+
+impl ProfileFile {
+    pub fn from_state(state: &KeyboardState, capabilities: &Capabilities) -> ProfileFile {
+        ProfileFile {
+            mode: mode_name(state.mode).to_string(),
+            colors: state.colors.iter().map(format_color).collect(),
+            brightness: format!("{}%", percent_of_range(state.brightness, capabilities.brightness_range)),
+            speed: state.speed,
+        }
+    }
+
+    pub fn parse(text: &str, format: Format) -> Result<ProfileFile, String> {
+        match format {
+            Format::Toml => toml::from_str(text).map_err(|e| e.message().to_string()),
+            Format::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn to_text(&self, format: Format) -> Result<String, String> {
+        match format {
+            Format::Toml => toml::to_string(self).map_err(|e| e.to_string()),
+            Format::Json => serde_json::to_string_pretty(self).map(|text| text + "\n").map_err(|e| e.to_string()),
+        }
+    }
+
+    //Checks file against capabilities of keyboard, so nothing is sent
+    //to klmd unless the whole file can be applied
+    pub fn resolve(&self, capabilities: &Capabilities) -> Result<Lighting, String> {
+        let mode = parse_mode(&self.mode)?;
+        if mode != Mode::Off && !capabilities.modes.contains(&mode) {
+            return Err(format!("mode {} is not supported by {}", self.mode, capabilities.name));
+        }
+        if self.colors.is_empty() {
+            return Err("at least one color is required".to_string());
+        }
+        if self.colors.len() > capabilities.max_colors as usize {
+            return Err(format!("{} colors given, {} supports at most {}", self.colors.len(), capabilities.name,
+                               capabilities.max_colors));
+        }
+        let colors = self.colors.iter().map(|color| parse_color(color)).collect::<Result<Vec<Color>, String>>()?;
+        let brightness = match self.brightness.strip_suffix('%').map(|percent| percent.trim().parse::<u8>()) {
+            Some(Ok(percent)) if percent <= 100 => Level::Percent(percent).resolve(capabilities.brightness_range),
+            _ => return Err(format!("bad brightness '{}', expected 0% to 100%", self.brightness)),
+        };
+        let (min_speed, max_speed) = capabilities.speed_range;
+        if self.speed < min_speed || self.speed > max_speed {
+            return Err(format!("speed {} is out of range {}..{}", self.speed, min_speed, max_speed));
+        }
+        Ok(Lighting { mode, colors, brightness, speed: self.speed })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, Lighting, ProfileFile};
+    use klm_client::{Capabilities, Color, KeyboardState, Layout, Mode};
+    use std::path::Path;
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            name: "MS1563".to_string(),
+            vendor_id: 0x1462,
+            product_id: 0x1563,
+            modes: vec![Mode::Steady, Mode::Breathing, Mode::ColorShift],
+            brightness_range: (0, 10),
+            speed_range: (0, 2),
+            max_colors: 7,
+            can_power_on: false,
+            layout: Layout::Zones(1),
+        }
+    }
+
+    #[test]
+    fn state_is_exported_and_imported() {
+        let state = KeyboardState {
+            mode: Mode::Breathing,
+            power: true,
+            brightness: 6,
+            speed: 1,
+            sync_locked: false,
+            colors: vec![Color::new(255, 136, 0), Color::new(0, 0, 255)],
+        };
+        let file = ProfileFile::from_state(&state, &capabilities());
+        let text = file.to_text(Format::Toml).unwrap();
+        assert_eq!(text, "mode = \"breathing\"\ncolors = [\"#ff8800\", \"#0000ff\"]\nbrightness = \"60%\"\nspeed = 1\n");
+        for format in [Format::Toml, Format::Json] {
+            let parsed = ProfileFile::parse(&file.to_text(format).unwrap(), format).unwrap();
+            assert_eq!(parsed.resolve(&capabilities()).unwrap(), Lighting::from(&state));
+        }
+        assert_eq!(Format::of_path(Path::new("night.JSON")), Format::Json);
+        assert_eq!(Format::of_path(Path::new("night")), Format::Toml);
+    }
+
+    #[test]
+    fn file_is_checked_against_keyboard() {
+        let check = |text: &str| ProfileFile::parse(text, Format::Toml).and_then(|file| file.resolve(&capabilities()));
+        assert!(check("mode = \"steady\"\ncolors = [\"#fff\"]\nbrightness = \"100%\"\nspeed = 0").is_ok());
+        assert!(check("mode = \"steady\"\ncolors = [\"#fff\"]\nbrightness = \"100%\"\nspeed = 3").is_err());
+        assert!(check("mode = \"steady\"\ncolors = [\"#fff\"]\nbrightness = \"7\"\nspeed = 0").is_err());
+        assert!(check("mode = \"steady\"\ncolors = []\nbrightness = \"7%\"\nspeed = 0").is_err());
+        assert!(check("mode = \"disco\"\ncolors = [\"#fff\"]\nbrightness = \"7%\"\nspeed = 0").is_err());
+        assert!(check("mode = \"steady\"\ncolors = [\"#fff\"]\nbrightness = \"7%\"\nspeed = 0\npower = true").is_err());
+        let colors = format!("mode = \"steady\"\ncolors = [{}]\nbrightness = \"7%\"\nspeed = 0", ["\"#fff\""; 8].join(","));
+        assert!(check(&colors).unwrap_err().contains("at most 7"));
+    }
+}
@@ -0,0 +1,41 @@
+/*
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+#![no_main]
+
+use klmd::drivers::ms1563::MS1563;
+use klmd::drivers::transport::RecordingTransport;
+use klmd::keyboard::Keyboard;
+use klmd::protocol::proto::proto_handle_message;
+use klmd::util::u8::U8VecSerializable;
+use libfuzzer_sys::fuzz_target;
+
+//Input is a sequence of v1 packets: size byte followed by commands.
+//Keyboard is shared between packets, so state left by one request
+//is seen by the next one.
+fuzz_target!(|data: &[u8]| {
+    let transport = RecordingTransport::new();
+    let mut keyboard = Keyboard::new(Box::new(MS1563::with_transport(Box::new(transport))));
+    //Profiles saved by input are kept out of system directory
+    keyboard.set_profiles_dir(&std::env::temp_dir().join("klmd-fuzz-profiles"));
+    let mut data = data;
+    while let Some((&size, rest)) = data.split_first() {
+        let size = (size as usize).min(rest.len());
+        let (buffer, rest) = rest.split_at(size);
+        data = rest;
+        if buffer.is_empty() {
+            continue;
+        }
+        let response = proto_handle_message(&mut keyboard, buffer);
+        response.to_u8_vec();
+        response.to_payload();
+    }
+});
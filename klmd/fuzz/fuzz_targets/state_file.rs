@@ -0,0 +1,29 @@
+/*
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+#![no_main]
+
+use klmd::drivers::ms1563::MS1563;
+use klmd::drivers::transport::RecordingTransport;
+use klmd::keyboard::Keyboard;
+use klmd::util::u8::U8VecSerializable;
+use libfuzzer_sys::fuzz_target;
+
+//Loaded state must be either rejected or safe to sync and report
+fuzz_target!(|data: &[u8]| {
+    let transport = RecordingTransport::new();
+    let mut keyboard = Keyboard::new(Box::new(MS1563::with_transport(Box::new(transport))));
+    if keyboard.load_state_from(data).is_ok() {
+        keyboard.unlock_sync();
+        let _ = keyboard.sync();
+        keyboard.get_status().to_u8_vec();
+    }
+});
@@ -0,0 +1,71 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+#ifndef KLMD_DRIVER_H
+#define KLMD_DRIVER_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+/* Plugins built against other ABI version are rejected by klmd */
+#define KLM_DRIVER_ABI_VERSION 2
+
+/* Keyboard modes, same values as in klmd protocol */
+#define KLM_MODE_STEADY     0x1
+#define KLM_MODE_BREATHING  0x2
+#define KLM_MODE_COLORSHIFT 0x3
+
+/* Keyboard layouts */
+#define KLM_LAYOUT_ZONES    0x0 /* `zones` independently colored zones */
+#define KLM_LAYOUT_PER_KEY  0x1 /* `rows` x `columns` matrix of keys */
+
+typedef struct {
+    uint8_t r;
+    uint8_t g;
+    uint8_t b;
+} klm_color_t;
+
+/*
+ * Driver descriptor. All functions are required and must return 0 on success.
+ * Brightness and speed ranges are inclusive. Name is 1 to 64 bytes long.
+ * `open` returns opaque device handle passed to other functions or NULL on failure.
+ */
+typedef struct {
+    uint32_t abi_version;
+    const char *name;
+    uint16_t vendor_id;
+    uint16_t product_id;
+    uint8_t max_colors;
+    uint8_t brightness_min;
+    uint8_t brightness_max;
+    uint8_t speed_min;
+    uint8_t speed_max;
+    uint8_t can_power_on;
+    uint8_t layout;
+    uint8_t zones;
+    uint8_t rows;
+    uint8_t columns;
+    size_t n_modes;
+    const uint8_t *modes;
+    void *(*open)(uint16_t vendor_id, uint16_t product_id);
+    void (*close)(void *handle);
+    int (*set_color)(void *handle, const klm_color_t *color, uint8_t brightness);
+    int (*set_breathing)(void *handle, const klm_color_t *colors, size_t n_colors,
+                         uint8_t brightness, uint8_t speed);
+    int (*set_shift)(void *handle, const klm_color_t *colors, size_t n_colors,
+                     uint8_t brightness, uint8_t speed);
+    int (*set_power)(void *handle, int power);
+} klm_driver_descriptor_t;
+
+/* Every plugin must export this symbol */
+const klm_driver_descriptor_t *klm_driver_entry(void);
+
+#endif /* KLMD_DRIVER_H */
@@ -0,0 +1,81 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2022 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use clap::Parser;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(name = "klmd", version, about = "Keyboard light manager daemon")]
+pub struct Args {
+    #[arg(short, long, help = "Configuration file [default: /etc/klmd/klmd.toml]")]
+    pub config: Option<PathBuf>,
+    #[arg(short = 'o', long = "set", value_name = "KEY=VALUE",
+          help = "Override configuration value, e.g. socket.group=wheel")]
+    pub overrides: Vec<String>,
+    #[arg(long, help = "Path of socket for binary requests")]
+    pub socket: Option<String>,
+    #[arg(long, help = "Path of socket for JSON requests")]
+    pub json_socket: Option<String>,
+    #[arg(long, help = "File keyboard state is saved to")]
+    pub state_file: Option<String>,
+    #[arg(long, value_parser = ["debug", "info", "warn", "error"], help = "Least severe level of printed messages")]
+    pub log_level: Option<String>,
+    #[arg(short, long, conflicts_with = "foreground",
+          help = "Detach from terminal, messages are discarded then")]
+    pub daemon: bool,
+    #[arg(short, long, help = "Do not detach from terminal, which is the default")]
+    pub foreground: bool,
+    #[arg(long, help = "Use only driver of this name")]
+    pub driver: Option<String>,
+    #[arg(long, help = "Print attached HID devices and drivers handling them, then exit")]
+    pub list_devices: bool,
+    #[arg(long, help = "Do not touch keyboard, log what would be sent to it instead")]
+    pub dry_run: bool,
+}
+
+impl Args {
+    //Configuration overrides, dedicated options are applied
+    //after --set ones
+    pub fn overrides(&self) -> Vec<String> {
+        let mut overrides = self.overrides.clone();
+        let options = [("socket.path", &self.socket), ("socket.json_path", &self.json_socket),
+            ("state_file", &self.state_file), ("log_level", &self.log_level), ("driver.name", &self.driver)];
+        for (key, value) in options {
+            if let Some(value) = value {
+                overrides.push(format!("{}={}", key, toml::Value::String(value.clone())));
+            }
+        }
+        overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Args;
+    use clap::Parser;
+    use klmd::config::Config;
+
+    #[test]
+    fn options_override_configuration() {
+        let args = Args::try_parse_from(["klmd", "-f", "--set", "socket.mode=0o600", "--socket", "/tmp/k=1.sock",
+                                         "--state-file", "/tmp/klm.state", "--log-level", "info",
+                                         "--driver", "MS1563"]).unwrap();
+        assert!(args.foreground && !args.daemon && !args.dry_run);
+        let config = Config::parse("", &args.overrides()).unwrap();
+        assert_eq!((config.socket.path.as_str(), config.socket.mode), ("/tmp/k=1.sock", 0o600));
+        assert_eq!((config.state_file.as_str(), config.log_level.as_str()), ("/tmp/klm.state", "info"));
+        assert_eq!(config.driver.name.as_deref(), Some("MS1563"));
+        assert!(Args::try_parse_from(["klmd", "--log-level", "loud"]).is_err());
+        assert!(!Args::try_parse_from(["klmd"]).unwrap().daemon);
+        assert!(Args::try_parse_from(["klmd", "-d"]).unwrap().daemon);
+        assert!(Args::try_parse_from(["klmd", "-d", "-f"]).is_err());
+    }
+}
@@ -0,0 +1,345 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2022 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+//Settings of klmd read from /etc/klmd/klmd.toml. Every setting has a
+//default, so the file and any of its sections may be omitted. Values
+//may be overridden from command line as dotted keys, e.g.
+//--set socket.group=wheel.
+
+use crate::drivers::plugin::PLUGIN_DIR;
+use crate::drivers::template::TEMPLATE_DIR;
+use crate::error::{KlmError, KlmResult};
+use crate::keyboard::{Keyboard, KeyboardState};
+use crate::util::color;
+use crate::util::log;
+
+use klm_proto::names::{parse_color, parse_mode};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use users::{Users, UsersCache};
+
+const TAG: &'static str = "config";
+pub const CONFIG_FILENAME: &'static str = "/etc/klmd/klmd.toml";
+
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketConfig {
+    pub path: String,
+    pub json_path: String,
+    //Group owning sockets and their permission bits
+    pub group: String,
+    pub mode: u32,
+    //Users allowed to connect besides root. Everyone with access
+    //to socket file may connect if list is empty.
+    pub allowed_users: Vec<String>,
+}
+
+impl Default for SocketConfig {
+    fn default() -> SocketConfig {
+        SocketConfig {
+            path: "/var/run/klmd.sock".to_string(),
+            json_path: "/var/run/klmd-json.sock".to_string(),
+            group: "klm".to_string(),
+            mode: 0o660,
+            allowed_users: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DriverConfig {
+    //Use only driver of this name, even if other supported
+    //keyboards are attached
+    pub name: Option<String>,
+    pub templates: String,
+    pub plugins: String,
+}
+
+impl Default for DriverConfig {
+    fn default() -> DriverConfig {
+        DriverConfig {
+            name: None,
+            templates: TEMPLATE_DIR.to_string(),
+            plugins: PLUGIN_DIR.to_string(),
+        }
+    }
+}
+
+//State applied on start when there is no saved state
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootState {
+    pub mode: String,
+    pub colors: Vec<String>,
+    pub brightness: u8,
+    pub speed: u8,
+    pub power: bool,
+}
+
+impl Default for BootState {
+    fn default() -> BootState {
+        BootState {
+            mode: "off".to_string(),
+            colors: vec!["#000000".to_string()],
+            brightness: 0,
+            speed: 0,
+            power: false,
+        }
+    }
+}
+
+impl BootState {
+    fn validate(&self) -> Result<(), String> {
+        parse_mode(&self.mode)?;
+        if self.colors.is_empty() {
+            return Err("boot_state.colors must have at least one color".to_string());
+        }
+        for color in self.colors.iter() {
+            parse_color(color)?;
+        }
+        Ok(())
+    }
+
+    //Limits of keyboard are only known once driver is open, so
+    //they are checked here and not in validate
+    pub fn apply(&self, keyboard: &mut Keyboard) -> KlmResult<()> {
+        let capabilities = keyboard.get_capabilities();
+        for (name, value, range) in [("brightness", self.brightness, capabilities.brightness_range),
+                                     ("speed", self.speed, capabilities.speed_range)] {
+            if value < range.0 || value > range.1 {
+                return Err(KlmError::Config(format!("boot_state.{} {} is out of range {}..{} of {}",
+                                                    name, value, range.0, range.1, capabilities.name)));
+            }
+        }
+        let mode = parse_mode(&self.mode).map_err(KlmError::Config)?;
+        keyboard.set_state(KeyboardState::from(mode))?;
+        keyboard.reset_colors();
+        for color in self.colors.iter() {
+            let color = parse_color(color).map_err(KlmError::Config)?;
+            keyboard.add_color(color::RGB::new(color.r, color.g, color.b))?;
+        }
+        keyboard.set_brightness(self.brightness)?;
+        keyboard.set_speed(self.speed)?;
+        keyboard.set_power(self.power);
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    //debug, info, warn or error
+    pub log_level: String,
+    pub state_file: String,
+    pub profiles_dir: String,
+    pub socket: SocketConfig,
+    pub driver: DriverConfig,
+    pub boot_state: BootState,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            log_level: "debug".to_string(),
+            state_file: "/var/cache/klm/klm.state".to_string(),
+            profiles_dir: "/var/lib/klmd/profiles".to_string(),
+            socket: SocketConfig::default(),
+            driver: DriverConfig::default(),
+            boot_state: BootState::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(text: &str, overrides: &[String]) -> KlmResult<Config> {
+        let mut table: toml::Table = toml::from_str(text).map_err(|e| KlmError::Config(e.to_string()))?;
+        for assignment in overrides {
+            apply_override(&mut table, assignment)?;
+        }
+        let config: Config = toml::Value::Table(table).try_into().map_err(|e| KlmError::Config(e.to_string()))?;
+        config.validate().map_err(KlmError::Config)?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        log::LogLevel::from_name(&self.log_level)
+            .ok_or_else(|| format!("unknown log_level '{}', expected debug, info, warn or error", self.log_level))?;
+        if self.socket.mode > 0o777 {
+            return Err(format!("socket mode {:o} is not a permission mode", self.socket.mode));
+        }
+        self.boot_state.validate()
+    }
+
+    pub fn log_level(&self) -> log::LogLevel {
+        log::LogLevel::from_name(&self.log_level).unwrap_or(log::LogLevel::DEBUG)
+    }
+
+    //User ids allowed to connect, None if everyone is allowed.
+    //Unknown users are skipped.
+    pub fn allowed_uids(&self) -> Option<Vec<u32>> {
+        if self.socket.allowed_users.is_empty() {
+            return None;
+        }
+        let cache = UsersCache::new();
+        Some(self.socket.allowed_users.iter().filter_map(|name| {
+            let user = cache.get_user_by_name(name);
+            if user.is_none() {
+                log::w(TAG, &format!("Allowed user {} does not exist", name));
+            }
+            user.map(|user| user.uid())
+        }).collect())
+    }
+
+    //Applies settings used by keyboard thread
+    pub fn apply(&self, keyboard: &mut Keyboard) {
+        log::set_level(self.log_level());
+        keyboard.set_state_file(Path::new(&self.state_file));
+        keyboard.set_profiles_dir(Path::new(&self.profiles_dir));
+    }
+}
+
+//Value of override is read as TOML, so numbers, booleans and arrays
+//keep their types. Anything else is taken as a string.
+fn apply_override(table: &mut toml::Table, assignment: &str) -> KlmResult<()> {
+    let (key, value) = assignment.split_once('=')
+        .ok_or_else(|| KlmError::Config(format!("override '{}' is not key=value", assignment)))?;
+    let value = toml::from_str::<toml::Table>(&format!("value = {}", value)).ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()));
+    let mut path: Vec<&str> = key.trim().split('.').collect();
+    let last = path.pop().unwrap_or_default();
+    let mut table = table;
+    for section in path {
+        table = table.entry(section).or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| KlmError::Config(format!("{} in '{}' is not a section", section, key)))?;
+    }
+    table.insert(last.to_string(), value);
+    Ok(())
+}
+
+//Where configuration comes from. Kept by listener, so file is read
+//again with the same overrides on reload.
+#[derive(Clone, Debug)]
+pub struct ConfigLoader {
+    path: PathBuf,
+    //Missing file is an error only if its path was given explicitly
+    required: bool,
+    overrides: Vec<String>,
+}
+
+impl Default for ConfigLoader {
+    fn default() -> ConfigLoader {
+        ConfigLoader {
+            path: PathBuf::from(CONFIG_FILENAME),
+            required: false,
+            overrides: vec![],
+        }
+    }
+}
+
+impl ConfigLoader {
+    pub fn new(path: Option<&Path>, overrides: Vec<String>) -> ConfigLoader {
+        ConfigLoader {
+            path: path.map(Path::to_path_buf).unwrap_or_else(|| PathBuf::from(CONFIG_FILENAME)),
+            required: path.is_some(),
+            overrides,
+        }
+    }
+
+    pub fn load(&self) -> KlmResult<Config> {
+        let text = if !self.required && !self.path.exists() {
+            log::d(TAG, &format!("{} does not exist, using defaults", self.path.display()));
+            String::new()
+        } else {
+            std::fs::read_to_string(&self.path)
+                .map_err(|e| KlmError::Config(format!("can not read {}: {}", self.path.display(), e)))?
+        };
+        Config::parse(&text, &self.overrides)
+            .map_err(|e| KlmError::Config(format!("{}: {}", self.path.display(), e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use crate::error::KlmError;
+    use crate::drivers::ms1563::MS1563;
+    use crate::drivers::transport::RecordingTransport;
+    use crate::keyboard::Keyboard;
+    use crate::util::u8::U8VecSerializable;
+
+    #[test]
+    fn empty_file_is_default_config() {
+        assert_eq!(Config::parse("", &[]).unwrap(), Config::default());
+        assert_eq!(Config::parse(include_str!("../config/klmd.toml"), &[]).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn config_file_is_parsed() {
+        let config = Config::parse(r##"
+            log_level = "warn"
+            state_file = "/tmp/klm.state"
+
+            [socket]
+            group = "wheel"
+            mode = 0o600
+            allowed_users = ["root"]
+
+            [driver]
+            name = "MS1563"
+
+            [boot_state]
+            mode = "breathing"
+            colors = ["#ff8800", "0,0,255"]
+            brightness = 7
+        "##, &[]).unwrap();
+        assert_eq!((config.socket.group.as_str(), config.socket.mode), ("wheel", 0o600));
+        assert_eq!(config.socket.path, "/var/run/klmd.sock");
+        assert_eq!(config.driver.name.as_deref(), Some("MS1563"));
+        assert_eq!(config.allowed_uids(), Some(vec![0]));
+        assert_eq!(Config::default().allowed_uids(), None);
+        assert!(Config::parse("[socket]\nowner = \"root\"", &[]).is_err());
+        assert!(Config::parse("log_level = \"loud\"", &[]).is_err());
+        assert!(Config::parse("[boot_state]\ncolors = [\"#ff88\"]", &[]).is_err());
+        assert!(Config::parse("[boot_state]\ncolors = []", &[]).is_err());
+    }
+
+    #[test]
+    fn overrides_replace_file_values() {
+        let overrides = ["socket.mode=0o666".to_string(), "socket.group=wheel".to_string(),
+                         "boot_state.power=true".to_string(), "log_level=error".to_string()];
+        let config = Config::parse("[socket]\ngroup = \"klm\"", &overrides).unwrap();
+        assert_eq!((config.socket.group.as_str(), config.socket.mode), ("wheel", 0o666));
+        assert!(config.boot_state.power);
+        assert_eq!(config.log_level, "error");
+        assert!(Config::parse("", &["log_level".to_string()]).is_err());
+        assert!(Config::parse("", &["log_level.debug=1".to_string()]).is_err());
+    }
+
+    #[test]
+    fn boot_state_is_applied() {
+        let mut keyboard = Keyboard::new(Box::new(MS1563::with_transport(Box::new(RecordingTransport::new()))));
+        let config = Config::parse("[boot_state]\nmode = \"steady\"\ncolors = [\"#010203\"]\nbrightness = 4\npower = true",
+                                   &[]).unwrap();
+        config.boot_state.apply(&mut keyboard).unwrap();
+        assert_eq!(keyboard.get_status().to_u8_vec(), vec![0x01, 1, 4, 0, 1, 1, 1, 2, 3]);
+        for (field, text) in [("brightness", "brightness = 11"), ("speed", "speed = 3")] {
+            let config = Config::parse(&format!("[boot_state]\n{}", text), &[]).unwrap();
+            match config.boot_state.apply(&mut keyboard) {
+                Err(KlmError::Config(message)) => assert!(message.contains(field)),
+                _ => panic!("{} out of range must be a config error", field),
+            }
+        }
+        assert_eq!(keyboard.get_status().brightness, 4);
+    }
+}
@@ -0,0 +1,177 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::config::DriverConfig;
+use crate::drivers;
+use crate::drivers::driver::Driver;
+use crate::error::{KlmError, KlmResult};
+use crate::keyboard::Keyboard;
+use crate::util::log;
+
+use std::time::{Duration, Instant};
+
+const TAG: &'static str = "devices";
+//Keyboard which is attached but fails to open is retried after this
+//delay, doubled on every failure up to the maximum
+const REOPEN_DELAY: Duration = Duration::from_secs(4);
+const MAX_REOPEN_DELAY: Duration = Duration::from_secs(64);
+
+pub enum DeviceChange {
+    Connected,
+    Removed,
+}
+
+//Source of keyboard drivers. Listener asks it to reopen keyboard
+//on reload and to check whether keyboard is still attached.
+pub trait Devices {
+    fn reload(&mut self, keyboard: &mut Keyboard) -> KlmResult<()>;
+    fn poll(&mut self, keyboard: &mut Keyboard) -> KlmResult<Option<DeviceChange>>;
+    //Applies reloaded configuration, used on next reload
+    fn configure(&mut self, _config: &DriverConfig) {}
+}
+
+//Attached HID device, as listed by klmd --list-devices
+pub struct DeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub product: Option<String>,
+    //Driver which would be used for device
+    pub driver: Option<String>,
+}
+
+pub struct HidDevices {
+    api: hidapi::HidApi,
+    present: bool,
+    config: DriverConfig,
+    //Built once per configuration, so templates and plugins
+    //are not loaded again on every poll
+    registry: drivers::DriverRegistry,
+    reopen_delay: Duration,
+    reopen_at: Option<Instant>,
+}
+
+//Builtin drivers along with templates and plugins, only the
+//configured one if driver name is set
+fn registry(config: &DriverConfig) -> drivers::DriverRegistry {
+    let mut registry = drivers::DriverRegistry::with_builtin();
+    drivers::template::load_templates(&mut registry, &config.templates);
+    drivers::plugin::load_plugins(&mut registry, &config.plugins);
+    if let Some(name) = &config.name {
+        registry.select(name);
+    }
+    registry
+}
+
+impl HidDevices {
+    pub fn new(config: DriverConfig) -> KlmResult<HidDevices> {
+        Ok(HidDevices {
+            api: hidapi::HidApi::new()?,
+            present: false,
+            registry: registry(&config),
+            config,
+            reopen_delay: REOPEN_DELAY,
+            reopen_at: None,
+        })
+    }
+
+    //Opens first supported device
+    pub fn open_driver(&mut self) -> KlmResult<Box<dyn Driver>> {
+        self.api.refresh_devices()?;
+        if let Some(name) = &self.config.name {
+            if self.registry.is_empty() {
+                return Err(KlmError::Driver(format!("driver {} is not registered", name)));
+            }
+        }
+        let driver = self.registry.probe(&self.api)
+            .ok_or_else(|| KlmError::Driver("no compatiable keyboard found".to_string()))?;
+        self.present = true;
+        self.reopen_delay = REOPEN_DELAY;
+        self.reopen_at = None;
+        Ok(driver)
+    }
+
+    pub fn list(&mut self) -> KlmResult<Vec<DeviceInfo>> {
+        self.api.refresh_devices()?;
+        let mut devices: Vec<DeviceInfo> = self.api.devices().iter().map(|device| DeviceInfo {
+            vendor_id: device.vendor_id,
+            product_id: device.product_id,
+            product: device.product_string.clone(),
+            driver: self.registry.find(device.vendor_id, device.product_id).map(|entry| entry.name.clone()),
+        }).collect();
+        //Device with several interfaces is listed once
+        devices.dedup_by(|a, b| a.vendor_id == b.vendor_id && a.product_id == b.product_id);
+        Ok(devices)
+    }
+
+    fn is_attached(&self, vendor_id: u16, product_id: u16) -> bool {
+        self.api.devices().iter().any(|device| device.vendor_id == vendor_id &&
+            device.product_id == product_id)
+    }
+}
+
+impl Devices for HidDevices {
+    fn reload(&mut self, keyboard: &mut Keyboard) -> KlmResult<()> {
+        keyboard.set_driver(self.open_driver()?);
+        keyboard.sync()
+    }
+
+    fn configure(&mut self, config: &DriverConfig) {
+        self.registry = registry(config);
+        self.config = config.clone();
+    }
+
+    fn poll(&mut self, keyboard: &mut Keyboard) -> KlmResult<Option<DeviceChange>> {
+        self.api.refresh_devices()?;
+        let capabilities = keyboard.get_capabilities();
+        let attached = self.is_attached(capabilities.vendor_id, capabilities.product_id);
+        if attached == self.present {
+            return Ok(None);
+        }
+        if !attached {
+            log::w(TAG, &format!("Keyboard {} was removed", capabilities.name));
+            self.present = false;
+            self.reopen_delay = REOPEN_DELAY;
+            self.reopen_at = None;
+            return Ok(Some(DeviceChange::Removed));
+        }
+        if self.reopen_at.is_some_and(|at| Instant::now() < at) {
+            return Ok(None);
+        }
+        log::i(TAG, &format!("Keyboard {} is connected again", capabilities.name));
+        if let Err(e) = self.reload(keyboard) {
+            log::w(TAG, &format!("Unable to open keyboard, retrying in {}s", self.reopen_delay.as_secs()));
+            self.reopen_at = Some(Instant::now() + self.reopen_delay);
+            self.reopen_delay = (self.reopen_delay * 2).min(MAX_REOPEN_DELAY);
+            return Err(e);
+        }
+        Ok(Some(DeviceChange::Connected))
+    }
+}
+
+//Devices of dry run: keyboard is driven by null driver, which
+//is never removed
+pub struct NullDevices;
+
+impl NullDevices {
+    pub fn open_driver(&mut self) -> Box<dyn Driver> {
+        Box::new(drivers::null::NullDriver)
+    }
+}
+
+impl Devices for NullDevices {
+    fn reload(&mut self, keyboard: &mut Keyboard) -> KlmResult<()> {
+        keyboard.sync()
+    }
+
+    fn poll(&mut self, _keyboard: &mut Keyboard) -> KlmResult<Option<DeviceChange>> {
+        Ok(None)
+    }
+}
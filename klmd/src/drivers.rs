@@ -11,3 +11,101 @@
 
 pub mod driver;
 pub mod ms1563;
+pub mod null;
+pub mod plugin;
+pub mod template;
+pub mod transport;
+
+use crate::drivers::driver::Driver;
+use crate::util::log;
+
+const TAG: &'static str = "drivers";
+
+pub type DriverConstructor = Box<dyn Fn(&hidapi::HidApi) -> Option<Box<dyn Driver>>>;
+
+//Describes a driver known to klmd: which device
+//it handles and how to open it
+pub struct DriverEntry {
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub constructor: DriverConstructor,
+}
+
+impl DriverEntry {
+    pub fn new(name: &str, vendor_id: u16, product_id: u16,
+               constructor: DriverConstructor) -> DriverEntry {
+        DriverEntry {
+            name: name.to_string(),
+            vendor_id,
+            product_id,
+            constructor,
+        }
+    }
+
+    pub fn matches(&self, vendor_id: u16, product_id: u16) -> bool {
+        self.vendor_id == vendor_id && self.product_id == product_id
+    }
+}
+
+//Registry of all drivers available at runtime. Drivers
+//register themselves here and the registry picks the one
+//matching an attached device.
+#[derive(Default)]
+pub struct DriverRegistry {
+    entries: Vec<DriverEntry>,
+}
+
+impl DriverRegistry {
+    pub fn new() -> DriverRegistry {
+        DriverRegistry::default()
+    }
+
+    //Creates registry with all drivers compiled into klmd
+    pub fn with_builtin() -> DriverRegistry {
+        let mut registry = DriverRegistry::new();
+        ms1563::register(&mut registry);
+        registry
+    }
+
+    pub fn register(&mut self, entry: DriverEntry) {
+        log::d(TAG, &format!("Registering driver {} for {:04x}:{:04x}", entry.name,
+                             entry.vendor_id, entry.product_id));
+        if self.find(entry.vendor_id, entry.product_id).is_some() {
+            log::w(TAG, &format!("Driver {} shadows previously registered driver for {:04x}:{:04x}",
+                                 entry.name, entry.vendor_id, entry.product_id));
+        }
+        self.entries.insert(0, entry);
+    }
+
+    //Forgets every driver except ones with given name. Returns
+    //false if there is no such driver.
+    pub fn select(&mut self, name: &str) -> bool {
+        self.entries.retain(|entry| entry.name == name);
+        !self.entries.is_empty()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn find(&self, vendor_id: u16, product_id: u16) -> Option<&DriverEntry> {
+        self.entries.iter().find(|entry| entry.matches(vendor_id, product_id))
+    }
+
+    //Enumerates attached HID devices and opens the first one
+    //for which a driver is registered
+    pub fn probe(&self, api: &hidapi::HidApi) -> Option<Box<dyn Driver>> {
+        for device in api.devices() {
+            if let Some(entry) = self.find(device.vendor_id, device.product_id) {
+                log::i(TAG, &format!("Found {:04x}:{:04x}, using driver {}", device.vendor_id,
+                                     device.product_id, entry.name));
+                if let Some(driver) = (entry.constructor)(api) {
+                    return Some(driver);
+                }
+                log::e(TAG, &format!("Driver {} failed to open device", entry.name));
+            }
+        }
+        None
+    }
+}
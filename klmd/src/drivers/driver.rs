@@ -9,8 +9,9 @@
  * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
  */
 
+use crate::error::KlmResult;
 use crate::util::color;
-use crate::util::u8::U8Serializable;
+use crate::util::u8::{U8Serializable, U8VecSerializable};
 
 //use hidapi::HidApi;
 
@@ -21,6 +22,17 @@ pub enum KeyboardMode {
     ModeColorshift,
 }
 
+impl KeyboardMode {
+    pub fn from_u8(byte: u8) -> Option<KeyboardMode> {
+        match byte {
+            0x1 => Some(KeyboardMode::ModeSteady),
+            0x2 => Some(KeyboardMode::ModeBreathing),
+            0x3 => Some(KeyboardMode::ModeColorshift),
+            _ => None,
+        }
+    }
+}
+
 impl U8Serializable for KeyboardMode {
     fn to_u8(&self) -> u8 {
         match *self {
@@ -31,13 +43,91 @@ impl U8Serializable for KeyboardMode {
     }
 }
 
+//Describes how keyboard lightning is split into
+//independently colored areas
+#[derive(Clone)]
+pub enum KeyboardLayout {
+    Zones(u8),
+    PerKey { rows: u8, columns: u8 },
+}
+
+impl U8VecSerializable for KeyboardLayout {
+    fn to_u8_vec(&self) -> Vec<u8> {
+        match *self {
+            KeyboardLayout::Zones(zones) => vec![0x0, zones, 0x0],
+            KeyboardLayout::PerKey { rows, columns } => vec![0x1, rows, columns],
+        }
+    }
+}
+
+//Longer names are truncated to keep capabilities in one response,
+//plugins and templates declaring them are rejected
+pub const MAX_NAME_LENGTH: usize = 64;
+
+//Everything client needs to know about keyboard
+//to build valid requests
+pub struct Capabilities {
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub modes: Vec<KeyboardMode>,
+    pub brightness_range: (u8, u8),
+    pub speed_range: (u8, u8),
+    pub max_colors: u8,
+    pub can_power_on: bool,
+    pub layout: KeyboardLayout,
+}
+
+impl U8VecSerializable for Capabilities {
+    fn to_u8_vec(&self) -> Vec<u8> {
+        let name = self.name.as_bytes();
+        let name_len = name.len().min(MAX_NAME_LENGTH);
+        let mut result = vec![name_len as u8];
+        result.extend(&name[..name_len]);
+        result.extend(self.vendor_id.to_be_bytes());
+        result.extend(self.product_id.to_be_bytes());
+        result.push(self.modes.len() as u8);
+        result.extend(self.modes.to_u8_vec());
+        result.push(self.brightness_range.0);
+        result.push(self.brightness_range.1);
+        result.push(self.speed_range.0);
+        result.push(self.speed_range.1);
+        result.push(self.max_colors);
+        result.push(self.can_power_on as u8);
+        result.extend(self.layout.to_u8_vec());
+        result
+    }
+}
+
+//Drivers are constructed through DriverRegistry, so the
+//trait only describes operations on an opened device
 pub trait Driver {
-    fn new(api: &hidapi::HidApi) -> Option<Self> where Self: Sized;
-    fn is_present(api: &hidapi::HidApi) -> bool where Self: Sized;
-    fn set_color(&self, color: &color::RGB, brightness: u8) -> bool;
-    fn set_breathing(&self, colors: &Vec<color::RGB>, brightness: u8, speed: u8) -> bool;
-    fn set_shift(&self, colors: &Vec<color::RGB>, brightness: u8, speed: u8) -> bool;
-    fn set_power(&self, value: bool) -> bool;
+    fn set_color(&self, color: &color::RGB, brightness: u8) -> KlmResult<()>;
+    fn set_breathing(&self, colors: &Vec<color::RGB>, brightness: u8, speed: u8) -> KlmResult<()>;
+    fn set_shift(&self, colors: &Vec<color::RGB>, brightness: u8, speed: u8) -> KlmResult<()>;
+    fn set_power(&self, value: bool) -> KlmResult<()>;
     fn get_modes(&self) -> Vec<KeyboardMode>;
     fn get_max_colors(&self) -> u8;
+    fn get_name(&self) -> String;
+    fn get_vendor_id(&self) -> u16;
+    fn get_product_id(&self) -> u16;
+    //Inclusive ranges of values accepted by driver
+    fn get_brightness_range(&self) -> (u8, u8);
+    fn get_speed_range(&self) -> (u8, u8);
+    fn can_power_on(&self) -> bool;
+    fn get_layout(&self) -> KeyboardLayout;
+
+    fn get_capabilities(&self) -> Capabilities {
+        Capabilities {
+            name: self.get_name(),
+            vendor_id: self.get_vendor_id(),
+            product_id: self.get_product_id(),
+            modes: self.get_modes(),
+            brightness_range: self.get_brightness_range(),
+            speed_range: self.get_speed_range(),
+            max_colors: self.get_max_colors(),
+            can_power_on: self.can_power_on(),
+            layout: self.get_layout(),
+        }
+    }
 }
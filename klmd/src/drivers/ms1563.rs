@@ -10,9 +10,13 @@
  */
 
 use crate::drivers::driver;
+use crate::drivers::driver::Driver;
+use crate::drivers::transport::Transport;
+use crate::drivers::{DriverEntry, DriverRegistry};
+use crate::error::{KlmError, KlmResult};
 use crate::util::log;
 use crate::util::color;
-use crate::driver::KeyboardMode;
+use crate::drivers::driver::{KeyboardLayout, KeyboardMode};
 
 //use hidapi::HidApi;
 //use hidapi::HidDevice;
@@ -24,47 +28,59 @@ const VENDOR_ID: u16 = 0x1462;
 const PRODUCT_ID: u16 = 0x1563;
 
 pub struct MS1563 {
-    device: hidapi::HidDevice,
+    device: Box<dyn Transport>,
 }
 
-impl MS1563 {
-    fn get_buffer() -> [u8; 64] {
-        let mut buffer = [0; 64];
-        buffer[0] = 0x02;
-        buffer
-    }
-
-    fn write_buffer(&self, buffer: &[u8; 64]) -> bool {
-        if let Ok(_) = self.device.send_feature_report(buffer) {
-            log::d(TAG, "Succesfully written buffer.");
-            true
-        } else {
-            log::e(TAG, "Failed writing buffer.");
-            false
+pub fn register(registry: &mut DriverRegistry) {
+    registry.register(DriverEntry::new(TAG, VENDOR_ID, PRODUCT_ID, Box::new(|api| {
+        if !MS1563::is_present(api) {
+            return None;
         }
-    }
+        let driver: Box<dyn Driver> = Box::new(MS1563::new(api)?);
+        Some(driver)
+    })));
 }
 
-impl driver::Driver for MS1563 {
-    fn new(api: &hidapi::HidApi) -> Option<MS1563> {
+impl MS1563 {
+    pub fn new(api: &hidapi::HidApi) -> Option<MS1563> {
         log::i(TAG, "Opening MS1563 device");
         if let Ok(_device) = api.open(VENDOR_ID, PRODUCT_ID) {
-            Some(MS1563 {
-                device: _device,
-            })
+            Some(MS1563::with_transport(Box::new(_device)))
         } else {
             log::e(TAG, "Opening device failed. Check that program has right access rights.");
-            log::panic(TAG, "Unable to open MS1563 device!");
             None
         }
     }
 
-    fn is_present(_api: &hidapi::HidApi) -> bool {
-        log::e(TAG, "is_present not implemented");
-        true
+    pub fn with_transport(transport: Box<dyn Transport>) -> MS1563 {
+        MS1563 {
+            device: transport,
+        }
+    }
+
+    pub fn is_present(api: &hidapi::HidApi) -> bool {
+        api.devices().iter().any(|device| device.vendor_id == VENDOR_ID &&
+            device.product_id == PRODUCT_ID)
+    }
+
+    fn get_buffer() -> [u8; 64] {
+        let mut buffer = [0; 64];
+        buffer[0] = 0x02;
+        buffer
     }
 
-    fn set_color(&self, color: &color::RGB, _brightness: u8) -> bool {
+    fn write_buffer(&self, buffer: &[u8; 64]) -> KlmResult<()> {
+        if let Err(e) = self.device.send_feature_report(buffer) {
+            log::e(TAG, "Failed writing buffer.");
+            return Err(KlmError::Hid(e));
+        }
+        log::d(TAG, "Succesfully written buffer.");
+        Ok(())
+    }
+}
+
+impl driver::Driver for MS1563 {
+    fn set_color(&self, color: &color::RGB, _brightness: u8) -> KlmResult<()> {
         let mut brightness = _brightness;
         if brightness > 10 {
             log::w(TAG, &format!("Requested brightnesss is too big: {}", brightness));
@@ -80,10 +96,10 @@ impl driver::Driver for MS1563 {
         self.write_buffer(&buffer)
     }
 
-    fn set_breathing(&self, colors: &Vec<color::RGB>, _brightness: u8, _speed: u8) -> bool {
+    fn set_breathing(&self, colors: &Vec<color::RGB>, _brightness: u8, _speed: u8) -> KlmResult<()> {
         if colors.len() > 7 {
             log::w(TAG, "Color vector is too large, ignoring request");
-            return false;
+            return Err(KlmError::Driver(format!("MS1563 supports at most 7 colors, got {}", colors.len())));
         }
         let mut brightness = _brightness;
         if brightness > 10 {
@@ -110,10 +126,10 @@ impl driver::Driver for MS1563 {
         self.write_buffer(&buffer)
     }
 
-    fn set_shift(&self, colors: &Vec<color::RGB>, _brightness: u8, _speed: u8) -> bool {
+    fn set_shift(&self, colors: &Vec<color::RGB>, _brightness: u8, _speed: u8) -> KlmResult<()> {
         if colors.len() > 7 {
             log::w(TAG, "Color vector is too large, ignoring request");
-            return false;
+            return Err(KlmError::Driver(format!("MS1563 supports at most 7 colors, got {}", colors.len())));
         }
         let mut brightness = _brightness;
         if brightness > 10 {
@@ -140,14 +156,13 @@ impl driver::Driver for MS1563 {
         self.write_buffer(&buffer)
     }
 
-    fn set_power(&self, value: bool) -> bool {
+    fn set_power(&self, value: bool) -> KlmResult<()> {
         if !value {
             log::d(TAG, "Powering off keyboard lightning");
-            self.write_buffer(&MS1563::get_buffer());
-            true
+            self.write_buffer(&MS1563::get_buffer())
         } else {
             log::e(TAG, "Powering on keyboard lightning is not supported for MS1563");
-            false
+            Err(KlmError::Driver("powering on is not supported for MS1563".to_string()))
         }
     }
 
@@ -155,6 +170,131 @@ impl driver::Driver for MS1563 {
         MS1563_SUPPORTED_MODES.to_vec()
     }
     fn get_max_colors(&self) -> u8 {
-        8
+        7
+    }
+
+    fn get_name(&self) -> String {
+        TAG.to_string()
+    }
+
+    fn get_vendor_id(&self) -> u16 {
+        VENDOR_ID
+    }
+
+    fn get_product_id(&self) -> u16 {
+        PRODUCT_ID
+    }
+
+    fn get_brightness_range(&self) -> (u8, u8) {
+        (0, 10)
+    }
+
+    fn get_speed_range(&self) -> (u8, u8) {
+        (0, 2)
+    }
+
+    fn can_power_on(&self) -> bool {
+        false
+    }
+
+    fn get_layout(&self) -> KeyboardLayout {
+        KeyboardLayout::Zones(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MS1563;
+    use crate::drivers::driver::Driver;
+    use crate::drivers::transport::RecordingTransport;
+    use crate::util::color;
+    use crate::util::u8::U8VecSerializable;
+
+    fn driver() -> (MS1563, RecordingTransport) {
+        let transport = RecordingTransport::new();
+        (MS1563::with_transport(Box::new(transport.clone())), transport)
+    }
+
+    fn report(header: &[u8]) -> Vec<u8> {
+        let mut report = vec![0u8; 64];
+        report[..header.len()].copy_from_slice(header);
+        report
+    }
+
+    fn colors() -> Vec<color::RGB> {
+        vec![color::RGB::new(0xff, 0x00, 0x00), color::RGB::new(0x00, 0xff, 0x00),
+             color::RGB::new(0x00, 0x00, 0xff)]
+    }
+
+    #[test]
+    fn set_color_writes_steady_report() {
+        let (driver, transport) = driver();
+        assert!(driver.set_color(&color::RGB::new(0x12, 0x34, 0x56), 7).is_ok());
+        assert_eq!(transport.feature_reports(),
+                   vec![report(&[0x02, 0x00, 0x01, 0x00, 0x07, 0x01, 0x12, 0x34, 0x56])]);
+    }
+
+    #[test]
+    fn set_color_clamps_brightness() {
+        let (driver, transport) = driver();
+        assert!(driver.set_color(&color::RGB::new(1, 2, 3), 200).is_ok());
+        assert_eq!(transport.feature_reports(),
+                   vec![report(&[0x02, 0x00, 0x01, 0x00, 0x0a, 0x01, 1, 2, 3])]);
+    }
+
+    #[test]
+    fn set_breathing_writes_color_vector() {
+        let (driver, transport) = driver();
+        assert!(driver.set_breathing(&colors(), 5, 1).is_ok());
+        assert_eq!(transport.feature_reports(),
+                   vec![report(&[0x02, 0x00, 0x02, 0x01, 0x05, 0x03,
+                                 0xff, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0xff])]);
+    }
+
+    #[test]
+    fn set_shift_writes_color_vector_and_clamps_speed() {
+        let (driver, transport) = driver();
+        assert!(driver.set_shift(&colors(), 10, 9).is_ok());
+        assert_eq!(transport.feature_reports(),
+                   vec![report(&[0x02, 0x00, 0x05, 0x02, 0x0a, 0x03,
+                                 0xff, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0xff])]);
+    }
+
+    #[test]
+    fn too_many_colors_are_rejected() {
+        let (driver, transport) = driver();
+        let colors: Vec<color::RGB> = (0..8).map(|i| color::RGB::new(i, i, i)).collect();
+        assert!(driver.set_breathing(&colors, 5, 1).is_err());
+        assert!(driver.set_shift(&colors, 5, 1).is_err());
+        assert!(transport.feature_reports().is_empty());
+    }
+
+    #[test]
+    fn set_power_off_writes_empty_report() {
+        let (driver, transport) = driver();
+        assert!(driver.set_power(false).is_ok());
+        assert_eq!(transport.feature_reports(), vec![report(&[0x02])]);
+    }
+
+    #[test]
+    fn set_power_on_is_not_supported() {
+        let (driver, transport) = driver();
+        assert!(driver.set_power(true).is_err());
+        assert!(transport.feature_reports().is_empty());
+    }
+
+    #[test]
+    fn capabilities_describe_ms1563() {
+        let (driver, _) = driver();
+        let capabilities = driver.get_capabilities().to_u8_vec();
+        assert_eq!(capabilities, vec![6, b'M', b'S', b'1', b'5', b'6', b'3', 0x14, 0x62, 0x15, 0x63,
+                                      3, 0x1, 0x2, 0x3, 0, 10, 0, 2, 7, 0, 0x0, 1, 0x0]);
+    }
+
+    #[test]
+    fn transport_failure_is_reported() {
+        let (driver, transport) = driver();
+        transport.set_failing(true);
+        assert!(driver.set_color(&color::RGB::new(1, 2, 3), 5).is_err());
     }
 }
@@ -0,0 +1,83 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2022 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::drivers::driver::{Driver, KeyboardLayout, KeyboardMode};
+use crate::error::KlmResult;
+use crate::util::color;
+use crate::util::log;
+
+const TAG: &'static str = "null";
+
+//Driver which only logs what it would send to keyboard. Used by
+//dry runs, so klmd can be tried out without supported device.
+pub struct NullDriver;
+
+fn colors_to_s(colors: &[color::RGB]) -> String {
+    colors.iter().map(|color| color.to_s()).collect::<Vec<String>>().join(" ")
+}
+
+impl Driver for NullDriver {
+    fn set_color(&self, color: &color::RGB, brightness: u8) -> KlmResult<()> {
+        log::i(TAG, &format!("steady {}, brightness {}", color.to_s(), brightness));
+        Ok(())
+    }
+
+    fn set_breathing(&self, colors: &Vec<color::RGB>, brightness: u8, speed: u8) -> KlmResult<()> {
+        log::i(TAG, &format!("breathing {}, brightness {}, speed {}", colors_to_s(colors), brightness, speed));
+        Ok(())
+    }
+
+    fn set_shift(&self, colors: &Vec<color::RGB>, brightness: u8, speed: u8) -> KlmResult<()> {
+        log::i(TAG, &format!("color shift {}, brightness {}, speed {}", colors_to_s(colors), brightness, speed));
+        Ok(())
+    }
+
+    fn set_power(&self, value: bool) -> KlmResult<()> {
+        log::i(TAG, &format!("power {}", value));
+        Ok(())
+    }
+
+    fn get_modes(&self) -> Vec<KeyboardMode> {
+        vec![KeyboardMode::ModeSteady, KeyboardMode::ModeBreathing, KeyboardMode::ModeColorshift]
+    }
+
+    fn get_max_colors(&self) -> u8 {
+        7
+    }
+
+    fn get_name(&self) -> String {
+        "null".to_string()
+    }
+
+    fn get_vendor_id(&self) -> u16 {
+        0
+    }
+
+    fn get_product_id(&self) -> u16 {
+        0
+    }
+
+    fn get_brightness_range(&self) -> (u8, u8) {
+        (0, 10)
+    }
+
+    fn get_speed_range(&self) -> (u8, u8) {
+        (0, 2)
+    }
+
+    fn can_power_on(&self) -> bool {
+        true
+    }
+
+    fn get_layout(&self) -> KeyboardLayout {
+        KeyboardLayout::Zones(1)
+    }
+}
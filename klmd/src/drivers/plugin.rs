@@ -0,0 +1,401 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::drivers::driver;
+use crate::drivers::driver::{KeyboardLayout, KeyboardMode};
+use crate::drivers::{DriverEntry, DriverRegistry};
+use crate::error::{KlmError, KlmResult};
+use crate::util::color;
+use crate::util::log;
+
+use std::ffi::{c_char, c_int, c_void, CStr, OsStr};
+use std::path::Path;
+use std::rc::Rc;
+
+const TAG: &'static str = "plugin";
+pub const PLUGIN_DIR: &'static str = "/usr/lib/klmd/drivers";
+//Must be bumped on every change of KlmDriverDescriptor layout or semantics
+pub const KLM_DRIVER_ABI_VERSION: u32 = 2;
+const ENTRY_SYMBOL: &'static [u8] = b"klm_driver_entry\0";
+const KLM_LAYOUT_ZONES: u8 = 0x0;
+const KLM_LAYOUT_PER_KEY: u8 = 0x1;
+
+//C representation of a color passed to plugins
+#[repr(C)]
+pub struct KlmColor {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+type OpenFn = unsafe extern "C" fn(vendor_id: u16, product_id: u16) -> *mut c_void;
+type CloseFn = unsafe extern "C" fn(handle: *mut c_void);
+type SetColorFn = unsafe extern "C" fn(handle: *mut c_void, color: *const KlmColor,
+                                       brightness: u8) -> c_int;
+type SetColorsFn = unsafe extern "C" fn(handle: *mut c_void, colors: *const KlmColor,
+                                        n_colors: usize, brightness: u8, speed: u8) -> c_int;
+type SetPowerFn = unsafe extern "C" fn(handle: *mut c_void, power: c_int) -> c_int;
+
+//Descriptor returned by `klm_driver_entry` symbol of a plugin. It mirrors
+//drivers::driver::Driver trait; functions return 0 on success.
+//See include/klmd/driver.h for C definition.
+#[repr(C)]
+pub struct KlmDriverDescriptor {
+    pub abi_version: u32,
+    pub name: *const c_char,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub max_colors: u8,
+    pub brightness_min: u8,
+    pub brightness_max: u8,
+    pub speed_min: u8,
+    pub speed_max: u8,
+    pub can_power_on: u8,
+    pub layout: u8,
+    pub zones: u8,
+    pub rows: u8,
+    pub columns: u8,
+    pub n_modes: usize,
+    pub modes: *const u8,
+    pub open: Option<OpenFn>,
+    pub close: Option<CloseFn>,
+    pub set_color: Option<SetColorFn>,
+    pub set_breathing: Option<SetColorsFn>,
+    pub set_shift: Option<SetColorsFn>,
+    pub set_power: Option<SetPowerFn>,
+}
+
+type EntryFn = unsafe extern "C" fn() -> *const KlmDriverDescriptor;
+
+//Loaded and validated plugin. Library is kept alive as
+//long as any driver created from it exists.
+struct Plugin {
+    name: String,
+    modes: Vec<KeyboardMode>,
+    layout: KeyboardLayout,
+    descriptor: *const KlmDriverDescriptor,
+    _library: libloading::Library,
+}
+
+impl Plugin {
+    fn descriptor(&self) -> &KlmDriverDescriptor {
+        //Descriptor is validated on load and lives as long as library
+        unsafe { &*self.descriptor }
+    }
+}
+
+pub struct PluginDriver {
+    plugin: Rc<Plugin>,
+    handle: *mut c_void,
+}
+
+fn to_klm_colors(colors: &[color::RGB]) -> Vec<KlmColor> {
+    colors.iter().map(|color| KlmColor { r: color.r, g: color.g, b: color.b }).collect()
+}
+
+impl PluginDriver {
+    fn open(plugin: &Rc<Plugin>) -> Option<PluginDriver> {
+        let descriptor = plugin.descriptor();
+        log::i(TAG, &format!("Opening device using plugin {}", plugin.name));
+        let handle = unsafe { (descriptor.open.unwrap())(descriptor.vendor_id, descriptor.product_id) };
+        if handle.is_null() {
+            log::e(TAG, &format!("Plugin {} failed to open device", plugin.name));
+            return None;
+        }
+        Some(PluginDriver {
+            plugin: plugin.clone(),
+            handle,
+        })
+    }
+
+    fn check_result(&self, operation: &str, result: c_int) -> KlmResult<()> {
+        if result != 0 {
+            log::e(TAG, &format!("Plugin {}: {} failed with code {}", self.plugin.name, operation, result));
+            Err(KlmError::Driver(format!("{}: {} failed with code {}", self.plugin.name, operation, result)))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for PluginDriver {
+    fn drop(&mut self) {
+        unsafe { (self.plugin.descriptor().close.unwrap())(self.handle) };
+    }
+}
+
+impl driver::Driver for PluginDriver {
+    fn set_color(&self, color: &color::RGB, brightness: u8) -> KlmResult<()> {
+        let color = KlmColor { r: color.r, g: color.g, b: color.b };
+        let result = unsafe {
+            (self.plugin.descriptor().set_color.unwrap())(self.handle, &color, brightness)
+        };
+        self.check_result("set_color", result)
+    }
+
+    fn set_breathing(&self, colors: &Vec<color::RGB>, brightness: u8, speed: u8) -> KlmResult<()> {
+        let colors = to_klm_colors(colors);
+        let result = unsafe {
+            (self.plugin.descriptor().set_breathing.unwrap())(self.handle, colors.as_ptr(),
+                                                              colors.len(), brightness, speed)
+        };
+        self.check_result("set_breathing", result)
+    }
+
+    fn set_shift(&self, colors: &Vec<color::RGB>, brightness: u8, speed: u8) -> KlmResult<()> {
+        let colors = to_klm_colors(colors);
+        let result = unsafe {
+            (self.plugin.descriptor().set_shift.unwrap())(self.handle, colors.as_ptr(),
+                                                          colors.len(), brightness, speed)
+        };
+        self.check_result("set_shift", result)
+    }
+
+    fn set_power(&self, value: bool) -> KlmResult<()> {
+        let result = unsafe {
+            (self.plugin.descriptor().set_power.unwrap())(self.handle, value as c_int)
+        };
+        self.check_result("set_power", result)
+    }
+
+    fn get_modes(&self) -> Vec<KeyboardMode> {
+        self.plugin.modes.clone()
+    }
+
+    fn get_max_colors(&self) -> u8 {
+        self.plugin.descriptor().max_colors
+    }
+
+    fn get_name(&self) -> String {
+        self.plugin.name.clone()
+    }
+
+    fn get_vendor_id(&self) -> u16 {
+        self.plugin.descriptor().vendor_id
+    }
+
+    fn get_product_id(&self) -> u16 {
+        self.plugin.descriptor().product_id
+    }
+
+    fn get_brightness_range(&self) -> (u8, u8) {
+        let descriptor = self.plugin.descriptor();
+        (descriptor.brightness_min, descriptor.brightness_max)
+    }
+
+    fn get_speed_range(&self) -> (u8, u8) {
+        let descriptor = self.plugin.descriptor();
+        (descriptor.speed_min, descriptor.speed_max)
+    }
+
+    fn can_power_on(&self) -> bool {
+        self.plugin.descriptor().can_power_on != 0
+    }
+
+    fn get_layout(&self) -> KeyboardLayout {
+        self.plugin.layout.clone()
+    }
+}
+
+//Checks that descriptor is compatible with this version of klmd
+//and converts its static data. Returns reason of rejection on failure.
+fn validate_descriptor(descriptor: &KlmDriverDescriptor)
+    -> Result<(String, Vec<KeyboardMode>, KeyboardLayout), String> {
+    if descriptor.abi_version != KLM_DRIVER_ABI_VERSION {
+        return Err(format!("ABI version {} is not supported (klmd supports {})",
+                           descriptor.abi_version, KLM_DRIVER_ABI_VERSION));
+    }
+    if descriptor.name.is_null() {
+        return Err("driver name is not set".to_string());
+    }
+    let name = unsafe { CStr::from_ptr(descriptor.name) }.to_string_lossy().into_owned();
+    if name.is_empty() || name.len() > driver::MAX_NAME_LENGTH {
+        return Err(format!("driver name must be 1 to {} bytes long, got {}", driver::MAX_NAME_LENGTH, name.len()));
+    }
+    if descriptor.open.is_none() || descriptor.close.is_none() || descriptor.set_color.is_none() ||
+        descriptor.set_breathing.is_none() || descriptor.set_shift.is_none() ||
+        descriptor.set_power.is_none() {
+        return Err(format!("driver {} does not implement all required functions", name));
+    }
+    if descriptor.n_modes > 0 && descriptor.modes.is_null() {
+        return Err(format!("driver {} declares {} modes, but modes are not set", name, descriptor.n_modes));
+    }
+    if descriptor.brightness_min > descriptor.brightness_max || descriptor.speed_min > descriptor.speed_max {
+        return Err(format!("driver {} declares empty brightness or speed range", name));
+    }
+    let layout = match descriptor.layout {
+        KLM_LAYOUT_ZONES if descriptor.zones > 0 => KeyboardLayout::Zones(descriptor.zones),
+        KLM_LAYOUT_PER_KEY if descriptor.rows > 0 && descriptor.columns > 0 => KeyboardLayout::PerKey {
+            rows: descriptor.rows,
+            columns: descriptor.columns,
+        },
+        _ => return Err(format!("driver {} declares invalid layout", name)),
+    };
+    let mut modes = Vec::<KeyboardMode>::new();
+    for i in 0..descriptor.n_modes {
+        let byte = unsafe { *descriptor.modes.add(i) };
+        match KeyboardMode::from_u8(byte) {
+            Some(mode) => modes.push(mode),
+            None => return Err(format!("driver {} declares unknown mode {}", name, byte)),
+        }
+    }
+    Ok((name, modes, layout))
+}
+
+fn load_plugin(path: &Path) -> Result<Plugin, String> {
+    let library = unsafe { libloading::Library::new(path) }
+        .map_err(|e| format!("can not load library: {}", e))?;
+    let descriptor = unsafe {
+        let entry = library.get::<EntryFn>(ENTRY_SYMBOL)
+            .map_err(|e| format!("no klm_driver_entry symbol: {}", e))?;
+        entry()
+    };
+    if descriptor.is_null() {
+        return Err("klm_driver_entry returned NULL".to_string());
+    }
+    let (name, modes, layout) = validate_descriptor(unsafe { &*descriptor })?;
+    Ok(Plugin {
+        name,
+        modes,
+        layout,
+        descriptor,
+        _library: library,
+    })
+}
+
+//Loads every shared library in `dir` and registers drivers
+//provided by them. Incompatible plugins are skipped.
+pub fn load_plugins(registry: &mut DriverRegistry, dir: &str) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::d(TAG, &format!("Not loading plugins from {}: {}", dir, e));
+            return;
+        },
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension() != Some(OsStr::new("so")) {
+            continue;
+        }
+        match load_plugin(&path) {
+            Ok(plugin) => {
+                log::i(TAG, &format!("Loaded driver plugin {} from {}", plugin.name, path.display()));
+                let plugin = Rc::new(plugin);
+                let descriptor = plugin.descriptor();
+                let entry_name = plugin.name.clone();
+                let (vendor_id, product_id) = (descriptor.vendor_id, descriptor.product_id);
+                registry.register(DriverEntry::new(&entry_name, vendor_id, product_id, Box::new(move |_api| {
+                    let driver: Box<dyn driver::Driver> = Box::new(PluginDriver::open(&plugin)?);
+                    Some(driver)
+                })));
+            },
+            Err(e) => log::e(TAG, &format!("Rejecting plugin {}: {}", path.display(), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_descriptor, KlmColor, KlmDriverDescriptor, KLM_DRIVER_ABI_VERSION, KLM_LAYOUT_ZONES};
+    use crate::drivers::driver::{KeyboardLayout, KeyboardMode};
+    use std::ffi::{c_int, c_void, CString};
+
+    unsafe extern "C" fn open(_vendor_id: u16, _product_id: u16) -> *mut c_void {
+        std::ptr::null_mut()
+    }
+
+    unsafe extern "C" fn close(_handle: *mut c_void) {}
+
+    unsafe extern "C" fn set_color(_handle: *mut c_void, _color: *const KlmColor, _brightness: u8) -> c_int {
+        0
+    }
+
+    unsafe extern "C" fn set_colors(_handle: *mut c_void, _colors: *const KlmColor, _n_colors: usize,
+                                    _brightness: u8, _speed: u8) -> c_int {
+        0
+    }
+
+    unsafe extern "C" fn set_power(_handle: *mut c_void, _power: c_int) -> c_int {
+        0
+    }
+
+    const MODES: [u8; 2] = [0x1, 0x2];
+
+    fn descriptor(name: &CString) -> KlmDriverDescriptor {
+        KlmDriverDescriptor {
+            abi_version: KLM_DRIVER_ABI_VERSION,
+            name: name.as_ptr(),
+            vendor_id: 0x1462,
+            product_id: 0x1563,
+            max_colors: 7,
+            brightness_min: 0,
+            brightness_max: 10,
+            speed_min: 0,
+            speed_max: 2,
+            can_power_on: 0,
+            layout: KLM_LAYOUT_ZONES,
+            zones: 1,
+            rows: 0,
+            columns: 0,
+            n_modes: MODES.len(),
+            modes: MODES.as_ptr(),
+            open: Some(open),
+            close: Some(close),
+            set_color: Some(set_color),
+            set_breathing: Some(set_colors),
+            set_shift: Some(set_colors),
+            set_power: Some(set_power),
+        }
+    }
+
+    fn rejection(descriptor: &KlmDriverDescriptor) -> String {
+        match validate_descriptor(descriptor) {
+            Ok(_) => panic!("descriptor must be rejected"),
+            Err(reason) => reason,
+        }
+    }
+
+    #[test]
+    fn valid_descriptor_is_accepted() {
+        let name = CString::new("test").unwrap();
+        let (name, modes, layout) = validate_descriptor(&descriptor(&name)).unwrap();
+        assert_eq!(name, "test");
+        assert!(matches!(modes[..], [KeyboardMode::ModeSteady, KeyboardMode::ModeBreathing]));
+        assert!(matches!(layout, KeyboardLayout::Zones(1)));
+    }
+
+    #[test]
+    fn invalid_descriptors_are_rejected() {
+        let name = CString::new("test").unwrap();
+        let mut other_abi = descriptor(&name);
+        other_abi.abi_version = KLM_DRIVER_ABI_VERSION + 1;
+        assert!(rejection(&other_abi).contains("ABI version"));
+        let mut unnamed = descriptor(&name);
+        unnamed.name = std::ptr::null();
+        assert!(rejection(&unnamed).contains("name is not set"));
+        let long_name = CString::new("x".repeat(65)).unwrap();
+        assert!(rejection(&descriptor(&long_name)).contains("1 to 64 bytes"));
+        let mut incomplete = descriptor(&name);
+        incomplete.set_shift = None;
+        assert!(rejection(&incomplete).contains("does not implement all required functions"));
+        let mut brightness = descriptor(&name);
+        brightness.brightness_min = 11;
+        assert!(rejection(&brightness).contains("empty brightness or speed range"));
+        let mut speed = descriptor(&name);
+        (speed.speed_min, speed.speed_max) = (3, 2);
+        assert!(rejection(&speed).contains("empty brightness or speed range"));
+        let unknown = [0x1, 0x7];
+        let mut unknown_mode = descriptor(&name);
+        unknown_mode.modes = unknown.as_ptr();
+        assert!(rejection(&unknown_mode).contains("unknown mode 7"));
+    }
+}
@@ -0,0 +1,397 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::drivers::driver;
+use crate::drivers::driver::{KeyboardLayout, KeyboardMode};
+use crate::drivers::transport::Transport;
+use crate::drivers::{DriverEntry, DriverRegistry};
+use crate::error::{KlmError, KlmResult};
+use crate::util::color;
+use crate::util::log;
+
+use serde::Deserialize;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::rc::Rc;
+
+const TAG: &'static str = "template";
+pub const TEMPLATE_DIR: &'static str = "/etc/klmd/drivers.d";
+
+//Opcodes written to mode field for each of supported modes.
+//Modes which are not specified are not supported by keyboard.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateModes {
+    pub steady: Option<u8>,
+    pub breathing: Option<u8>,
+    pub colorshift: Option<u8>,
+}
+
+//Byte offsets of report fields
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateOffsets {
+    pub mode: usize,
+    pub speed: usize,
+    pub brightness: usize,
+    pub color_count: usize,
+    pub colors: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateRange {
+    pub min: u8,
+    pub max: u8,
+}
+
+//Description of a keyboard which is controlled by
+//a single fixed-length feature report
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DriverTemplate {
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub report_id: u8,
+    pub report_length: usize,
+    pub max_colors: u8,
+    pub modes: TemplateModes,
+    pub offsets: TemplateOffsets,
+    pub brightness: TemplateRange,
+    pub speed: TemplateRange,
+    #[serde(default = "default_zones")]
+    pub zones: u8,
+}
+
+fn default_zones() -> u8 {
+    1
+}
+
+impl DriverTemplate {
+    pub fn parse(text: &str) -> Result<DriverTemplate, String> {
+        let template: DriverTemplate = toml::from_str(text).map_err(|e| e.to_string())?;
+        template.validate()?;
+        Ok(template)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() || self.name.len() > driver::MAX_NAME_LENGTH {
+            return Err(format!("driver name must be 1 to {} bytes long, got {}", driver::MAX_NAME_LENGTH,
+                               self.name.len()));
+        }
+        if self.report_length < 2 {
+            return Err(format!("report_length {} is too small", self.report_length));
+        }
+        let fields = [("mode", self.offsets.mode), ("speed", self.offsets.speed),
+            ("brightness", self.offsets.brightness), ("color_count", self.offsets.color_count)];
+        for (field, offset) in fields {
+            if offset == 0 || offset >= self.report_length {
+                return Err(format!("offset of {} field {} is out of report", field, offset));
+            }
+        }
+        if self.max_colors == 0 {
+            return Err("max_colors must be at least 1".to_string());
+        }
+        if self.offsets.colors == 0 || self.offsets.colors + 3 * self.max_colors as usize > self.report_length {
+            return Err(format!("{} colors at offset {} do not fit into report", self.max_colors,
+                               self.offsets.colors));
+        }
+        if self.brightness.min > self.brightness.max || self.speed.min > self.speed.max {
+            return Err("range minimum is greater than maximum".to_string());
+        }
+        if self.zones == 0 {
+            return Err("zones must be at least 1".to_string());
+        }
+        if self.get_modes().is_empty() {
+            return Err("no modes are specified".to_string());
+        }
+        Ok(())
+    }
+
+    fn get_modes(&self) -> Vec<KeyboardMode> {
+        let mut modes = vec![];
+        if self.modes.steady.is_some() {
+            modes.push(KeyboardMode::ModeSteady);
+        }
+        if self.modes.breathing.is_some() {
+            modes.push(KeyboardMode::ModeBreathing);
+        }
+        if self.modes.colorshift.is_some() {
+            modes.push(KeyboardMode::ModeColorshift);
+        }
+        modes
+    }
+}
+
+fn clamp(name: &str, value: u8, range: &TemplateRange) -> u8 {
+    if value > range.max {
+        log::w(TAG, &format!("Requested {} is too big: {}", name, value));
+        range.max
+    } else if value < range.min {
+        log::w(TAG, &format!("Requested {} is too small: {}", name, value));
+        range.min
+    } else {
+        value
+    }
+}
+
+//Generic driver filling report according to DriverTemplate
+pub struct TemplateDriver {
+    template: Rc<DriverTemplate>,
+    device: Box<dyn Transport>,
+}
+
+impl TemplateDriver {
+    pub fn with_transport(template: Rc<DriverTemplate>, transport: Box<dyn Transport>) -> TemplateDriver {
+        TemplateDriver {
+            template,
+            device: transport,
+        }
+    }
+
+    fn get_buffer(&self) -> Vec<u8> {
+        let mut buffer = vec![0; self.template.report_length];
+        buffer[0] = self.template.report_id;
+        buffer
+    }
+
+    fn write_buffer(&self, buffer: &[u8]) -> KlmResult<()> {
+        if let Err(e) = self.device.send_feature_report(buffer) {
+            log::e(TAG, "Failed writing buffer.");
+            return Err(KlmError::Hid(e));
+        }
+        log::d(TAG, "Succesfully written buffer.");
+        Ok(())
+    }
+
+    fn write_mode(&self, opcode: Option<u8>, colors: &[color::RGB], brightness: u8, speed: u8) -> KlmResult<()> {
+        let template = &self.template;
+        let opcode = match opcode {
+            Some(opcode) => opcode,
+            None => {
+                log::e(TAG, &format!("Mode is not supported by {}", template.name));
+                return Err(KlmError::Driver(format!("mode is not supported by {}", template.name)));
+            },
+        };
+        if colors.len() > template.max_colors as usize {
+            log::w(TAG, "Color vector is too large, ignoring request");
+            return Err(KlmError::Driver(format!("{} supports at most {} colors, got {}", template.name,
+                                                template.max_colors, colors.len())));
+        }
+        let mut buffer = self.get_buffer();
+        buffer[template.offsets.mode] = opcode;
+        buffer[template.offsets.speed] = clamp("speed", speed, &template.speed);
+        buffer[template.offsets.brightness] = clamp("brightness", brightness, &template.brightness);
+        buffer[template.offsets.color_count] = colors.len() as u8;
+        let mut color_ptr = template.offsets.colors;
+        for color in colors {
+            buffer[color_ptr] = color.r;
+            buffer[color_ptr + 1] = color.g;
+            buffer[color_ptr + 2] = color.b;
+            color_ptr += 3;
+        }
+        self.write_buffer(&buffer)
+    }
+}
+
+impl driver::Driver for TemplateDriver {
+    fn set_color(&self, color: &color::RGB, brightness: u8) -> KlmResult<()> {
+        let color = color::RGB::new(color.r, color.g, color.b);
+        self.write_mode(self.template.modes.steady, &[color], brightness, self.template.speed.min)
+    }
+
+    fn set_breathing(&self, colors: &Vec<color::RGB>, brightness: u8, speed: u8) -> KlmResult<()> {
+        self.write_mode(self.template.modes.breathing, colors, brightness, speed)
+    }
+
+    fn set_shift(&self, colors: &Vec<color::RGB>, brightness: u8, speed: u8) -> KlmResult<()> {
+        self.write_mode(self.template.modes.colorshift, colors, brightness, speed)
+    }
+
+    fn set_power(&self, value: bool) -> KlmResult<()> {
+        if !value {
+            log::d(TAG, "Powering off keyboard lightning");
+            self.write_buffer(&self.get_buffer())
+        } else {
+            log::e(TAG, &format!("Powering on keyboard lightning is not supported for {}", self.template.name));
+            Err(KlmError::Driver(format!("powering on is not supported for {}", self.template.name)))
+        }
+    }
+
+    fn get_modes(&self) -> Vec<KeyboardMode> {
+        self.template.get_modes()
+    }
+
+    fn get_max_colors(&self) -> u8 {
+        self.template.max_colors
+    }
+
+    fn get_name(&self) -> String {
+        self.template.name.clone()
+    }
+
+    fn get_vendor_id(&self) -> u16 {
+        self.template.vendor_id
+    }
+
+    fn get_product_id(&self) -> u16 {
+        self.template.product_id
+    }
+
+    fn get_brightness_range(&self) -> (u8, u8) {
+        (self.template.brightness.min, self.template.brightness.max)
+    }
+
+    fn get_speed_range(&self) -> (u8, u8) {
+        (self.template.speed.min, self.template.speed.max)
+    }
+
+    fn can_power_on(&self) -> bool {
+        false
+    }
+
+    fn get_layout(&self) -> KeyboardLayout {
+        KeyboardLayout::Zones(self.template.zones)
+    }
+}
+
+fn load_template(path: &Path) -> Result<DriverTemplate, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    DriverTemplate::parse(&text)
+}
+
+//Registers a driver for every *.toml template in `dir`
+pub fn load_templates(registry: &mut DriverRegistry, dir: &str) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::d(TAG, &format!("Not loading driver templates from {}: {}", dir, e));
+            return;
+        },
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension() != Some(OsStr::new("toml")) {
+            continue;
+        }
+        match load_template(&path) {
+            Ok(template) => {
+                log::i(TAG, &format!("Loaded driver template {} from {}", template.name, path.display()));
+                let template = Rc::new(template);
+                let (vendor_id, product_id) = (template.vendor_id, template.product_id);
+                let name = template.name.clone();
+                registry.register(DriverEntry::new(&name, vendor_id, product_id, Box::new(move |api| {
+                    match api.open(template.vendor_id, template.product_id) {
+                        Ok(device) => {
+                            let driver: Box<dyn driver::Driver> = Box::new(
+                                TemplateDriver::with_transport(template.clone(), Box::new(device)));
+                            Some(driver)
+                        },
+                        Err(e) => {
+                            log::e(TAG, &format!("Opening {} device failed: {}", template.name, e));
+                            None
+                        },
+                    }
+                })));
+            },
+            Err(e) => log::e(TAG, &format!("Rejecting driver template {}: {}", path.display(), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DriverTemplate, TemplateDriver};
+    use crate::drivers::driver::Driver;
+    use crate::drivers::ms1563::MS1563;
+    use crate::drivers::transport::RecordingTransport;
+    use crate::util::color;
+    use std::rc::Rc;
+
+    const MS1563_TEMPLATE: &str = r#"
+name = "MS1563 template"
+vendor_id = 0x1462
+product_id = 0x1563
+report_id = 0x02
+report_length = 64
+max_colors = 7
+
+[modes]
+steady = 0x01
+breathing = 0x02
+colorshift = 0x05
+
+[offsets]
+mode = 2
+speed = 3
+brightness = 4
+color_count = 5
+colors = 6
+
+[brightness]
+min = 0
+max = 10
+
+[speed]
+min = 0
+max = 2
+"#;
+
+    fn drivers() -> (TemplateDriver, RecordingTransport, MS1563, RecordingTransport) {
+        let template = Rc::new(DriverTemplate::parse(MS1563_TEMPLATE).unwrap());
+        let template_transport = RecordingTransport::new();
+        let ms1563_transport = RecordingTransport::new();
+        (TemplateDriver::with_transport(template, Box::new(template_transport.clone())), template_transport,
+         MS1563::with_transport(Box::new(ms1563_transport.clone())), ms1563_transport)
+    }
+
+    #[test]
+    fn ms1563_template_matches_builtin_driver() {
+        let (template, template_transport, ms1563, ms1563_transport) = drivers();
+        let colors = vec![color::RGB::new(1, 2, 3), color::RGB::new(4, 5, 6)];
+        for driver in [&template as &dyn Driver, &ms1563 as &dyn Driver] {
+            assert!(driver.set_color(&color::RGB::new(7, 8, 9), 20).is_ok());
+            assert!(driver.set_breathing(&colors, 3, 1).is_ok());
+            assert!(driver.set_shift(&colors, 10, 5).is_ok());
+            assert!(driver.set_power(false).is_ok());
+            assert!(driver.set_power(true).is_err());
+        }
+        assert_eq!(template_transport.feature_reports(), ms1563_transport.feature_reports());
+        let (template, ms1563) = (template.get_capabilities(), ms1563.get_capabilities());
+        assert_eq!(template.modes.len(), ms1563.modes.len());
+        assert_eq!(template.brightness_range, ms1563.brightness_range);
+        assert_eq!(template.speed_range, ms1563.speed_range);
+        assert_eq!(template.max_colors, ms1563.max_colors);
+    }
+
+    #[test]
+    fn unsupported_mode_is_rejected() {
+        let text = MS1563_TEMPLATE.replace("breathing = 0x02\n", "");
+        let template = Rc::new(DriverTemplate::parse(&text).unwrap());
+        let transport = RecordingTransport::new();
+        let driver = TemplateDriver::with_transport(template, Box::new(transport.clone()));
+        assert!(driver.set_breathing(&vec![color::RGB::new(1, 1, 1)], 1, 1).is_err());
+        assert!(transport.feature_reports().is_empty());
+        assert_eq!(driver.get_modes().len(), 2);
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        assert!(DriverTemplate::parse(&MS1563_TEMPLATE.replace("max_colors = 7", "max_colors = 20")).is_err());
+        assert!(DriverTemplate::parse(&MS1563_TEMPLATE.replace("mode = 2", "mode = 64")).is_err());
+        assert!(DriverTemplate::parse(&MS1563_TEMPLATE.replace("max = 10", "max = 10\nstep = 1")).is_err());
+        assert!(DriverTemplate::parse("name = \"empty\"").is_err());
+        assert!(DriverTemplate::parse(&MS1563_TEMPLATE.replace("name = \"MS1563 template\"", "name = \"\"")).is_err());
+        let long_name = format!("name = \"{}\"", "x".repeat(65));
+        assert!(DriverTemplate::parse(&MS1563_TEMPLATE.replace("name = \"MS1563 template\"", &long_name)).is_err());
+    }
+}
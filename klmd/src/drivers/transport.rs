@@ -0,0 +1,156 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+//Transport is a channel between driver and device. Drivers
+//talk to it instead of hidapi, so they can be tested without hardware.
+//Not every driver needs every operation.
+#[allow(dead_code)]
+pub trait Transport {
+    fn send_feature_report(&self, data: &[u8]) -> hidapi::HidResult<()>;
+    fn get_feature_report(&self, buffer: &mut [u8]) -> hidapi::HidResult<usize>;
+    fn write(&self, data: &[u8]) -> hidapi::HidResult<usize>;
+    fn read(&self, buffer: &mut [u8]) -> hidapi::HidResult<usize>;
+}
+
+impl Transport for hidapi::HidDevice {
+    fn send_feature_report(&self, data: &[u8]) -> hidapi::HidResult<()> {
+        hidapi::HidDevice::send_feature_report(self, data)
+    }
+
+    fn get_feature_report(&self, buffer: &mut [u8]) -> hidapi::HidResult<usize> {
+        hidapi::HidDevice::get_feature_report(self, buffer)
+    }
+
+    fn write(&self, data: &[u8]) -> hidapi::HidResult<usize> {
+        hidapi::HidDevice::write(self, data)
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> hidapi::HidResult<usize> {
+        hidapi::HidDevice::read(self, buffer)
+    }
+}
+
+#[cfg(any(test, feature = "fuzzing"))]
+pub use self::recording::RecordingTransport;
+
+#[cfg(any(test, feature = "fuzzing"))]
+mod recording {
+    use super::Transport;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct Recording {
+        feature_reports: Vec<Vec<u8>>,
+        writes: Vec<Vec<u8>>,
+        responses: VecDeque<Vec<u8>>,
+        fail: bool,
+    }
+
+    //In-memory transport recording everything driver sends. Clones
+    //share the same recording, so a test can keep one clone and give
+    //another one to driver.
+    #[derive(Clone, Default)]
+    pub struct RecordingTransport {
+        recording: Rc<RefCell<Recording>>,
+    }
+
+    impl RecordingTransport {
+        pub fn new() -> RecordingTransport {
+            RecordingTransport::default()
+        }
+
+        pub fn feature_reports(&self) -> Vec<Vec<u8>> {
+            self.recording.borrow().feature_reports.clone()
+        }
+
+        pub fn writes(&self) -> Vec<Vec<u8>> {
+            self.recording.borrow().writes.clone()
+        }
+
+        //Queues data returned by next get_feature_report or read
+        pub fn push_response(&self, data: &[u8]) {
+            self.recording.borrow_mut().responses.push_back(data.to_vec());
+        }
+
+        //Makes every following operation fail as if device was unplugged
+        pub fn set_failing(&self, fail: bool) {
+            self.recording.borrow_mut().fail = fail;
+        }
+
+        fn check_failing(&self) -> hidapi::HidResult<()> {
+            if self.recording.borrow().fail {
+                Err(hidapi::HidError::HidApiError { message: "recording transport failure".to_string() })
+            } else {
+                Ok(())
+            }
+        }
+
+        fn pop_response(&self, buffer: &mut [u8]) -> hidapi::HidResult<usize> {
+            self.check_failing()?;
+            match self.recording.borrow_mut().responses.pop_front() {
+                Some(response) => {
+                    let size = response.len().min(buffer.len());
+                    buffer[..size].copy_from_slice(&response[..size]);
+                    Ok(size)
+                },
+                None => Ok(0),
+            }
+        }
+    }
+
+    impl Transport for RecordingTransport {
+        fn send_feature_report(&self, data: &[u8]) -> hidapi::HidResult<()> {
+            self.check_failing()?;
+            self.recording.borrow_mut().feature_reports.push(data.to_vec());
+            Ok(())
+        }
+
+        fn get_feature_report(&self, buffer: &mut [u8]) -> hidapi::HidResult<usize> {
+            self.pop_response(buffer)
+        }
+
+        fn write(&self, data: &[u8]) -> hidapi::HidResult<usize> {
+            self.check_failing()?;
+            self.recording.borrow_mut().writes.push(data.to_vec());
+            Ok(data.len())
+        }
+
+        fn read(&self, buffer: &mut [u8]) -> hidapi::HidResult<usize> {
+            self.pop_response(buffer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RecordingTransport, Transport};
+
+    #[test]
+    fn recording_transport_records_and_replays() {
+        let transport = RecordingTransport::new();
+        let driver_side: Box<dyn Transport> = Box::new(transport.clone());
+        assert_eq!(driver_side.write(&[1, 2, 3]).unwrap(), 3);
+        driver_side.send_feature_report(&[4, 5]).unwrap();
+        assert_eq!(transport.writes(), vec![vec![1, 2, 3]]);
+        assert_eq!(transport.feature_reports(), vec![vec![4, 5]]);
+
+        transport.push_response(&[7, 8, 9]);
+        transport.push_response(&[10]);
+        let mut buffer = [0u8; 2];
+        assert_eq!(driver_side.get_feature_report(&mut buffer).unwrap(), 2);
+        assert_eq!(buffer, [7, 8]);
+        assert_eq!(driver_side.read(&mut buffer).unwrap(), 1);
+        assert_eq!(buffer[0], 10);
+        assert_eq!(driver_side.read(&mut buffer).unwrap(), 0);
+    }
+}
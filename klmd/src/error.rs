@@ -0,0 +1,63 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use std::fmt;
+use std::io;
+
+//Errors of all klmd subsystems. Failures of a single request are
+//reported to client and logged, they never stop the daemon.
+#[derive(Debug)]
+pub enum KlmError {
+    //Communication with device failed
+    Hid(hidapi::HidError),
+    //Socket or file operation failed
+    Io(io::Error),
+    //Client sent malformed request
+    Protocol(String),
+    //Keyboard state can not be saved or loaded
+    Persistence(String),
+    //Driver can not perform requested operation
+    Driver(String),
+    //Keyboard state can not be applied to device
+    State(String),
+    //Configuration file or its override is malformed
+    Config(String),
+}
+
+pub type KlmResult<T> = Result<T, KlmError>;
+
+impl fmt::Display for KlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KlmError::Hid(e) => write!(f, "HID error: {}", e),
+            KlmError::Io(e) => write!(f, "I/O error: {}", e),
+            KlmError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            KlmError::Persistence(msg) => write!(f, "persistence error: {}", msg),
+            KlmError::Driver(msg) => write!(f, "driver error: {}", msg),
+            KlmError::State(msg) => write!(f, "bad keyboard state: {}", msg),
+            KlmError::Config(msg) => write!(f, "configuration error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for KlmError {}
+
+impl From<io::Error> for KlmError {
+    fn from(e: io::Error) -> KlmError {
+        KlmError::Io(e)
+    }
+}
+
+impl From<hidapi::HidError> for KlmError {
+    fn from(e: hidapi::HidError) -> KlmError {
+        KlmError::Hid(e)
+    }
+}
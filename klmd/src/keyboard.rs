@@ -10,17 +10,30 @@
  */
 
 use crate::drivers::driver;
+use crate::error::{KlmError, KlmResult};
 use crate::util::color;
+use crate::util::crc32::crc32;
 use crate::util::log;
+use crate::util::u8::U8VecSerializable;
 
-use std::io::Write;
 use std::io::prelude::*;
 use std::fs::File;
-use std::path::Path;
-use crate::drivers::driver::KeyboardMode;
+use std::path::{Path, PathBuf};
+use crate::drivers::driver::{Capabilities, KeyboardMode};
 
 const TAG: &'static str = "keyboard";
 const CACHE_FILENAME: &'static str = "/var/cache/klm/klm.state";
+const PROFILES_DIRNAME: &'static str = "/var/lib/klmd/profiles";
+const STATE_MAGIC: &'static [u8] = b"KLMS";
+const STATE_VERSION: u8 = 1;
+
+//Format state file was loaded from
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum StateFormat {
+    //Headerless file written before versioning
+    V0,
+    V1,
+}
 
 #[derive(PartialEq)]
 #[derive(Clone)]
@@ -63,6 +76,103 @@ impl KeyboardState {
     }
 }
 
+impl From<klm_proto::Mode> for KeyboardState {
+    fn from(mode: klm_proto::Mode) -> KeyboardState {
+        match mode {
+            klm_proto::Mode::Off => KeyboardState::KeyboardOff,
+            klm_proto::Mode::Steady => KeyboardState::KeyboardSteady,
+            klm_proto::Mode::Breathing => KeyboardState::KeyboardBreathing,
+            klm_proto::Mode::ColorShift => KeyboardState::KeyboardColorShift,
+        }
+    }
+}
+
+//Snapshot of keyboard state reported to clients
+pub struct KeyboardStatus {
+    pub state: KeyboardState,
+    pub power: bool,
+    pub brightness: u8,
+    pub speed: u8,
+    pub sync_locked: bool,
+    pub colors: Vec<color::RGB>,
+}
+
+impl U8VecSerializable for KeyboardStatus {
+    fn to_u8_vec(&self) -> Vec<u8> {
+        let mut result = vec![KeyboardState::to_u8(self.state), self.power as u8, self.brightness,
+                              self.speed, self.sync_locked as u8, self.colors.len() as u8];
+        result.extend(self.colors.to_u8_vec());
+        result
+    }
+}
+
+//Keyboard settings saved before a request, so it can be rolled back.
+//Also contents of state file and profiles.
+#[derive(Clone, PartialEq)]
+pub struct KeyboardSnapshot {
+    state: KeyboardState,
+    colors: Vec<color::RGB>,
+    brightness: u8,
+    speed: u8,
+    power: bool,
+}
+
+impl KeyboardSnapshot {
+    pub fn new(state: KeyboardState, colors: Vec<color::RGB>, brightness: u8, speed: u8,
+               power: bool) -> KeyboardSnapshot {
+        KeyboardSnapshot { state, colors, brightness, speed, power }
+    }
+
+    //State in format of version 0: brightness, speed, state, power
+    //and color vector. Newer versions wrap it into header and checksum.
+    fn encode_state(&self) -> KlmResult<Vec<u8>> {
+        let mut buffer = Vec::<u8>::new();
+        buffer.push(self.brightness);
+        buffer.push(self.speed);
+        buffer.push(KeyboardState::to_u8(self.state));
+        if self.power {
+            buffer.push(0x01);
+        } else {
+            buffer.push(0x00);
+        }
+        if self.colors.len() > 255 {
+            return Err(KlmError::Persistence(format!("too many colors to save: {}", self.colors.len())));
+        }
+        buffer.push(self.colors.len() as u8);
+        for color in &self.colors {
+            buffer.push(color.r);
+            buffer.push(color.g);
+            buffer.push(color.b);
+        }
+        Ok(buffer)
+    }
+
+    //Contents of state file: magic, version, body length, body and
+    //CRC-32 of everything after magic
+    pub fn encode_state_file(&self) -> KlmResult<Vec<u8>> {
+        let body = self.encode_state()?;
+        let mut buffer = STATE_MAGIC.to_vec();
+        buffer.push(STATE_VERSION);
+        buffer.extend((body.len() as u16).to_be_bytes());
+        buffer.extend(body);
+        let checksum = crc32(&buffer[STATE_MAGIC.len()..]);
+        buffer.extend(checksum.to_be_bytes());
+        Ok(buffer)
+    }
+
+    //Status of state which is not applied to keyboard
+    pub fn to_status(&self) -> KeyboardStatus {
+        KeyboardStatus {
+            state: self.state,
+            power: self.power,
+            brightness: self.brightness,
+            speed: self.speed,
+            sync_locked: false,
+            colors: self.colors.clone(),
+        }
+    }
+}
+
 //Implements a controller which stores state of keyboard
 //and communicates with driver
 pub struct Keyboard {
@@ -74,6 +184,78 @@ pub struct Keyboard {
     syncing: bool,
     power: bool,
     need_sync: bool,
+    state_file: PathBuf,
+    profiles_dir: PathBuf,
+}
+
+//Decodes contents of state file. Files without magic were written
+//by older versions and are decoded as version 0.
+pub fn decode_state_file(buffer: &[u8]) -> KlmResult<(KeyboardSnapshot, StateFormat)> {
+    if !buffer.starts_with(STATE_MAGIC) {
+        return Ok((decode_state_body(buffer)?, StateFormat::V0));
+    }
+    let header_size = STATE_MAGIC.len() + 3;
+    if buffer.len() < header_size + 4 {
+        return Err(KlmError::Persistence("state file header is truncated".to_string()));
+    }
+    let version = buffer[STATE_MAGIC.len()];
+    if version != STATE_VERSION {
+        return Err(KlmError::Persistence(format!("unsupported state file version {}", version)));
+    }
+    let length = u16::from_be_bytes([buffer[header_size - 2], buffer[header_size - 1]]) as usize;
+    if buffer.len() != header_size + length + 4 {
+        return Err(KlmError::Persistence(format!("state file has {} bytes, expected {}",
+                                                 buffer.len(), header_size + length + 4)));
+    }
+    let checksum_offset = header_size + length;
+    let checksum = u32::from_be_bytes([buffer[checksum_offset], buffer[checksum_offset + 1],
+                                       buffer[checksum_offset + 2], buffer[checksum_offset + 3]]);
+    if crc32(&buffer[STATE_MAGIC.len()..checksum_offset]) != checksum {
+        return Err(KlmError::Persistence("state file checksum mismatch".to_string()));
+    }
+    Ok((decode_state_body(&buffer[header_size..checksum_offset])?, StateFormat::V1))
+}
+
+fn decode_state_body(buffer: &[u8]) -> KlmResult<KeyboardSnapshot> {
+    if buffer.len() < 5 {
+        return Err(KlmError::Persistence("state file is truncated".to_string()));
+    }
+    let state = KeyboardState::from_u8(buffer[2])
+        .ok_or_else(|| KlmError::Persistence(format!("bad state specifier {}", buffer[2])))?;
+    let n = buffer[4] as usize;
+    let color_bytes = &buffer[5..];
+    if color_bytes.len() < 3 * n {
+        return Err(KlmError::Persistence("color vector is truncated".to_string()));
+    }
+    Ok(KeyboardSnapshot {
+        state,
+        colors: color_bytes.chunks(3).take(n)
+            .map(|color| color::RGB::new(color[0], color[1], color[2]))
+            .collect(),
+        brightness: buffer[0],
+        speed: buffer[1],
+        power: buffer[3] != 0x0,
+    })
+}
+
+//File is written to temporary one which replaces it only when it is
+//completely on disk, so crash leaves either old or new contents
+pub(crate) fn write_atomically(path: &Path, buffer: &[u8]) -> KlmResult<()> {
+    let error = |action: &str, e: std::io::Error|
+        KlmError::Persistence(format!("can not {} {}: {}", action, path.display(), e));
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = Path::new(&temp_path);
+    let mut file = File::create(temp_path).map_err(|e| error("create", e))?;
+    file.write_all(buffer).and_then(|_| file.sync_all()).map_err(|e| error("write", e))?;
+    std::fs::rename(temp_path, path).map_err(|e| error("replace", e))?;
+    //Rename itself is durable only after directory is synced
+    if let Some(directory) = path.parent() {
+        if let Err(e) = File::open(directory).and_then(|directory| directory.sync_all()) {
+            log::w(TAG, &format!("Unable to sync {}: {}", directory.display(), e));
+        }
+    }
+    Ok(())
 }
 
 impl Keyboard {
@@ -87,48 +269,64 @@ impl Keyboard {
             syncing: false,
             power: false,
             need_sync: false,
+            state_file: PathBuf::from(CACHE_FILENAME),
+            profiles_dir: PathBuf::from(PROFILES_DIRNAME),
         }
     }
 
-    pub fn sync(&mut self) {
+    //File state is saved to and loaded from
+    pub fn set_state_file(&mut self, path: &Path) {
+        self.state_file = path.to_path_buf();
+    }
+
+    //Directory named profiles are stored in
+    pub fn set_profiles_dir(&mut self, path: &Path) {
+        self.profiles_dir = path.to_path_buf();
+    }
+
+    pub fn get_profiles_dir(&self) -> &Path {
+        &self.profiles_dir
+    }
+
+    //Replaces driver after devices were probed again. New driver
+    //receives current state on next sync.
+    pub fn set_driver(&mut self, driver: Box<dyn driver::Driver>) {
+        self.driver = driver;
+        self.need_sync = true;
+    }
+
+    pub fn sync(&mut self) -> KlmResult<()> {
         if !self.syncing {
             log::w(TAG, "Sync is called, when keyboard syncing is off");
         }
         if !self.need_sync {
             // Do not touch driver if nothing was updated
-            return;
+            return Ok(());
         }
         self.need_sync = false;
-        if !self.power {
-            self.driver.set_power(false);
-            return;
+        if !self.power || self.state == KeyboardState::KeyboardOff {
+            return self.driver.set_power(false);
+        }
+        if self.colors.is_empty() {
+            return Err(KlmError::State("can not synchronize state: empty colors array".to_string()));
+        }
+        if self.brightness == 0 {
+            log::w(TAG, "Brightness is 0");
         }
-        if self.state == KeyboardState::KeyboardOff {
-            self.driver.set_power(false);
-        } else if self.state == KeyboardState::KeyboardSteady {
-            if self.colors.len() == 0 {
-                log::panic(TAG, "Can not synchronize state: empty colors array!");
-            }
-            if self.brightness == 0 {
-                log::w(TAG, "Brightness is 0");
-            }
-            self.driver.set_color(&self.colors[0], self.brightness);
+        if self.state == KeyboardState::KeyboardSteady {
+            self.driver.set_color(&self.colors[0], self.brightness)
         } else if self.state == KeyboardState::KeyboardBreathing {
-            if self.colors.len() == 0 {
-                log::panic(TAG, "Can not synchronize state: empty colors array!");
-            }
-            if self.brightness == 0 {
-                log::w(TAG, "Brightness is 0");
-            }
-            self.driver.set_breathing(&self.colors, self.brightness, self.speed);
-        } else if self.state == KeyboardState::KeyboardColorShift {
-            if self.colors.len() == 0 {
-                log::panic(TAG, "Can not synchronize state: empty colors array!");
-            }
-            if self.brightness == 0 {
-                log::w(TAG, "Brightness is 0");
-            }
-            self.driver.set_shift(&self.colors, self.brightness, self.speed);
+            self.driver.set_breathing(&self.colors, self.brightness, self.speed)
+        } else {
+            self.driver.set_shift(&self.colors, self.brightness, self.speed)
+        }
+    }
+
+    fn sync_if_unlocked(&mut self) -> KlmResult<()> {
+        if self.syncing {
+            self.sync()
+        } else {
+            Ok(())
         }
     }
 
@@ -140,44 +338,34 @@ impl Keyboard {
         self.syncing = true;
     }
 
-    pub fn set_state(&mut self, state: KeyboardState) {
+    pub fn set_state(&mut self, state: KeyboardState) -> KlmResult<()> {
         self.state = state;
         self.need_sync = true;
-        if self.syncing {
-            self.sync();
-        }
+        self.sync_if_unlocked()
     }
 
-    pub fn set_color(&mut self, color: color::RGB) {
+    pub fn set_color(&mut self, color: color::RGB) -> KlmResult<()> {
         self.colors = vec![color];
         self.need_sync = true;
-        if self.syncing {
-            self.sync();
-        }
+        self.sync_if_unlocked()
     }
 
-    pub fn add_color(&mut self, color: color::RGB) {
+    pub fn add_color(&mut self, color: color::RGB) -> KlmResult<()> {
         self.colors.push(color);
         self.need_sync = true;
-        if self.syncing {
-            self.sync();
-        }
+        self.sync_if_unlocked()
     }
 
-    pub fn set_brightness(&mut self, brightness: u8) {
+    pub fn set_brightness(&mut self, brightness: u8) -> KlmResult<()> {
         self.brightness = brightness;
         self.need_sync = true;
-        if self.syncing {
-            self.sync();
-        }
+        self.sync_if_unlocked()
     }
 
-    pub fn set_speed(&mut self, speed: u8) {
+    pub fn set_speed(&mut self, speed: u8) -> KlmResult<()> {
         self.speed = speed;
         self.need_sync = true;
-        if self.syncing {
-            self.sync();
-        }
+        self.sync_if_unlocked()
     }
 
     pub fn reset_colors(&mut self) {
@@ -189,81 +377,289 @@ impl Keyboard {
         self.power = power;
     }
 
-    pub fn toggle_power(&mut self) {
+    pub fn toggle_power(&mut self) -> KlmResult<()> {
         self.power = !self.power;
         self.need_sync = true;
-        self.sync();
+        self.sync_if_unlocked()
     }
-    pub fn save_state(&self) -> bool {
-        //Prepare buffer
-        let mut buffer = Vec::<u8>::new();
-        buffer.push(self.brightness);
-        buffer.push(self.speed);
-        buffer.push(KeyboardState::to_u8(self.state));
-        if self.power {
-            buffer.push(0x01);
-        } else {
-            buffer.push(0x00);
+
+    pub fn snapshot(&self) -> KeyboardSnapshot {
+        KeyboardSnapshot {
+            state: self.state,
+            colors: self.colors.clone(),
+            brightness: self.brightness,
+            speed: self.speed,
+            power: self.power,
         }
-        if self.colors.len() > 255 {
-            log::panic(TAG, "Too many colors. Maybe a bug?");
+    }
+
+    //Restored state is sent to driver on next sync, as
+    //device could be left partially updated
+    pub fn restore(&mut self, snapshot: KeyboardSnapshot) {
+        self.state = snapshot.state;
+        self.colors = snapshot.colors;
+        self.brightness = snapshot.brightness;
+        self.speed = snapshot.speed;
+        self.power = snapshot.power;
+        self.need_sync = true;
+    }
+
+    pub fn encode_state_file(&self) -> KlmResult<Vec<u8>> {
+        self.snapshot().encode_state_file()
+    }
+
+    pub fn save_state(&self) -> KlmResult<()> {
+        self.save_state_to(&self.state_file)
+    }
+
+    pub fn save_state_to(&self, path: &Path) -> KlmResult<()> {
+        write_atomically(path, &self.encode_state_file()?)
+    }
+
+    //Restores state saved by save_state. Keyboard is left
+    //untouched if buffer is malformed.
+    pub fn load_state_from(&mut self, buffer: &[u8]) -> KlmResult<StateFormat> {
+        let (snapshot, format) = decode_state_file(buffer)?;
+        self.restore(snapshot);
+        Ok(format)
+    }
+
+    //Profile sets mode, colors, brightness and speed saved in
+    //it. Power is left as it is.
+    pub fn apply_profile(&mut self, buffer: &[u8]) -> KlmResult<()> {
+        let power = self.power;
+        self.load_state_from(buffer)?;
+        self.power = power;
+        Ok(())
+    }
+
+    pub fn load_state_if_exists(&mut self) -> KlmResult<bool> {
+        let path = self.state_file.clone();
+        self.load_state_file(&path)
+    }
+
+    //Loads state file if there is one. Corrupted file is ignored, so
+    //keyboard keeps defaults, and file of older version is rewritten
+    //in current format.
+    pub fn load_state_file(&mut self, path: &Path) -> KlmResult<bool> {
+        if !path.exists() {
+            return Ok(false);
         }
-        buffer.push(self.colors.len().try_into().unwrap());
-        for color in &self.colors {
-            buffer.push(color.r);
-            buffer.push(color.g);
-            buffer.push(color.b);
+        log::i(TAG, &format!("Loading previous keyboard state from {}", path.display()));
+        let mut buffer = Vec::<u8>::new();
+        File::open(path).and_then(|mut file| file.read_to_end(&mut buffer))
+            .map_err(|e| KlmError::Persistence(format!("can not read {}: {}", path.display(), e)))?;
+        match self.load_state_from(&buffer) {
+            Ok(StateFormat::V1) => {},
+            Ok(StateFormat::V0) => {
+                log::i(TAG, &format!("Migrating {} to state file version {}", path.display(), STATE_VERSION));
+                if let Err(e) = self.save_state_to(path) {
+                    log::w(TAG, &format!("Unable to migrate state file: {}", e));
+                }
+            },
+            Err(e) => {
+                log::w(TAG, &format!("Ignoring corrupted state file {}: {}", path.display(), e));
+                return Ok(false);
+            },
         }
-        //Write to buffer to file
-        let mut file = File::create(CACHE_FILENAME).expect("Unable to create file");
-        file.write_all(&buffer).expect("Unable to write buffer");
-        true
+        Ok(true)
     }
 
+    pub fn get_color_modes(&self) -> Vec<KeyboardMode>{
+        self.driver.get_modes()
+    }
 
-    fn load_state(&mut self) -> bool {
-        let mut file = File::open(CACHE_FILENAME).expect("Unable to open file");
-        let mut state_buffer = [0u8; 1];
-        let mut color_buffer = [0u8; 3];
-        self.need_sync = true;
-        //Read Brightness
-        file.read_exact(&mut state_buffer).expect("Can not read brightness state");
-        self.brightness = state_buffer[0];
-        //Read speed
-        file.read_exact(&mut state_buffer).expect("Can not read speed state");
-        self.speed = state_buffer[0];
-        //Read state
-        file.read_exact(&mut state_buffer).expect("Can not read state");
-        self.state = KeyboardState::from_u8(state_buffer[0]).expect("Bad state specifier");
-        //Read power
-        file.read_exact(&mut state_buffer).expect("Can not read power");
-        let power_byte = state_buffer[0];
-        if power_byte == 0x0 {
-            self.power = false;
-        } else {
-            self.power = true;
+    pub fn get_color_count(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn get_status(&self) -> KeyboardStatus {
+        KeyboardStatus {
+            state: self.state,
+            power: self.power,
+            brightness: self.brightness,
+            speed: self.speed,
+            sync_locked: !self.syncing,
+            colors: self.colors.clone(),
         }
-        //Read number of colors
-        file.read_exact(&mut state_buffer).expect("Can not read color vector");
-        let n = state_buffer[0];
-        self.colors = Vec::<color::RGB>::new();
-        for _ in 0..n {
-            file.read_exact(&mut color_buffer).expect("Can no read from color buffer");
-            self.colors.push(color::RGB::new(color_buffer[0], color_buffer[1], color_buffer[2]));
+    }
+
+    pub fn get_capabilities(&self) -> Capabilities {
+        self.driver.get_capabilities()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Keyboard, KeyboardState, StateFormat};
+    use crate::drivers::ms1563::MS1563;
+    use crate::drivers::transport::RecordingTransport;
+    use crate::util::color;
+    use crate::util::u8::U8VecSerializable;
+
+    fn keyboard() -> (Keyboard, RecordingTransport) {
+        let transport = RecordingTransport::new();
+        let driver = Box::new(MS1563::with_transport(Box::new(transport.clone())));
+        (Keyboard::new(driver), transport)
+    }
+
+    #[test]
+    fn locked_keyboard_syncs_once() {
+        let (mut keyboard, transport) = keyboard();
+        keyboard.lock_sync();
+        keyboard.set_power(true);
+        keyboard.set_color(color::RGB::new(1, 2, 3)).unwrap();
+        keyboard.set_brightness(4).unwrap();
+        keyboard.set_state(KeyboardState::KeyboardSteady).unwrap();
+        assert!(transport.feature_reports().is_empty());
+        keyboard.unlock_sync();
+        keyboard.sync().unwrap();
+        let reports = transport.feature_reports();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0][..9], [0x02, 0x00, 0x01, 0x00, 0x04, 0x01, 1, 2, 3]);
+    }
+
+    #[test]
+    fn sync_without_changes_does_not_touch_driver() {
+        let (mut keyboard, transport) = keyboard();
+        keyboard.unlock_sync();
+        keyboard.sync().unwrap();
+        assert!(transport.feature_reports().is_empty());
+    }
+
+    #[test]
+    fn powered_off_keyboard_turns_lightning_off() {
+        let (mut keyboard, transport) = keyboard();
+        keyboard.unlock_sync();
+        keyboard.set_state(KeyboardState::KeyboardBreathing).unwrap();
+        let reports = transport.feature_reports();
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0][1..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn status_reflects_keyboard_state() {
+        let (mut keyboard, _) = keyboard();
+        keyboard.lock_sync();
+        keyboard.set_power(true);
+        keyboard.set_color(color::RGB::new(1, 2, 3)).unwrap();
+        keyboard.add_color(color::RGB::new(4, 5, 6)).unwrap();
+        keyboard.set_brightness(7).unwrap();
+        keyboard.set_speed(2).unwrap();
+        keyboard.set_state(KeyboardState::KeyboardBreathing).unwrap();
+        assert_eq!(keyboard.get_status().to_u8_vec(), vec![0x02, 1, 7, 2, 1, 2, 1, 2, 3, 4, 5, 6]);
+        keyboard.unlock_sync();
+        assert!(!keyboard.get_status().sync_locked);
+    }
+
+    #[test]
+    fn toggle_power_switches_between_states() {
+        let (mut keyboard, transport) = keyboard();
+        keyboard.lock_sync();
+        keyboard.add_color(color::RGB::new(9, 9, 9)).unwrap();
+        keyboard.set_speed(1).unwrap();
+        keyboard.set_brightness(3).unwrap();
+        keyboard.set_state(KeyboardState::KeyboardColorShift).unwrap();
+        keyboard.toggle_power().unwrap();
+        assert!(transport.feature_reports().is_empty());
+        keyboard.unlock_sync();
+        keyboard.sync().unwrap();
+        let reports = transport.feature_reports();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0][..12], [0x02, 0x00, 0x05, 0x01, 0x03, 0x02, 0, 0, 0, 9, 9, 9]);
+    }
+
+    #[test]
+    fn restored_snapshot_is_synced_again() {
+        let (mut keyboard, transport) = keyboard();
+        keyboard.unlock_sync();
+        keyboard.set_power(true);
+        keyboard.set_color(color::RGB::new(1, 2, 3)).unwrap();
+        let snapshot = keyboard.snapshot();
+        keyboard.lock_sync();
+        keyboard.add_color(color::RGB::new(4, 5, 6)).unwrap();
+        keyboard.set_state(KeyboardState::KeyboardBreathing).unwrap();
+        keyboard.restore(snapshot);
+        keyboard.unlock_sync();
+        assert_eq!(keyboard.get_status().to_u8_vec(), vec![0x0, 1, 0, 0, 0, 1, 1, 2, 3]);
+        keyboard.sync().unwrap();
+        assert_eq!(transport.feature_reports().len(), 2);
+    }
+
+    #[test]
+    fn state_is_loaded_from_buffer() {
+        let (mut keyboard, _) = keyboard();
+        keyboard.load_state_from(&[7, 2, 0x03, 1, 2, 1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(keyboard.get_status().to_u8_vec(), vec![0x03, 1, 7, 2, 1, 2, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn malformed_state_is_rejected() {
+        let (mut keyboard, _) = keyboard();
+        let status = keyboard.get_status().to_u8_vec();
+        for buffer in [&[][..], &[7, 2, 0x03, 1], &[7, 2, 0x09, 1, 0], &[7, 2, 0x03, 1, 255, 1, 2, 3]] {
+            assert!(keyboard.load_state_from(buffer).is_err());
         }
-        true
+        assert_eq!(keyboard.get_status().to_u8_vec(), status);
     }
 
-    pub fn load_state_if_exists(&mut self) -> bool {
-        if Path::new(CACHE_FILENAME).exists() {
-            log::i(TAG, &format!("Loading previous keyboard state from {}", CACHE_FILENAME));
-            self.load_state()
-        } else {
-            false
+    fn state_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("klmd-{}-{}.state", std::process::id(), name))
+    }
+
+    #[test]
+    fn state_file_is_versioned_and_checked() {
+        let (mut keyboard, _) = keyboard();
+        keyboard.load_state_from(&[7, 2, 0x03, 1, 1, 1, 2, 3]).unwrap();
+        let path = state_path("versioned");
+        keyboard.save_state_to(&path).unwrap();
+        let file = std::fs::read(&path).unwrap();
+        assert_eq!(file[..7], [b'K', b'L', b'M', b'S', 1, 0, 8]);
+        let (mut loaded, _) = self::keyboard();
+        assert_eq!(loaded.load_state_from(&file).unwrap(), StateFormat::V1);
+        assert_eq!(loaded.get_status().to_u8_vec(), keyboard.get_status().to_u8_vec());
+        let mut corrupted = file.clone();
+        corrupted[9] ^= 0x1;
+        for buffer in [&corrupted[..], &file[..file.len() - 1], &file[..6]] {
+            assert!(loaded.load_state_from(buffer).is_err());
         }
+        std::fs::remove_file(path).unwrap();
     }
 
-    pub fn get_color_modes(&self) -> Vec<KeyboardMode>{
-        self.driver.get_modes()
+    #[test]
+    fn corrupted_state_file_is_ignored() {
+        let (mut keyboard, _) = keyboard();
+        let status = keyboard.get_status().to_u8_vec();
+        let path = state_path("corrupted");
+        std::fs::write(&path, b"KLMS\x01\x00\x05\x07").unwrap();
+        assert!(!keyboard.load_state_file(&path).unwrap());
+        assert_eq!(keyboard.get_status().to_u8_vec(), status);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn v0_state_file_is_migrated() {
+        let (mut keyboard, _) = keyboard();
+        let path = state_path("v0");
+        std::fs::write(&path, [7, 2, 0x03, 1, 1, 1, 2, 3]).unwrap();
+        assert!(keyboard.load_state_file(&path).unwrap());
+        let file = std::fs::read(&path).unwrap();
+        let (mut loaded, _) = self::keyboard();
+        assert_eq!(loaded.load_state_from(&file).unwrap(), StateFormat::V1);
+        assert_eq!(loaded.get_status().to_u8_vec(), vec![0x03, 1, 7, 2, 1, 1, 1, 2, 3]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn driver_failure_is_returned_to_caller() {
+        let (mut keyboard, transport) = keyboard();
+        transport.set_failing(true);
+        keyboard.unlock_sync();
+        keyboard.set_power(true);
+        keyboard.set_color(color::RGB::new(1, 2, 3)).unwrap_err();
+        assert!(keyboard.set_state(KeyboardState::KeyboardSteady).is_err());
+        transport.set_failing(false);
+        keyboard.set_brightness(5).unwrap();
+        assert_eq!(transport.feature_reports().len(), 1);
     }
 }
@@ -0,0 +1,27 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+//Daemon is built as a library, so fuzz targets can drive
+//the same code as the binary does
+
+extern crate hidapi;
+
+pub mod config;
+pub mod devices;
+pub mod drivers;
+pub mod error;
+pub mod util;
+pub mod keyboard;
+pub mod listener;
+pub mod profiles;
+pub mod protocol;
+pub mod signals;
+pub mod systemd;
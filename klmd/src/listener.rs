@@ -10,71 +10,925 @@
  */
 
 
+use crate::config::{Config, ConfigLoader, SocketConfig};
+use crate::devices::{DeviceChange, Devices};
+use crate::error::{KlmError, KlmResult};
 use crate::protocol;
+use crate::protocol::error::{ProtoError, ProtoErrorCode};
+use crate::protocol::event::ProtoEvent;
+use crate::protocol::frame::{self, Frame};
+use crate::protocol::json::{self, JsonError, JsonRequest};
+use crate::protocol::response::ProtoResponse;
 use crate::util::log;
 use crate::keyboard;
+use crate::signals;
+use crate::systemd;
 
-use std::os::unix::net::UnixListener;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
 use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 use users::{Groups, UsersCache};
 use file_owner::PathExt;
-use crate::util::u8::U8Serializable;
 use crate::util::u8::U8VecSerializable;
 
 const TAG: &'static str = "listener";
+//Longest JSON request line accepted
+const MAX_JSON_LINE: u64 = 64 * 1024;
+//Number of threads serving client connections. Clients may keep
+//connection open, so each of them occupies a worker while connected.
+//Subscribed connections are handed over to notifier thread.
+const WORKERS: usize = 16;
+//Number of accepted connections waiting for a free worker
+const BACKLOG: usize = 16;
+//Clients which do not send whole request or receive data for this long
+//are disconnected, idle persistent connections are closed after the same time
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+//How often attached devices are checked for keyboard removal
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(2);
 
-fn set_socket_permissions(){
+//Request passed from connection workers to the thread owning keyboard
+pub struct Request {
+    pub buffer: Vec<u8>,
+    pub reply: mpsc::Sender<ProtoResponse>,
+}
+
+//Everything thread owning keyboard has to react on
+pub enum Event {
+    Request(Request),
+    //Save state and stop serving clients
+    Terminate,
+    //Reload configuration and probe devices again
+    Reload,
+    //Time to tell systemd that daemon is alive
+    Watchdog,
+    //Time to check whether keyboard is still attached
+    Poll,
+}
+
+//Accepted connection, along with protocol spoken on it
+enum Connection {
+    Binary(UnixStream),
+    Json(UnixStream),
+}
+
+//Connections which subscribed to events, as seen by keyboard thread
+struct Subscribers {
+    replies: Vec<mpsc::Sender<ProtoResponse>>,
+    //Notifier writing queued events to subscribed sockets
+    notices: mpsc::Sender<Notice>,
+}
+
+impl Subscribers {
+    fn new(notices: mpsc::Sender<Notice>) -> Subscribers {
+        Subscribers {
+            replies: vec![],
+            notices,
+        }
+    }
+
+    fn add(&mut self, reply: mpsc::Sender<ProtoResponse>) {
+        self.replies.push(reply);
+    }
+
+    //Queues event for every subscriber, forgetting disconnected ones
+    fn notify(&mut self, event: &ProtoEvent) {
+        self.replies.retain(|reply| reply.send(ProtoResponse::from_event(event)).is_ok());
+        let _ = self.notices.send(Notice::Events);
+    }
+}
+
+//Messages of notifier thread
+pub enum Notice {
+    //Connection subscribed, its socket is not read by worker anymore
+    Subscribe(Subscriber),
+    //Events were queued for subscribers
+    Events,
+}
+
+type EventEncoder = Box<dyn Fn(&ProtoResponse) -> Vec<u8> + Send>;
+
+//Subscribed connection owned by notifier. Events are encoded the same
+//way as response to subscribe request.
+pub struct Subscriber {
+    sock: UnixStream,
+    events: mpsc::Receiver<ProtoResponse>,
+    encode: EventEncoder,
+}
+
+impl Subscriber {
+    //Socket is made non-blocking, so a client which does not read
+    //events can not stall the others
+    fn new(sock: UnixStream, events: mpsc::Receiver<ProtoResponse>,
+           encode: EventEncoder) -> KlmResult<Subscriber> {
+        sock.set_nonblocking(true)?;
+        Ok(Subscriber { sock, events, encode })
+    }
+
+    //Writes queued events. Returns false if subscriber should be dropped.
+    fn flush(&mut self) -> bool {
+        loop {
+            match self.events.try_recv() {
+                Ok(event) => {
+                    if let Err(e) = self.sock.write_all(&(self.encode)(&event)) {
+                        log::w(TAG, &format!("Dropping subscriber: {}", e));
+                        return false;
+                    }
+                },
+                Err(mpsc::TryRecvError::Empty) => return true,
+                Err(mpsc::TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    //Anything subscriber sends is ignored, only end of stream matters
+    fn is_closed(&self) -> bool {
+        let mut buffer = [0u8; 64];
+        match (&self.sock).read(&mut buffer) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+        }
+    }
+}
+
+//Owns sockets of all subscribed connections, so they do not occupy
+//workers serving requests
+fn notify_subscribers(notices: mpsc::Receiver<Notice>) {
+    let mut subscribers: Vec<Subscriber> = vec![];
+    loop {
+        match notices.recv_timeout(CLIENT_TIMEOUT) {
+            Ok(Notice::Subscribe(subscriber)) => subscribers.push(subscriber),
+            Ok(Notice::Events) => {},
+            Err(mpsc::RecvTimeoutError::Timeout) => subscribers.retain(|subscriber| !subscriber.is_closed()),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+        subscribers.retain_mut(|subscriber| subscriber.flush());
+    }
+}
+
+fn spawn_notifier(notices: mpsc::Receiver<Notice>) {
+    thread::spawn(move || notify_subscribers(notices));
+}
+
+//User ids allowed to connect, None if everyone is. Shared with
+//acceptor threads and replaced on reload.
+type AllowedUids = Arc<RwLock<Option<Vec<u32>>>>;
+
+//Configuration in effect, read again on reload
+pub struct Settings {
+    loader: ConfigLoader,
+    config: Config,
+    allowed: AllowedUids,
+    //Sockets created by klmd, their permissions follow configuration
+    owned_sockets: Vec<String>,
+}
+
+impl Settings {
+    pub fn new(loader: ConfigLoader, config: Config) -> Settings {
+        Settings {
+            loader,
+            allowed: Arc::new(RwLock::new(config.allowed_uids())),
+            config,
+            owned_sockets: vec![],
+        }
+    }
+
+    //Applies settings which can change without restart. Invalid
+    //configuration is reported and previous one is kept.
+    fn reload(&mut self, keyboard: &mut keyboard::Keyboard, devices: &mut dyn Devices) {
+        let config = match self.loader.load() {
+            Ok(config) => config,
+            Err(e) => {
+                log::e(TAG, &format!("Keeping previous configuration: {}", e));
+                return;
+            },
+        };
+        if config.socket.path != self.config.socket.path || config.socket.json_path != self.config.socket.json_path {
+            log::w(TAG, "Socket paths are changed only on restart");
+        }
+        config.apply(keyboard);
+        devices.configure(&config.driver);
+        if let Ok(mut allowed) = self.allowed.write() {
+            *allowed = config.allowed_uids();
+        }
+        for path in self.owned_sockets.iter() {
+            if let Err(e) = set_socket_permissions(path, &config.socket) {
+                log::e(TAG, &format!("Unable to set permissions of {}: {}", path, e));
+            }
+        }
+        self.config = config;
+    }
+}
+
+fn set_socket_permissions(path: &str, config: &SocketConfig) -> KlmResult<()> {
     let cache = UsersCache::new();
-    let group = cache.get_group_by_name("klm");
-    let perms = std::fs::Permissions::from_mode(0o660);
+    let group = cache.get_group_by_name(&config.group);
+    let perms = std::fs::Permissions::from_mode(config.mode);
     if group.is_none() {
-        log::w(TAG, "You do not have klm group in your system.");
+        log::w(TAG, &format!("You do not have {} group in your system.", config.group));
         log::w(TAG, "The permissions for socket would be set, but you may be unable to access it");
-    } else {
-        "/var/run/klmd.sock".set_group("klm").unwrap();
-    }
-    std::fs::set_permissions("/var/run/klmd.sock",
-                             perms).unwrap();
-}
-//Listeners accept UNIX-socket connections
-//and reads to buffer requests. Then it passes
-//buffer to protocol handler.
-//TODO: check errors in listen
-pub fn listen(keyboard: &mut keyboard::Keyboard){
-    let listener = UnixListener::bind("/var/run/klmd.sock").unwrap();
-
-    set_socket_permissions();
-
-    log::i(TAG, "Started listening at /var/run/klmd.sock");
-
-    loop{
-        match listener.accept() {
-            Ok((mut sock, addr)) => {
-                log::d(TAG, &format!("Received connection from {:?} - {:?}", sock, addr));
-                let mut size_buffer = [0; 1];
-                let mut response = [0; 1];
-                let mut data_buffer = [0; 1];
-                sock.read_exact(&mut size_buffer).unwrap();
-
-                let sz = size_buffer[0];
-                log::d(TAG, &format!("Expecting request size to be {} bytes", sz));
-                if sz > 0 {
-                    let mut buffer = Vec::<u8>::with_capacity(sz as usize);
-                    for _ in 0..sz {
-                        sock.read_exact(&mut data_buffer).unwrap();
-                        buffer.push(data_buffer[0]);
+    } else if let Err(e) = path.set_group(config.group.as_str()) {
+        log::w(TAG, &format!("Unable to change socket group to {}: {}", config.group, e));
+    }
+    std::fs::set_permissions(path, perms)?;
+    Ok(())
+}
+
+fn keyboard_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "keyboard thread is not running")
+}
+
+fn bad_request() -> ProtoResponse {
+    ProtoResponse::from_state(protocol::response::ProtoResponseState::ResultBadRequest)
+}
+
+//Reads client socket against deadline for the whole request, so a
+//client sending it byte by byte can not hold worker forever
+struct DeadlineReader {
+    sock: UnixStream,
+    deadline: Instant,
+}
+
+impl DeadlineReader {
+    fn new(sock: UnixStream) -> DeadlineReader {
+        DeadlineReader { sock, deadline: Instant::now() + CLIENT_TIMEOUT }
+    }
+
+    //Called before each request
+    fn start(&mut self) {
+        self.deadline = Instant::now() + CLIENT_TIMEOUT;
+    }
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "client request timed out"));
+        }
+        self.sock.set_read_timeout(Some(left))?;
+        self.sock.read(buf)
+    }
+}
+
+//Reads requests from client until it closes connection or stays
+//idle, passes each one to keyboard thread and writes responses back
+//in the order requests were received
+fn handle_client(mut sock: UnixStream, requests: &mpsc::Sender<Event>,
+                 notices: &mpsc::Sender<Notice>) -> KlmResult<()> {
+    sock.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = DeadlineReader::new(sock.try_clone()?);
+    loop {
+        reader.start();
+        let frame = match frame::read_frame(&mut reader) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(()),
+            Err(KlmError::Protocol(reason)) => {
+                //Request could not be recognized, answer as v1 does
+                sock.write_all(&bad_request().to_u8_vec())?;
+                return Err(KlmError::Protocol(reason));
+            },
+            Err(e) => return Err(e),
+        };
+        //Rest of the stream can not be trusted after rejected frame
+        if let Frame::Rejected { reason, .. } = &frame {
+            let response = ProtoResponse::from_error(ProtoError::new(ProtoErrorCode::BadFrame, reason));
+            sock.write_all(&frame.encode_response(&response))?;
+            return Err(KlmError::Protocol(reason.clone()));
+        }
+        let buffer = frame.payload().to_vec();
+        let (reply, response) = mpsc::channel();
+        requests.send(Event::Request(Request { buffer, reply })).map_err(|_| keyboard_gone())?;
+        let result = response.recv().map_err(|_| keyboard_gone())?;
+        sock.write_all(&frame.encode_response(&result))?;
+        if result.subscribed {
+            let subscriber = Subscriber::new(sock, response, Box::new(move |event| frame.encode_response(event)))?;
+            return notices.send(Notice::Subscribe(subscriber)).map_err(|_| KlmError::Io(keyboard_gone()));
+        }
+    }
+}
+
+fn write_json(sock: &mut UnixStream, value: &serde_json::Value) -> KlmResult<()> {
+    let mut line = value.to_string();
+    line.push('\n');
+    sock.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+//Serves JSON requests, one per line, until client closes connection or
+//stays idle. Each request is answered with a single line.
+fn handle_json_client(mut sock: UnixStream, requests: &mpsc::Sender<Event>,
+                      notices: &mpsc::Sender<Notice>) -> KlmResult<()> {
+    sock.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(DeadlineReader::new(sock.try_clone()?));
+    loop {
+        reader.get_mut().start();
+        let mut line = String::new();
+        let size = (&mut reader).take(MAX_JSON_LINE).read_line(&mut line)?;
+        if size == 0 {
+            return Ok(());
+        }
+        if size as u64 == MAX_JSON_LINE && !line.ends_with('\n') {
+            let e = JsonError { code: "bad_json", index: None, message: "request line is too long".to_string() };
+            write_json(&mut sock, &e.to_json())?;
+            return Err(KlmError::Protocol(e.message));
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = match JsonRequest::parse(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                write_json(&mut sock, &e.to_json())?;
+                continue;
+            },
+        };
+        let (reply, response) = mpsc::channel();
+        let buffer = request.buffer().to_vec();
+        requests.send(Event::Request(Request { buffer, reply })).map_err(|_| keyboard_gone())?;
+        let result = response.recv().map_err(|_| keyboard_gone())?;
+        write_json(&mut sock, &request.response_json(&result))?;
+        if result.subscribed {
+            let subscriber = Subscriber::new(sock, response, Box::new(|event| {
+                let mut line = json::event_json(event).to_string();
+                line.push('\n');
+                line.into_bytes()
+            }))?;
+            return notices.send(Notice::Subscribe(subscriber)).map_err(|_| KlmError::Io(keyboard_gone()));
+        }
+    }
+}
+
+fn spawn_workers(connections: mpsc::Receiver<Connection>, requests: mpsc::Sender<Event>,
+                 notices: mpsc::Sender<Notice>) {
+    let connections = Arc::new(Mutex::new(connections));
+    for worker in 0..WORKERS {
+        let connections = connections.clone();
+        let requests = requests.clone();
+        let notices = notices.clone();
+        thread::spawn(move || loop {
+            let connection = {
+                let receiver = match connections.lock() {
+                    Ok(receiver) => receiver,
+                    Err(_) => return,
+                };
+                match receiver.recv() {
+                    Ok(connection) => connection,
+                    Err(_) => return,
+                }
+            };
+            let result = match connection {
+                Connection::Binary(sock) => handle_client(sock, &requests, &notices),
+                Connection::Json(sock) => handle_json_client(sock, &requests, &notices),
+            };
+            if let Err(e) = result {
+                log::w(TAG, &format!("worker {}: client dropped: {}", worker, e));
+            }
+        });
+    }
+}
+
+//Uid of process on the other end of connection, see unix(7)
+fn peer_uid(sock: &UnixStream) -> io::Result<u32> {
+    let mut credentials = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                         &mut credentials as *mut libc::ucred as *mut libc::c_void, &mut length)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(credentials.uid)
+}
+
+//Root may always connect, other users only if they are allowed
+fn is_allowed(sock: &UnixStream, allowed: &AllowedUids) -> bool {
+    let uid = match peer_uid(sock) {
+        Ok(uid) => uid,
+        Err(e) => {
+            log::e(TAG, &format!("Unable to get credentials of client: {}", e));
+            return false;
+        },
+    };
+    match allowed.read() {
+        Ok(allowed) => match allowed.as_ref() {
+            Some(uids) => uid == 0 || uids.contains(&uid),
+            None => true,
+        },
+        Err(_) => false,
+    }
+}
+
+fn spawn_acceptor(listener: UnixListener, connections: mpsc::SyncSender<Connection>,
+                  wrap: fn(UnixStream) -> Connection, allowed: AllowedUids) {
+    thread::spawn(move || {
+        for connection in listener.incoming() {
+            match connection {
+                Ok(sock) => {
+                    log::d(TAG, &format!("Received connection {:?}", sock));
+                    if !is_allowed(&sock, &allowed) {
+                        log::w(TAG, "Dropping connection of user who is not allowed to connect");
+                        continue;
+                    }
+                    if let Err(mpsc::TrySendError::Full(_)) = connections.try_send(wrap(sock)) {
+                        log::w(TAG, "Too many pending clients, dropping connection");
                     }
-                    let result = protocol::proto::proto_handle_message(keyboard, &buffer);
-                    let result_vec = result.to_u8_vec();
-                    sock.write_all(&result_vec).unwrap();
-                } else {
-                    log::e(TAG, "Request length is zero. Responding with bad request.");
-                    response[0] = protocol::response::ProtoResponseState::ResultBadRequest.to_u8();
-                    sock.write_all(&response).unwrap();
+                },
+                Err(e) => log::e(TAG, &format!("accept: {:?}", e)),
+            }
+        }
+    });
+}
+
+fn ready_status(keyboard: &keyboard::Keyboard) -> String {
+    format!("READY=1\nSTATUS=Serving {} keyboard", keyboard.get_capabilities().name)
+}
+
+//Periodic events go through the keyboard thread, so watchdog pings
+//stop and systemd restarts daemon if that thread hangs
+fn spawn_ticker(interval: Duration, events: mpsc::Sender<Event>, event: fn() -> Event) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if events.send(event()).is_err() {
+            return;
+        }
+    });
+}
+
+fn poll_devices(keyboard: &mut keyboard::Keyboard, devices: &mut dyn Devices,
+                subscribers: &mut Subscribers) {
+    match devices.poll(keyboard) {
+        Ok(Some(DeviceChange::Connected)) => {
+            subscribers.notify(&ProtoEvent::DeviceConnected(keyboard.get_capabilities()));
+        },
+        Ok(Some(DeviceChange::Removed)) => subscribers.notify(&ProtoEvent::DeviceRemoved),
+        Ok(None) => {},
+        Err(e) => log::e(TAG, &format!("Device polling failed: {}", e)),
+    }
+}
+
+//State subscribers are interested in. Sync lock only changes
+//while a request is being handled, so it is not compared.
+fn observed_state(keyboard: &keyboard::Keyboard) -> Vec<u8> {
+    let mut status = keyboard.get_status();
+    status.sync_locked = false;
+    status.to_u8_vec()
+}
+
+//Handles events on thread owning keyboard until termination is requested.
+//Subscribers are notified whenever keyboard state changes.
+fn serve(keyboard: &mut keyboard::Keyboard, devices: &mut dyn Devices, events: mpsc::Receiver<Event>,
+         notices: mpsc::Sender<Notice>, settings: &mut Settings) {
+    let mut subscribers = Subscribers::new(notices);
+    for event in events {
+        let state = observed_state(keyboard);
+        match event {
+            Event::Request(request) => {
+                let result = protocol::proto::proto_handle_message(keyboard, &request.buffer);
+                let subscribed = result.subscribed;
+                if request.reply.send(result).is_err() {
+                    log::w(TAG, "Client disconnected before receiving response");
+                } else if subscribed {
+                    subscribers.add(request.reply);
                 }
             },
-            Err(e) => log::e(TAG, &format!("accept: {:?}", e)),
+            Event::Reload => {
+                log::i(TAG, "Reloading configuration");
+                systemd::notify("RELOADING=1");
+                settings.reload(keyboard, devices);
+                if let Err(e) = devices.reload(keyboard) {
+                    log::e(TAG, &format!("Reload failed, keeping previous driver: {}", e));
+                }
+                systemd::notify(&ready_status(keyboard));
+            },
+            Event::Poll => poll_devices(keyboard, devices, &mut subscribers),
+            Event::Watchdog => systemd::notify("WATCHDOG=1"),
+            Event::Terminate => break,
+        }
+        if observed_state(keyboard) != state {
+            subscribers.notify(&ProtoEvent::StateChanged(keyboard.get_status()));
         }
     }
 }
+
+//Socket file is left behind if daemon was killed. Remove it, unless
+//there is another instance still listening on it.
+fn remove_stale_socket(path: &str) -> KlmResult<()> {
+    if !Path::new(path).exists() {
+        return Ok(());
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(KlmError::Io(io::Error::new(io::ErrorKind::AddrInUse,
+                                               format!("{} is used by another running instance", path))));
+    }
+    log::w(TAG, &format!("Removing stale socket {}", path));
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+//Listeners accept UNIX-socket connections and serve them on worker
+//threads. Requests are passed to protocol handler on calling thread,
+//which is the only one touching keyboard. Signals are handled on the
+//same thread, between requests.
+pub fn listen(keyboard: &mut keyboard::Keyboard, devices: &mut dyn Devices, mut settings: Settings) -> KlmResult<()> {
+    let socket = settings.config.socket.clone();
+    //Socket passed by systemd is owned by it, klmd should not remove it
+    let listener = match systemd::activated_listener()? {
+        Some(listener) => listener,
+        None => {
+            remove_stale_socket(&socket.path)?;
+            let listener = UnixListener::bind(&socket.path)?;
+            set_socket_permissions(&socket.path, &socket)?;
+            settings.owned_sockets.push(socket.path.clone());
+            listener
+        },
+    };
+    remove_stale_socket(&socket.json_path)?;
+    let json_listener = UnixListener::bind(&socket.json_path)?;
+    set_socket_permissions(&socket.json_path, &socket)?;
+    settings.owned_sockets.push(socket.json_path.clone());
+
+    log::i(TAG, &format!("Started listening at {} and {}", socket.path, socket.json_path));
+
+    let (connections_sender, connections) = mpsc::sync_channel(BACKLOG);
+    let (events_sender, events) = mpsc::channel::<Event>();
+    signals::forward_signals(events_sender.clone())?;
+    if let Some(interval) = systemd::watchdog() {
+        spawn_ticker(interval, events_sender.clone(), || Event::Watchdog);
+    }
+    spawn_ticker(DEVICE_POLL_INTERVAL, events_sender.clone(), || Event::Poll);
+    let (notices_sender, notices) = mpsc::channel();
+    spawn_notifier(notices);
+    spawn_workers(connections, events_sender, notices_sender.clone());
+    spawn_acceptor(listener, connections_sender.clone(), Connection::Binary, settings.allowed.clone());
+    spawn_acceptor(json_listener, connections_sender, Connection::Json, settings.allowed.clone());
+    systemd::notify(&ready_status(keyboard));
+
+    serve(keyboard, devices, events, notices_sender, &mut settings);
+
+    log::i(TAG, "Shutting down");
+    systemd::notify("STOPPING=1");
+    if let Err(e) = keyboard.save_state() {
+        log::e(TAG, &format!("Unable to save keyboard state: {}", e));
+    }
+    for path in settings.owned_sockets.iter() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle_client, handle_json_client, is_allowed, remove_stale_socket, serve, spawn_notifier,
+                spawn_workers, Connection, Event, Notice, Request, Settings, CLIENT_TIMEOUT, WORKERS};
+    use crate::config::{Config, ConfigLoader};
+    use crate::devices::{DeviceChange, Devices};
+    use crate::drivers::ms1563::MS1563;
+    use crate::drivers::transport::RecordingTransport;
+    use crate::error::KlmResult;
+    use crate::keyboard::Keyboard;
+    use crate::protocol::event::ProtoEvent;
+    use klm_proto::frame::{encode_header, FRAME_HEADER_SIZE};
+    use crate::protocol::response::{ProtoResponse, ProtoResponseState};
+    use crate::util::u8::U8VecSerializable;
+    use std::io::prelude::*;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn request_is_forwarded_to_keyboard_thread() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (requests_sender, requests) = mpsc::channel::<Event>();
+        let worker = thread::spawn(move || handle_client(server, &requests_sender, &mpsc::channel().0));
+        client.write_all(&[2, 0x08, 0x09]).unwrap();
+        let request = match requests.recv().unwrap() {
+            Event::Request(request) => request,
+            _ => panic!("expected request event"),
+        };
+        assert_eq!(request.buffer, vec![0x08, 0x09]);
+        request.reply.send(ProtoResponse::from_state(ProtoResponseState::ResultOk)).unwrap();
+        let mut response = [0xff; 1];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(response, [0x0]);
+        drop(client);
+        assert!(worker.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn zero_sized_request_is_rejected() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (requests_sender, requests) = mpsc::channel::<Event>();
+        client.write_all(&[0, b'X', b'Y', b'Z']).unwrap();
+        assert!(handle_client(server, &requests_sender, &mpsc::channel().0).is_err());
+        let mut response = [0xff; 1];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(response, [0x2]);
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn stalled_client_times_out() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (requests_sender, requests) = mpsc::channel::<Event>();
+        client.write_all(&[5, 0x08]).unwrap();
+        let started = Instant::now();
+        assert!(handle_client(server, &requests_sender, &mpsc::channel().0).is_err());
+        assert!(started.elapsed() >= CLIENT_TIMEOUT);
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn slow_drip_client_times_out() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (requests_sender, requests) = mpsc::channel::<Event>();
+        let drip = thread::spawn(move || {
+            client.write_all(&[200]).unwrap();
+            //Each byte arrives well within timeout, whole request does not
+            for _ in 0..20 {
+                thread::sleep(Duration::from_millis(500));
+                if client.write_all(&[0x03]).is_err() {
+                    break;
+                }
+            }
+        });
+        let started = Instant::now();
+        assert!(handle_client(server, &requests_sender, &mpsc::channel().0).is_err());
+        assert!(started.elapsed() < CLIENT_TIMEOUT + Duration::from_secs(1));
+        assert!(requests.try_recv().is_err());
+        drip.join().unwrap();
+    }
+
+    #[test]
+    fn slow_drip_json_client_times_out() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (requests_sender, requests) = mpsc::channel::<Event>();
+        let drip = thread::spawn(move || {
+            for _ in 0..20 {
+                thread::sleep(Duration::from_millis(500));
+                if client.write_all(b" ").is_err() {
+                    break;
+                }
+            }
+        });
+        let started = Instant::now();
+        assert!(handle_json_client(server, &requests_sender, &mpsc::channel().0).is_err());
+        assert!(started.elapsed() < CLIENT_TIMEOUT + Duration::from_secs(1));
+        assert!(requests.try_recv().is_err());
+        drip.join().unwrap();
+    }
+
+    #[test]
+    fn stale_socket_is_removed() {
+        let path = std::env::temp_dir().join(format!("klmd-stale-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        drop(UnixListener::bind(path).unwrap());
+        remove_stale_socket(path).unwrap();
+        assert!(!Path::new(path).exists());
+        remove_stale_socket(path).unwrap();
+    }
+
+    #[test]
+    fn socket_of_running_instance_is_kept() {
+        let path = std::env::temp_dir().join(format!("klmd-live-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _listener = UnixListener::bind(path).unwrap();
+        assert!(remove_stale_socket(path).is_err());
+        assert!(Path::new(path).exists());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn v2_request_is_answered_with_frame() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (requests_sender, requests) = mpsc::channel::<Event>();
+        let worker = thread::spawn(move || handle_client(server, &requests_sender, &mpsc::channel().0));
+        let mut request = encode_header(0xabcd, 1);
+        request.push(0x0b);
+        client.write_all(&request).unwrap();
+        let request = match requests.recv().unwrap() {
+            Event::Request(request) => request,
+            _ => panic!("expected request event"),
+        };
+        assert_eq!(request.buffer, vec![0x0b]);
+        request.reply.send(ProtoResponse::from_state(ProtoResponseState::ResultOk)).unwrap();
+        let mut response = [0xff; FRAME_HEADER_SIZE + 1];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(response[..FRAME_HEADER_SIZE], encode_header(0xabcd, 1)[..]);
+        assert_eq!(response[FRAME_HEADER_SIZE], 0x0);
+        drop(client);
+        assert!(worker.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (requests_sender, requests) = mpsc::channel::<Event>();
+        let worker = thread::spawn(move || handle_client(server, &requests_sender, &mpsc::channel().0));
+        let mut stream = vec![1, 0x08];
+        stream.extend(encode_header(1, 1));
+        stream.push(0x0b);
+        stream.extend([1, 0xff]);
+        client.write_all(&stream).unwrap();
+        let states = [ProtoResponseState::ResultOk, ProtoResponseState::ResultError,
+                      ProtoResponseState::ResultBadRequest];
+        let mut buffers = vec![];
+        for state in states {
+            match requests.recv().unwrap() {
+                Event::Request(request) => {
+                    buffers.push(request.buffer);
+                    request.reply.send(ProtoResponse::from_state(state)).unwrap();
+                },
+                _ => panic!("expected request event"),
+            }
+        }
+        assert_eq!(buffers, vec![vec![0x08], vec![0x0b], vec![0xff]]);
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut responses = vec![];
+        client.read_to_end(&mut responses).unwrap();
+        let mut expected = vec![0x0];
+        expected.extend(encode_header(1, 1));
+        expected.extend([0x1, 0x2]);
+        assert_eq!(responses, expected);
+        assert!(worker.join().unwrap().is_ok());
+    }
+
+    fn settings() -> Settings {
+        Settings::new(ConfigLoader::default(), Config::default())
+    }
+
+    struct FakeDevices {
+        changes: Vec<DeviceChange>,
+    }
+
+    impl Devices for FakeDevices {
+        fn reload(&mut self, _keyboard: &mut Keyboard) -> KlmResult<()> {
+            Ok(())
+        }
+
+        fn poll(&mut self, _keyboard: &mut Keyboard) -> KlmResult<Option<DeviceChange>> {
+            Ok(self.changes.pop())
+        }
+    }
+
+    #[test]
+    fn subscribers_receive_state_and_device_events() {
+        let mut keyboard = Keyboard::new(Box::new(MS1563::with_transport(Box::new(RecordingTransport::new()))));
+        let mut devices = FakeDevices { changes: vec![DeviceChange::Removed] };
+        let (events_sender, events) = mpsc::channel();
+        let (subscriber, subscription) = mpsc::channel();
+        let (reply, response) = mpsc::channel();
+        let (notices_sender, notices) = mpsc::channel();
+        events_sender.send(Event::Request(Request { buffer: vec![0x0c], reply: subscriber })).unwrap();
+        events_sender.send(Event::Request(Request { buffer: vec![0x03, 0x05], reply })).unwrap();
+        events_sender.send(Event::Poll).unwrap();
+        events_sender.send(Event::Poll).unwrap();
+        events_sender.send(Event::Terminate).unwrap();
+        serve(&mut keyboard, &mut devices, events, notices_sender, &mut settings());
+
+        assert!(matches!(notices.try_recv(), Ok(Notice::Events)));
+        assert_eq!(response.recv().unwrap().to_u8_vec(), vec![0x0]);
+        let subscribed = subscription.recv().unwrap();
+        assert!(subscribed.subscribed);
+        assert_eq!(subscribed.to_u8_vec(), vec![0x0]);
+        assert_eq!(subscription.recv().unwrap().to_u8_vec(), vec![0x4, 10, 0x0, 0, 0, 5, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(subscription.recv().unwrap().to_u8_vec(), vec![0x4, 1, 0x2]);
+        assert!(subscription.recv().is_err());
+    }
+
+    #[test]
+    fn subscribed_connection_streams_events() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (requests_sender, requests) = mpsc::channel::<Event>();
+        let (notices_sender, notices) = mpsc::channel();
+        spawn_notifier(notices);
+        let worker_notices = notices_sender.clone();
+        let worker = thread::spawn(move || handle_client(server, &requests_sender, &worker_notices));
+        client.write_all(&[1, 0x0c]).unwrap();
+        let request = match requests.recv().unwrap() {
+            Event::Request(request) => request,
+            _ => panic!("expected request event"),
+        };
+        let mut subscribed = ProtoResponse::from_state(ProtoResponseState::ResultOk);
+        subscribed.subscribed = true;
+        request.reply.send(subscribed).unwrap();
+        //Worker is released as soon as connection is subscribed
+        assert!(worker.join().unwrap().is_ok());
+        request.reply.send(ProtoResponse::from_event(&ProtoEvent::DeviceRemoved)).unwrap();
+        notices_sender.send(Notice::Events).unwrap();
+        let mut response = [0xff; 4];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(response, [0x0, 0x4, 1, 0x2]);
+    }
+
+    #[test]
+    fn subscribers_do_not_occupy_workers() {
+        let (events_sender, events) = mpsc::channel::<Event>();
+        let (notices_sender, notices) = mpsc::channel();
+        let (connections_sender, connections) = mpsc::sync_channel(WORKERS);
+        spawn_notifier(notices);
+        spawn_workers(connections, events_sender.clone(), notices_sender.clone());
+        let daemon = thread::spawn(move || {
+            let mut keyboard = Keyboard::new(Box::new(MS1563::with_transport(Box::new(RecordingTransport::new()))));
+            serve(&mut keyboard, &mut FakeDevices { changes: vec![] }, events, notices_sender, &mut settings());
+        });
+        let mut subscribers = vec![];
+        for _ in 0..WORKERS + 2 {
+            let (mut client, server) = UnixStream::pair().unwrap();
+            connections_sender.send(Connection::Binary(server)).unwrap();
+            client.write_all(&[1, 0x0c]).unwrap();
+            let mut response = [0xff; 1];
+            client.read_exact(&mut response).unwrap();
+            assert_eq!(response, [0x0]);
+            subscribers.push(client);
+        }
+        let (mut client, server) = UnixStream::pair().unwrap();
+        connections_sender.send(Connection::Binary(server)).unwrap();
+        client.write_all(&[2, 0x03, 5]).unwrap();
+        let mut response = [0xff; 1];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(response, [0x0]);
+        //Every subscriber is told about the change
+        for subscriber in subscribers.iter_mut() {
+            let mut event = [0xff; 3];
+            subscriber.read_exact(&mut event).unwrap();
+            assert_eq!(event, [0x4, 10, 0x0]);
+        }
+        events_sender.send(Event::Terminate).unwrap();
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn rust_client_is_served() {
+        use klm_client::{Client, ClientError, Color, ErrorCode, Mode, Request};
+        let (client, server) = UnixStream::pair().unwrap();
+        let (events_sender, events) = mpsc::channel::<Event>();
+        let daemon = thread::spawn(move || {
+            let mut keyboard = Keyboard::new(Box::new(MS1563::with_transport(Box::new(RecordingTransport::new()))));
+            serve(&mut keyboard, &mut FakeDevices { changes: vec![] }, events, mpsc::channel().0, &mut settings());
+        });
+        let requests_sender = events_sender.clone();
+        let worker = thread::spawn(move || handle_client(server, &requests_sender, &mpsc::channel().0));
+        let mut client = Client::from_stream(client);
+        client.send(&Request::new().set_color(Color::new(1, 2, 3)).brightness(5).mode(Mode::Steady).power(true))
+            .unwrap();
+        let state = client.state().unwrap();
+        assert_eq!((state.mode, state.power, state.brightness), (Mode::Steady, true, 5));
+        assert_eq!(state.colors, vec![Color::new(1, 2, 3)]);
+        let capabilities = client.capabilities().unwrap();
+        assert_eq!((capabilities.name.as_str(), capabilities.max_colors), ("MS1563", 7));
+        assert_eq!(client.modes().unwrap(), capabilities.modes);
+        let e = client.send(&Request::new().mode(Mode::Breathing).brightness(11)).unwrap_err();
+        assert!(matches!(e, ClientError::BadRequest { code: ErrorCode::BadArgument, offset: 2, .. }));
+        assert_eq!(client.state().unwrap().mode, Mode::Steady);
+        drop(client);
+        assert!(worker.join().unwrap().is_ok());
+        events_sender.send(Event::Terminate).unwrap();
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn json_requests_are_served() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let (events_sender, events) = mpsc::channel::<Event>();
+        let daemon = thread::spawn(move || {
+            let mut keyboard = Keyboard::new(Box::new(MS1563::with_transport(Box::new(RecordingTransport::new()))));
+            serve(&mut keyboard, &mut FakeDevices { changes: vec![] }, events, mpsc::channel().0, &mut settings());
+        });
+        let requests_sender = events_sender.clone();
+        let worker = thread::spawn(move || handle_json_client(server, &requests_sender, &mpsc::channel().0));
+        let mut writer = client.try_clone().unwrap();
+        writer.write_all(b"{\"cmd\":\"set_color\",\"color\":\"#00ff00\"}\n\n").unwrap();
+        writer.write_all(b"{\"cmd\":\"mode\",\"mode\":\"disco\"}\n").unwrap();
+        writer.write_all(b"[{\"cmd\":\"brightness\",\"value\":3},{\"cmd\":\"state\"}]\n").unwrap();
+        writer.shutdown(std::net::Shutdown::Write).unwrap();
+        let lines: Vec<String> = std::io::BufReader::new(client).lines().map(|line| line.unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], r#"{"ok":true}"#);
+        assert!(lines[1].starts_with(r#"{"error":{"code":"bad_argument","index":0,"#));
+        let state: serde_json::Value = serde_json::from_str(&lines[2]).unwrap();
+        assert_eq!((&state["state"]["brightness"], &state["state"]["colors"][0]), (&3.into(), &"#00ff00".into()));
+        assert!(worker.join().unwrap().is_ok());
+        events_sender.send(Event::Terminate).unwrap();
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn only_allowed_users_may_connect() {
+        let (client, _server) = UnixStream::pair().unwrap();
+        let uid = unsafe { libc::getuid() };
+        let allowed = |uids: Option<Vec<u32>>| is_allowed(&client, &std::sync::Arc::new(std::sync::RwLock::new(uids)));
+        assert!(allowed(None));
+        assert!(allowed(Some(vec![uid])));
+        assert_eq!(allowed(Some(vec![uid + 1])), uid == 0);
+    }
+}
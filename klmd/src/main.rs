@@ -9,47 +9,107 @@
  * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
  */
 
-extern crate hidapi;
+mod args;
 
-mod drivers;
-mod util;
-mod keyboard;
-mod listener;
-mod protocol;
-
-
-use crate::drivers::driver;
-use crate::drivers::ms1563;
-use crate::drivers::driver::Driver;
-use crate::util::log;
+use args::Args;
+use clap::Parser;
+use klmd::{devices, keyboard, listener};
+use klmd::config::{Config, ConfigLoader};
+use klmd::devices::Devices;
+use klmd::error::{KlmError, KlmResult};
+use klmd::util::log;
 
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
 
 const TAG: &'static str = "main";
-const VERSION: &'static str = "0.1.3"; //TODO: synchronize with cargo?
+const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
-fn main(){
-    log::i(TAG, &format!("klmd version {} starting.", VERSION));
-    log::w(TAG, "This version is early alpha and is not intended to be used in production mode. Many features are not yet implemnted.");
+fn list_devices(config: &Config) -> KlmResult<()> {
+    let mut devices = devices::HidDevices::new(config.driver.clone())?;
+    for device in devices.list()? {
+        println!("{:04x}:{:04x} {:<32} {}", device.vendor_id, device.product_id,
+                 device.product.unwrap_or_default(),
+                 device.driver.map(|name| format!("driver {}", name)).unwrap_or_else(|| "unsupported".to_string()));
+    }
+    Ok(())
+}
 
+fn os_error() -> KlmError {
+    KlmError::Io(io::Error::last_os_error())
+}
 
-    let api = match hidapi::HidApi::new() {
-        Ok(api) => Some(api),
-        Err(e) => {
-            log::panic(TAG, &format!("Can not initialize HID API: {}", e));
-            None
-        },
-    }.unwrap();
+//Detaches from terminal: parent exits once child is forked, child
+//starts new session and drops standard streams. Has to be done
+//before any thread is started.
+fn daemonize() -> KlmResult<()> {
+    match unsafe { libc::fork() } {
+        -1 => return Err(os_error()),
+        0 => {},
+        _ => std::process::exit(0),
+    }
+    if unsafe { libc::setsid() } == -1 {
+        return Err(os_error());
+    }
+    std::env::set_current_dir("/")?;
+    let null = File::options().read(true).write(true).open("/dev/null")?;
+    for fd in 0..3 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(os_error());
+        }
+    }
+    Ok(())
+}
 
-    //TODO: here the dynamic loading of drivers should happen
-    if !ms1563::MS1563::is_present(&api){
-        log::e(TAG, "This program supports only MS1563 keyboards.");
-        log::panic(TAG, "No compatiable keyboard found!");
+//Restores previous state, or boot state of configuration, and
+//serves clients until termination
+fn serve(keyboard: &mut keyboard::Keyboard, devices: &mut dyn Devices, loader: ConfigLoader,
+         config: Config) -> KlmResult<()> {
+    config.apply(keyboard);
+    match keyboard.load_state_if_exists() {
+        Ok(true) => {},
+        Ok(false) => {
+            if let Err(e) = config.boot_state.apply(keyboard) {
+                log::w(TAG, &format!("Unable to apply boot state: {}", e));
+            }
+        },
+        Err(e) => log::w(TAG, &format!("Unable to restore previous state, using defaults: {}", e)),
+    }
+    if let Err(e) = keyboard.sync() {
+        log::e(TAG, &format!("Unable to apply keyboard state: {}", e));
     }
+    listener::listen(keyboard, devices, listener::Settings::new(loader, config))
+}
 
-    let driver = Box::new(ms1563::MS1563::new(&api).unwrap());
-    let mut keyboard = keyboard::Keyboard::new(driver);
-    keyboard.load_state_if_exists();
-    keyboard.sync();
-    listener::listen(&mut keyboard);
+fn run(args: Args) -> KlmResult<()> {
+    let loader = ConfigLoader::new(args.config.as_deref(), args.overrides());
+    let config = loader.load()?;
+    log::set_level(config.log_level());
+    if args.list_devices {
+        return list_devices(&config);
+    }
+    if args.daemon {
+        log::i(TAG, "Detaching from terminal, further messages are discarded");
+        daemonize()?;
+    }
+    log::i(TAG, &format!("klmd version {} starting.", VERSION));
+    log::w(TAG, "This version is early alpha and is not intended to be used in production mode. Many features are not yet implemnted.");
+    if args.dry_run {
+        log::w(TAG, "Dry run: keyboard is not touched");
+        let mut devices = devices::NullDevices;
+        let mut keyboard = keyboard::Keyboard::new(devices.open_driver());
+        serve(&mut keyboard, &mut devices, loader, config)
+    } else {
+        let mut devices = devices::HidDevices::new(config.driver.clone())?;
+        let mut keyboard = keyboard::Keyboard::new(devices.open_driver()?);
+        serve(&mut keyboard, &mut devices, loader, config)
+    }
+}
 
+fn main(){
+    if let Err(e) = run(Args::parse()) {
+        log::e(TAG, &format!("Fatal: {}", e));
+        std::process::exit(1);
+    }
 }
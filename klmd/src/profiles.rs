@@ -0,0 +1,132 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::error::{KlmError, KlmResult};
+use crate::keyboard::{self, KeyboardStatus};
+use crate::util::log;
+use crate::util::u8::U8VecSerializable;
+
+use klm_proto::names::parse_profile_name;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const TAG: &'static str = "profiles";
+const PROFILE_EXTENSION: &'static str = "state";
+
+//Profiles are named state files in profiles directory. They are
+//written in the same format as the state file, power is not applied.
+
+//Names of stored profiles, count followed by names prefixed with their length
+pub struct ProfileList {
+    pub names: Vec<String>,
+}
+
+impl U8VecSerializable for ProfileList {
+    fn to_u8_vec(&self) -> Vec<u8> {
+        let mut result = vec![self.names.len().min(255) as u8];
+        for name in self.names.iter().take(255) {
+            result.push(name.len() as u8);
+            result.extend(name.as_bytes());
+        }
+        result
+    }
+}
+
+pub fn profile_path(dir: &Path, name: &str) -> KlmResult<PathBuf> {
+    parse_profile_name(name).map_err(KlmError::Protocol)?;
+    Ok(dir.join(format!("{}.{}", name, PROFILE_EXTENSION)))
+}
+
+pub fn exists(dir: &Path, name: &str) -> KlmResult<bool> {
+    Ok(profile_path(dir, name)?.is_file())
+}
+
+//Profiles sorted by name. Missing directory means there are none yet.
+pub fn list(dir: &Path) -> KlmResult<ProfileList> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ProfileList { names: vec![] }),
+        Err(e) => return Err(KlmError::Persistence(format!("can not list {}: {}", dir.display(), e))),
+    };
+    let mut names: Vec<String> = entries.filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == PROFILE_EXTENSION))
+        .filter_map(|path| path.file_stem().and_then(|stem| stem.to_str()).map(|stem| stem.to_string()))
+        .filter(|name| klm_proto::is_valid_profile_name(name))
+        .collect();
+    names.sort();
+    Ok(ProfileList { names })
+}
+
+//State stored in profile, without applying it
+pub fn status(dir: &Path, name: &str) -> KlmResult<KeyboardStatus> {
+    let (snapshot, _) = keyboard::decode_state_file(&read(dir, name)?)?;
+    Ok(snapshot.to_status())
+}
+
+pub fn read(dir: &Path, name: &str) -> KlmResult<Vec<u8>> {
+    let path = profile_path(dir, name)?;
+    fs::read(&path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => KlmError::Protocol(format!("there is no profile '{}'", name)),
+        _ => KlmError::Persistence(format!("can not read {}: {}", path.display(), e)),
+    })
+}
+
+//Directory is created on first save
+pub fn write(dir: &Path, name: &str, buffer: &[u8]) -> KlmResult<()> {
+    let path = profile_path(dir, name)?;
+    fs::create_dir_all(dir)
+        .map_err(|e| KlmError::Persistence(format!("can not create {}: {}", dir.display(), e)))?;
+    keyboard::write_atomically(&path, buffer)?;
+    log::i(TAG, &format!("Saved profile {}", name));
+    Ok(())
+}
+
+pub fn delete(dir: &Path, name: &str) -> KlmResult<()> {
+    let path = profile_path(dir, name)?;
+    fs::remove_file(&path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => KlmError::Protocol(format!("there is no profile '{}'", name)),
+        _ => KlmError::Persistence(format!("can not delete {}: {}", path.display(), e)),
+    })?;
+    log::i(TAG, &format!("Deleted profile {}", name));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{delete, list, read, write};
+    use crate::util::u8::U8VecSerializable;
+
+    fn profiles_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("klmd-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn profiles_are_stored_by_name() {
+        let dir = profiles_dir("profiles");
+        assert!(list(&dir).unwrap().names.is_empty());
+        write(&dir, "work", &[1, 2]).unwrap();
+        write(&dir, "night", &[3]).unwrap();
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+        let profiles = list(&dir).unwrap();
+        assert_eq!(profiles.names, vec!["night", "work"]);
+        assert_eq!(profiles.to_u8_vec(), vec![2, 5, b'n', b'i', b'g', b'h', b't', 4, b'w', b'o', b'r', b'k']);
+        assert_eq!(read(&dir, "work").unwrap(), vec![1, 2]);
+        delete(&dir, "work").unwrap();
+        assert!(read(&dir, "work").is_err());
+        assert!(delete(&dir, "work").is_err());
+        assert!(write(&dir, "../state", &[1]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
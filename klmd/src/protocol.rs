@@ -8,5 +8,9 @@
  *
  * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
  */
+pub mod error;
+pub mod event;
+pub mod frame;
+pub mod json;
 pub mod proto;
 pub mod response;
@@ -0,0 +1,129 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::error::KlmError;
+use crate::protocol::response::ProtoResponseState;
+use crate::util::u8::{U8Serializable, U8VecSerializable};
+
+use klm_proto::{DecodeError, DecodeErrorKind};
+use std::fmt;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ProtoErrorCode {
+    UnknownCommand,
+    //Message ended in the middle of a command
+    Truncated,
+    BadArgument,
+    //Request has more colors than driver supports
+    TooManyColors,
+    //Driver does not support requested operation
+    NotSupported,
+    //Communication with keyboard failed
+    DeviceFailure,
+    Internal,
+    //Frame header is malformed or of unsupported version
+    BadFrame,
+}
+
+impl U8Serializable for ProtoErrorCode {
+    fn to_u8(&self) -> u8 {
+        match *self {
+            ProtoErrorCode::UnknownCommand => 0x01,
+            ProtoErrorCode::Truncated => 0x02,
+            ProtoErrorCode::BadArgument => 0x03,
+            ProtoErrorCode::TooManyColors => 0x04,
+            ProtoErrorCode::NotSupported => 0x05,
+            ProtoErrorCode::DeviceFailure => 0x06,
+            ProtoErrorCode::Internal => 0x07,
+            ProtoErrorCode::BadFrame => 0x08,
+        }
+    }
+}
+
+impl ProtoErrorCode {
+    //Status reported to clients which do not understand error details
+    pub fn to_state(self) -> ProtoResponseState {
+        match self {
+            ProtoErrorCode::UnknownCommand | ProtoErrorCode::Truncated |
+            ProtoErrorCode::BadArgument | ProtoErrorCode::TooManyColors |
+            ProtoErrorCode::BadFrame => ProtoResponseState::ResultBadRequest,
+            _ => ProtoResponseState::ResultError,
+        }
+    }
+}
+
+//Reason of failed request. Offset points to the command which failed,
+//or to the end of message if it failed while applying whole request.
+#[derive(Debug)]
+pub struct ProtoError {
+    pub code: ProtoErrorCode,
+    pub offset: usize,
+    pub message: String,
+}
+
+impl ProtoError {
+    pub fn new(code: ProtoErrorCode, message: &str) -> ProtoError {
+        ProtoError {
+            code,
+            offset: 0,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn at(mut self, offset: usize) -> ProtoError {
+        self.offset = offset;
+        self
+    }
+}
+
+impl From<KlmError> for ProtoError {
+    fn from(e: KlmError) -> ProtoError {
+        let code = match e {
+            KlmError::Hid(_) | KlmError::Io(_) => ProtoErrorCode::DeviceFailure,
+            KlmError::Driver(_) => ProtoErrorCode::NotSupported,
+            KlmError::Protocol(_) | KlmError::State(_) => ProtoErrorCode::BadArgument,
+            KlmError::Persistence(_) | KlmError::Config(_) => ProtoErrorCode::Internal,
+        };
+        ProtoError::new(code, &e.to_string())
+    }
+}
+
+impl From<DecodeError> for ProtoError {
+    fn from(e: DecodeError) -> ProtoError {
+        let code = match e.kind {
+            DecodeErrorKind::UnknownCommand => ProtoErrorCode::UnknownCommand,
+            DecodeErrorKind::Truncated => ProtoErrorCode::Truncated,
+            DecodeErrorKind::BadArgument => ProtoErrorCode::BadArgument,
+        };
+        ProtoError::new(code, &e.message).at(e.offset)
+    }
+}
+
+impl fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} at {}: {}", self.code, self.offset, self.message)
+    }
+}
+
+//Error details: code, offset as 4 bytes and message prefixed by 2 bytes of length
+impl U8VecSerializable for ProtoError {
+    fn to_u8_vec(&self) -> Vec<u8> {
+        let mut message = self.message.as_bytes();
+        if message.len() > u16::MAX as usize {
+            message = &message[..u16::MAX as usize];
+        }
+        let mut result = vec![self.code.to_u8()];
+        result.extend((self.offset as u32).to_be_bytes());
+        result.extend((message.len() as u16).to_be_bytes());
+        result.extend(message);
+        result
+    }
+}
@@ -0,0 +1,40 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::drivers::driver::Capabilities;
+use crate::keyboard::KeyboardStatus;
+use crate::util::u8::U8VecSerializable;
+
+//Events pushed to subscribed clients
+pub enum ProtoEvent {
+    //Mode, power, brightness, speed or colors of keyboard changed
+    StateChanged(KeyboardStatus),
+    DeviceConnected(Capabilities),
+    DeviceRemoved,
+}
+
+impl U8VecSerializable for ProtoEvent {
+    fn to_u8_vec(&self) -> Vec<u8> {
+        match self {
+            ProtoEvent::StateChanged(status) => {
+                let mut result = vec![0x0];
+                result.extend(status.to_u8_vec());
+                result
+            },
+            ProtoEvent::DeviceConnected(capabilities) => {
+                let mut result = vec![0x1];
+                result.extend(capabilities.to_u8_vec());
+                result
+            },
+            ProtoEvent::DeviceRemoved => vec![0x2],
+        }
+    }
+}
@@ -0,0 +1,202 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::error::{KlmError, KlmResult};
+use crate::protocol::response::ProtoResponse;
+use crate::util::log;
+use crate::util::u8::U8VecSerializable;
+
+use klm_proto::frame::{decode_header, encode_header, FRAME_HEADER_SIZE, FRAME_MAGIC, MAX_PAYLOAD_SIZE,
+                       PROTO_VERSION};
+use std::io;
+use std::io::Read;
+
+const TAG: &'static str = "proto/frame";
+
+pub enum Frame {
+    //Legacy packet: one byte of size and up to 255 bytes of commands
+    V1(Vec<u8>),
+    V2 { request_id: u32, payload: Vec<u8> },
+    //Frame header was read, but request can not be served
+    Rejected { request_id: u32, reason: String },
+}
+
+//Reads first byte of a frame. Connection closed or left idle
+//between frames is not an error, there are just no more requests.
+fn read_frame_start(reader: &mut dyn Read) -> KlmResult<Option<u8>> {
+    let mut byte = [0u8; 1];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                log::d(TAG, "Client is idle, closing connection");
+                return Ok(None);
+            },
+            Err(e) => return Err(KlmError::Io(e)),
+        }
+    }
+}
+
+//Reads one request of any supported protocol version.
+//Returns None when client has no more requests.
+pub fn read_frame(reader: &mut dyn Read) -> KlmResult<Option<Frame>> {
+    let size = match read_frame_start(reader)? {
+        Some(size) => [size],
+        None => return Ok(None),
+    };
+    if size[0] != 0 {
+        log::d(TAG, &format!("v1 request of {} bytes", size[0]));
+        let mut buffer = vec![0; size[0] as usize];
+        reader.read_exact(&mut buffer)?;
+        return Ok(Some(Frame::V1(buffer)));
+    }
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    reader.read_exact(&mut header[1..FRAME_MAGIC.len()])?;
+    if header[..FRAME_MAGIC.len()] != FRAME_MAGIC {
+        return Err(KlmError::Protocol("zero request size or bad frame magic".to_string()));
+    }
+    reader.read_exact(&mut header[FRAME_MAGIC.len()..])?;
+    let (version, request_id, length) = match decode_header(&header) {
+        Some(header) => (header.version, header.request_id, header.length),
+        None => return Err(KlmError::Protocol("bad frame magic".to_string())),
+    };
+    log::d(TAG, &format!("v{} request {} of {} bytes", version, request_id, length));
+    if version != PROTO_VERSION {
+        return Ok(Some(Frame::Rejected { request_id,
+                                         reason: format!("unsupported protocol version {}", version) }));
+    }
+    if length == 0 {
+        return Ok(Some(Frame::Rejected { request_id, reason: "request is empty".to_string() }));
+    }
+    if length > MAX_PAYLOAD_SIZE {
+        return Ok(Some(Frame::Rejected { request_id,
+                                         reason: format!("request of {} bytes is too big", length) }));
+    }
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(Frame::V2 { request_id, payload }))
+}
+
+impl Frame {
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            Frame::V1(buffer) => buffer,
+            Frame::V2 { payload, .. } => payload,
+            Frame::Rejected { .. } => &[],
+        }
+    }
+
+    //Encodes response in the same protocol version as request
+    pub fn encode_response(&self, response: &ProtoResponse) -> Vec<u8> {
+        let request_id = match self {
+            Frame::V1(_) => return response.to_u8_vec(),
+            Frame::V2 { request_id, .. } => *request_id,
+            Frame::Rejected { request_id, .. } => *request_id,
+        };
+        let payload = response.to_payload();
+        let mut result = encode_header(request_id, payload.len() as u32);
+        result.extend(payload);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_frame, Frame};
+    use klm_proto::frame::{encode_header, MAX_PAYLOAD_SIZE};
+    use crate::protocol::response::{ProtoResponse, ProtoResponseState};
+    use crate::util::color;
+    use crate::util::u8::U8VecSerializable;
+
+    fn v2_request(request_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut request = encode_header(request_id, payload.len() as u32);
+        request.extend(payload);
+        request
+    }
+
+    #[test]
+    fn v1_request_is_read() {
+        let frame = read_frame(&mut &[2u8, 0x08, 0x09, 0xff][..]).unwrap().unwrap();
+        assert!(matches!(&frame, Frame::V1(buffer) if buffer == &vec![0x08, 0x09]));
+        let response = ProtoResponse::from_state(ProtoResponseState::ResultOk);
+        assert_eq!(frame.encode_response(&response), vec![0x0]);
+    }
+
+    #[test]
+    fn v2_request_is_read() {
+        let request = v2_request(0x01020304, &[0x03, 0x05]);
+        assert_eq!(request[..9], [0x00, b'K', b'L', b'M', 2, 1, 2, 3, 4]);
+        let frame = read_frame(&mut &request[..]).unwrap().unwrap();
+        assert!(matches!(&frame, Frame::V2 { request_id: 0x01020304, payload } if payload == &vec![0x03, 0x05]));
+    }
+
+    #[test]
+    fn v2_response_echoes_request_id() {
+        let frame = read_frame(&mut &v2_request(7, &[0x08])[..]).unwrap().unwrap();
+        let response = ProtoResponse::from_state(ProtoResponseState::ResultBadRequest);
+        assert_eq!(frame.encode_response(&response), vec![0x00, b'K', b'L', b'M', 2, 0, 0, 0, 7, 0, 0, 0, 1, 0x2]);
+    }
+
+    #[test]
+    fn v2_response_is_not_limited_to_255_bytes() {
+        let frame = read_frame(&mut &v2_request(1, &[0x0b])[..]).unwrap().unwrap();
+        let mut response = ProtoResponse::from_state(ProtoResponseState::ResultOk);
+        response.add_response(Box::new(vec![color::RGB::new(1, 2, 3); 100]));
+        let encoded = frame.encode_response(&response);
+        assert_eq!(encoded[9..13], 301u32.to_be_bytes());
+        assert_eq!(encoded[13], 0x3);
+        assert_eq!(encoded.len(), 13 + 301);
+        assert_eq!(response.to_u8_vec(), vec![0x1]);
+    }
+
+    #[test]
+    fn bad_magic_is_error() {
+        assert!(read_frame(&mut &[0u8, b'X', b'Y', b'Z'][..]).is_err());
+    }
+
+    #[test]
+    fn unsupported_version_is_rejected() {
+        let mut request = v2_request(9, &[0x08]);
+        request[4] = 3;
+        let frame = read_frame(&mut &request[..]).unwrap().unwrap();
+        assert!(matches!(frame, Frame::Rejected { request_id: 9, .. }));
+        assert!(frame.payload().is_empty());
+    }
+
+    #[test]
+    fn empty_and_oversized_requests_are_rejected() {
+        let frame = read_frame(&mut &v2_request(1, &[])[..]).unwrap().unwrap();
+        assert!(matches!(frame, Frame::Rejected { request_id: 1, .. }));
+        let frame = read_frame(&mut &encode_header(2, MAX_PAYLOAD_SIZE + 1)[..]).unwrap().unwrap();
+        assert!(matches!(frame, Frame::Rejected { request_id: 2, .. }));
+    }
+
+    #[test]
+    fn frames_are_read_until_end_of_stream() {
+        let mut stream = vec![1u8, 0x08];
+        stream.extend(v2_request(5, &[0x0b]));
+        stream.extend([1u8, 0x09]);
+        let mut reader = &stream[..];
+        assert!(matches!(read_frame(&mut reader).unwrap(), Some(Frame::V1(_))));
+        assert!(matches!(read_frame(&mut reader).unwrap(), Some(Frame::V2 { request_id: 5, .. })));
+        assert!(matches!(read_frame(&mut reader).unwrap(), Some(Frame::V1(_))));
+        assert!(read_frame(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_frame_is_error() {
+        let request = v2_request(1, &[0x08, 0x09]);
+        assert!(read_frame(&mut &request[..request.len() - 1]).is_err());
+        assert!(read_frame(&mut &request[..6]).is_err());
+    }
+}
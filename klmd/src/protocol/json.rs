@@ -0,0 +1,297 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2022 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+//Newline-delimited JSON requests. Each line holds one command object,
+//or an array of them applied as a single request, e.g.
+//{"cmd":"set_color","color":"#00ff00"}. Requests are translated into
+//binary commands, so they are handled exactly like binary ones.
+
+use crate::protocol::response::ProtoResponse;
+
+use klm_proto::names::{parse_color, parse_mode};
+use klm_client::response::{decode_capabilities, decode_event, decode_modes, decode_payload, decode_profiles,
+                           decode_state};
+use klm_client::{json as client_json, ClientError};
+use klm_proto::{Color, Command, Mode};
+use serde_json::{json, Map, Value};
+
+//Reason JSON request could not be served. Index is position of
+//failed command in request, if failure is caused by one.
+#[derive(PartialEq, Debug)]
+pub struct JsonError {
+    pub code: &'static str,
+    pub index: Option<usize>,
+    pub message: String,
+}
+
+impl JsonError {
+    fn new(code: &'static str, index: Option<usize>, message: &str) -> JsonError {
+        JsonError {
+            code,
+            index,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut error = json!({ "code": self.code, "message": self.message });
+        if let Some(index) = self.index {
+            error["index"] = json!(index);
+        }
+        json!({ "ok": false, "error": error })
+    }
+}
+
+pub struct JsonRequest {
+    commands: Vec<Command>,
+    //Offset of each command in encoded request
+    offsets: Vec<usize>,
+    buffer: Vec<u8>,
+}
+
+fn field<'a>(object: &'a Map<String, Value>, name: &str) -> Result<&'a Value, String> {
+    object.get(name).ok_or_else(|| format!("missing field '{}'", name))
+}
+
+fn color_field(object: &Map<String, Value>, name: &str) -> Result<Color, String> {
+    match field(object, name)? {
+        Value::String(color) => parse_color(color),
+        _ => Err(format!("'{}' must be a color string", name)),
+    }
+}
+
+fn u8_field(object: &Map<String, Value>, name: &str) -> Result<u8, String> {
+    field(object, name)?.as_u64()
+        .filter(|value| *value <= u8::MAX as u64)
+        .map(|value| value as u8)
+        .ok_or_else(|| format!("'{}' must be a number from 0 to 255", name))
+}
+
+fn mode_field(object: &Map<String, Value>) -> Result<Mode, String> {
+    match field(object, "mode")? {
+        Value::String(mode) => parse_mode(mode),
+        _ => Err("'mode' must be a string".to_string()),
+    }
+}
+
+fn name_field(object: &Map<String, Value>) -> Result<String, String> {
+    match field(object, "name")? {
+        Value::String(name) => Ok(name.clone()),
+        _ => Err("'name' must be a string".to_string()),
+    }
+}
+
+fn parse_colors(object: &Map<String, Value>) -> Result<Vec<Color>, String> {
+    match field(object, "colors")? {
+        Value::Array(colors) => colors.iter().map(|color| match color {
+            Value::String(color) => parse_color(color),
+            _ => Err("'colors' must be an array of color strings".to_string()),
+        }).collect(),
+        _ => Err("'colors' must be an array of color strings".to_string()),
+    }
+}
+
+fn parse_command(value: &Value, index: usize) -> Result<Command, JsonError> {
+    let bad_argument = |message: String| JsonError::new("bad_argument", Some(index), &message);
+    let object = value.as_object()
+        .ok_or_else(|| bad_argument("command must be an object".to_string()))?;
+    let name = field(object, "cmd").map_err(bad_argument)?.as_str()
+        .ok_or_else(|| bad_argument("'cmd' must be a string".to_string()))?;
+    let command = match name {
+        "colors" => Command::Colors(parse_colors(object).map_err(bad_argument)?),
+        "set_color" => Command::SetColor(color_field(object, "color").map_err(bad_argument)?),
+        "add_color" => Command::AddColor(color_field(object, "color").map_err(bad_argument)?),
+        "brightness" => Command::Brightness(u8_field(object, "value").map_err(bad_argument)?),
+        "speed" => Command::Speed(u8_field(object, "value").map_err(bad_argument)?),
+        "mode" => Command::Mode(mode_field(object).map_err(bad_argument)?),
+        "power" => Command::Power(field(object, "power").map_err(bad_argument)?.as_bool()
+            .ok_or_else(|| bad_argument("'power' must be true or false".to_string()))?),
+        "toggle" => Command::Toggle,
+        "modes" => Command::RequestModes,
+        "capabilities" => Command::RequestCapabilities,
+        "state" => Command::RequestState,
+        "subscribe" => Command::Subscribe,
+        "save_profile" => Command::SaveProfile(name_field(object).map_err(bad_argument)?),
+        "apply_profile" => Command::ApplyProfile(name_field(object).map_err(bad_argument)?),
+        "delete_profile" => Command::DeleteProfile(name_field(object).map_err(bad_argument)?),
+        "profiles" => Command::RequestProfiles,
+        "profile" => Command::RequestProfile(name_field(object).map_err(bad_argument)?),
+        "write_profile" => Command::WriteProfile {
+            name: name_field(object).map_err(bad_argument)?,
+            mode: mode_field(object).map_err(bad_argument)?,
+            brightness: u8_field(object, "brightness").map_err(bad_argument)?,
+            speed: u8_field(object, "speed").map_err(bad_argument)?,
+            colors: parse_colors(object).map_err(bad_argument)?,
+        },
+        _ => return Err(JsonError::new("unknown_command", Some(index), &format!("unknown command '{}'", name))),
+    };
+    Ok(command)
+}
+
+fn query_name(command: &Command) -> Option<&'static str> {
+    match command {
+        Command::RequestModes => Some("modes"),
+        Command::RequestCapabilities => Some("capabilities"),
+        Command::RequestState => Some("state"),
+        Command::RequestProfiles => Some("profiles"),
+        Command::RequestProfile(_) => Some("profile"),
+        _ => None,
+    }
+}
+
+//Data of query as sent by keyboard thread, in JSON form
+fn query_json(command: &Command, data: &[u8]) -> Result<Value, ClientError> {
+    match command {
+        Command::RequestModes => Ok(client_json::modes_json(&decode_modes(data)?)),
+        Command::RequestCapabilities => Ok(client_json::capabilities_json(&decode_capabilities(data)?)),
+        Command::RequestProfiles => Ok(client_json::profiles_json(&decode_profiles(data)?)),
+        _ => Ok(client_json::state_json(&decode_state(data)?)),
+    }
+}
+
+fn internal_error(e: ClientError) -> Value {
+    JsonError::new("internal", None, &e.to_string()).to_json()
+}
+
+impl JsonRequest {
+    pub fn parse(line: &str) -> Result<JsonRequest, JsonError> {
+        let value: Value = serde_json::from_str(line)
+            .map_err(|e| JsonError::new("bad_json", None, &e.to_string()))?;
+        let commands = match &value {
+            Value::Array(values) => values.iter().enumerate()
+                .map(|(index, value)| parse_command(value, index))
+                .collect::<Result<Vec<Command>, JsonError>>()?,
+            _ => vec![parse_command(&value, 0)?],
+        };
+        if commands.is_empty() {
+            return Err(JsonError::new("bad_argument", None, "request has no commands"));
+        }
+        let mut offsets = vec![];
+        let mut buffer = vec![];
+        for (index, command) in commands.iter().enumerate() {
+            offsets.push(buffer.len());
+            command.encode_to(&mut buffer)
+                .map_err(|e| JsonError::new("bad_argument", Some(index), &e.message))?;
+        }
+        Ok(JsonRequest { commands, offsets, buffer })
+    }
+
+    //Request in binary form, as passed to protocol handler
+    pub fn buffer(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    //Index of command error offset points to. Offset past the last
+    //command means keyboard failed to apply request as a whole.
+    fn command_at(&self, offset: u32) -> Option<usize> {
+        if offset as usize >= self.buffer.len() {
+            return None;
+        }
+        self.offsets.iter().rposition(|start| *start <= offset as usize)
+    }
+
+    //Result of request with data of its queries keyed by query name
+    pub fn response_json(&self, response: &ProtoResponse) -> Value {
+        match decode_payload(&response.to_payload()) {
+            Ok(_) => {},
+            Err(ClientError::Failed { code, offset, message }) |
+            Err(ClientError::BadRequest { code, offset, message }) =>
+                return JsonError::new(code.name(), self.command_at(offset), &message).to_json(),
+            Err(e) => return internal_error(e),
+        }
+        let mut result = json!({ "ok": true });
+        let queries = self.commands.iter().filter_map(|command| query_name(command).map(|name| (name, command)));
+        for ((name, command), data) in queries.zip(response.data()) {
+            match query_json(command, data) {
+                Ok(value) => result[name] = value,
+                Err(e) => return internal_error(e),
+            }
+        }
+        result
+    }
+}
+
+pub fn event_json(response: &ProtoResponse) -> Value {
+    let payload = response.to_payload();
+    match decode_payload(&payload).and_then(|(_, data)| decode_event(data)) {
+        Ok(event) => client_json::event_json(&event),
+        Err(e) => internal_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JsonRequest;
+    use crate::drivers::ms1563::MS1563;
+    use crate::drivers::transport::RecordingTransport;
+    use crate::keyboard::Keyboard;
+    use crate::protocol::proto::proto_handle_message;
+    use serde_json::Value;
+
+    fn keyboard() -> Keyboard {
+        Keyboard::new(Box::new(MS1563::with_transport(Box::new(RecordingTransport::new()))))
+    }
+
+    fn handle(keyboard: &mut Keyboard, line: &str) -> Value {
+        match JsonRequest::parse(line) {
+            Ok(request) => request.response_json(&proto_handle_message(keyboard, request.buffer())),
+            Err(e) => e.to_json(),
+        }
+    }
+
+    #[test]
+    fn commands_are_translated() {
+        let request = JsonRequest::parse(r##"[{"cmd":"set_color","color":"#00ff00"},{"cmd":"brightness","value":5},
+                                             {"cmd":"mode","mode":"breathing"},{"cmd":"power","power":false}]"##).unwrap();
+        assert_eq!(request.buffer(), [0x01, 0, 255, 0, 0x03, 5, 0x05, 0x02, 0x07, 0x00]);
+        let request = JsonRequest::parse(r##"{"cmd":"colors","colors":["#f00","0,0,1"]}"##).unwrap();
+        assert_eq!(request.buffer(), [0x00, 2, 255, 0, 0, 0, 0, 1]);
+        let request = JsonRequest::parse(r#"[{"cmd":"apply_profile","name":"ab"},{"cmd":"profiles"}]"#).unwrap();
+        assert_eq!(request.buffer(), [0x0E, 2, b'a', b'b', 0x10]);
+        let request = JsonRequest::parse(r##"{"cmd":"write_profile","name":"a","mode":"steady","brightness":7,
+                                            "speed":0,"colors":["#010203"]}"##).unwrap();
+        assert_eq!(request.buffer(), [0x12, 1, b'a', 0x01, 7, 0, 1, 1, 2, 3]);
+    }
+
+    #[test]
+    fn malformed_requests_are_rejected() {
+        let mut keyboard = keyboard();
+        assert_eq!(handle(&mut keyboard, "{\"cmd\":")["error"]["code"], "bad_json");
+        assert_eq!(handle(&mut keyboard, "[]")["error"]["code"], "bad_argument");
+        let response = handle(&mut keyboard, r#"[{"cmd":"toggle"},{"cmd":"disco"}]"#);
+        assert_eq!(response["ok"], false);
+        assert_eq!((&response["error"]["code"], &response["error"]["index"]), (&"unknown_command".into(), &1.into()));
+        let response = handle(&mut keyboard, r#"{"cmd":"brightness","value":300}"#);
+        assert_eq!(response["error"]["code"], "bad_argument");
+        let colors = format!(r#"[{{"cmd":"toggle"}},{{"cmd":"colors","colors":[{}]}}]"#, ["\"#fff\""; 256].join(","));
+        let response = handle(&mut keyboard, &colors);
+        assert_eq!((&response["error"]["code"], &response["error"]["index"]), (&"bad_argument".into(), &1.into()));
+    }
+
+    #[test]
+    fn keyboard_errors_point_to_command() {
+        let mut keyboard = keyboard();
+        let response = handle(&mut keyboard, r#"[{"cmd":"speed","value":1},{"cmd":"brightness","value":11}]"#);
+        assert_eq!(response["error"]["code"], "bad_argument");
+        assert_eq!(response["error"]["index"], 1);
+        assert!(response["error"]["message"].as_str().unwrap().contains("0..10"));
+    }
+
+    #[test]
+    fn queries_are_returned_by_name() {
+        let mut keyboard = keyboard();
+        let response = handle(&mut keyboard, r##"[{"cmd":"set_color","color":"#102030"},{"cmd":"modes"},{"cmd":"state"}]"##);
+        assert_eq!(response["ok"], true);
+        assert_eq!(response["modes"], serde_json::json!(["steady", "breathing", "colorshift"]));
+        assert_eq!(response["state"]["colors"][0], "#102030");
+        assert_eq!(handle(&mut keyboard, r#"{"cmd":"toggle"}"#).to_string(), r#"{"ok":true}"#);
+    }
+}
@@ -12,271 +12,520 @@
 use crate::util::log;
 use crate::util::color;
 use crate::keyboard;
+use crate::profiles;
+use crate::util::u8::U8Serializable;
+use crate::protocol::error::{ProtoError, ProtoErrorCode};
 use crate::protocol::response::{ProtoResponse, ProtoResponseState};
 
+use klm_proto::{Command, Mode};
+
 const TAG: &'static str = "proto";
 
-#[derive(PartialEq)]
-pub enum ProtoCmd {
-    CmdColors,
-    CmdSetColor,
-    CmdAddColor,
-    CmdBrightness,
-    CmdSpeed,
-    CmdMode,
-    CmdSyncState,
-    CmdPower,
-    CmdToggle,
-    CmdReqModesAvail,
+//Profile files are written only when whole request succeeded
+enum ProfileChange {
+    //Name and state file contents, captured when command is handled
+    Save(String, Vec<u8>),
+    Delete(String),
 }
 
-#[derive(PartialEq)]
-pub enum ProtoKeyboardMode {
-    ModeOff,
-    ModeSteady,
-    ModeBreathing,
-    ModeColorShift,
+//Handlers return reason of failure, if command can not be applied
+type ProtoResult = Result<(), ProtoError>;
+
+fn proto_to_rgb(color: &klm_proto::Color) -> color::RGB {
+    color::RGB::new(color.r, color.g, color.b)
 }
 
-impl ProtoCmd {
-    pub fn from_u8(cmd: u8) -> Option<ProtoCmd> {
-        if cmd == 0x0 {
-            Some(ProtoCmd::CmdColors)
-        } else if cmd == 0x01 {
-            Some(ProtoCmd::CmdSetColor)
-        } else if cmd == 0x02 {
-            Some(ProtoCmd::CmdAddColor)
-        } else if cmd == 0x03 {
-            Some(ProtoCmd::CmdBrightness)
-        } else if cmd == 0x04 {
-            Some(ProtoCmd::CmdSpeed)
-        } else if cmd == 0x05 {
-            Some(ProtoCmd::CmdMode)
-        } else if cmd == 0x06 {
-            Some(ProtoCmd::CmdSyncState)
-        } else if cmd == 0x07 {
-            Some(ProtoCmd::CmdPower)
-        } else if cmd == 0x08 {
-            Some(ProtoCmd::CmdToggle)
-        } else if cmd == 0x09 {
-            Some(ProtoCmd::CmdReqModesAvail)
-        } else {
-            None
-        }
+//Colors beyond driver limit are rejected when they are added, so
+//client learns which command exceeded it
+fn proto_check_color_limit(keyboard: &keyboard::Keyboard, count: usize) -> ProtoResult {
+    let max_colors = keyboard.get_capabilities().max_colors as usize;
+    if count > max_colors {
+        return Err(ProtoError::new(ProtoErrorCode::TooManyColors,
+                                   &format!("keyboard supports at most {} colors", max_colors)));
     }
+    Ok(())
 }
 
-impl ProtoKeyboardMode {
-    pub fn from_u8(byte: u8) -> Option<ProtoKeyboardMode> {
-        if byte == 0x0 {
-            Some(ProtoKeyboardMode::ModeOff)
-        } else if byte == 0x01 {
-            Some(ProtoKeyboardMode::ModeSteady)
-        } else if byte == 0x02 {
-            Some(ProtoKeyboardMode::ModeBreathing)
-        } else if byte == 0x03 {
-            Some(ProtoKeyboardMode::ModeColorShift)
-        } else {
-            None
-        }
-    }
-
-    pub fn to_state(&self) -> keyboard::KeyboardState {
-        match *self {
-            ProtoKeyboardMode::ModeOff => keyboard::KeyboardState::KeyboardOff,
-            ProtoKeyboardMode::ModeSteady => keyboard::KeyboardState::KeyboardSteady,
-            ProtoKeyboardMode::ModeBreathing => keyboard::KeyboardState::KeyboardBreathing,
-            ProtoKeyboardMode::ModeColorShift => keyboard::KeyboardState::KeyboardColorShift,
-        }
+fn proto_check_range(name: &str, value: u8, range: (u8, u8)) -> ProtoResult {
+    if value < range.0 || value > range.1 {
+        return Err(ProtoError::new(ProtoErrorCode::BadArgument,
+                                   &format!("{} {} is out of range {}..{}", name, value, range.0, range.1)));
     }
+    Ok(())
 }
 
-fn proto_handle_colors(keyboard: &mut keyboard::Keyboard, buffer: &Vec<u8>, mut buffer_ptr: usize) -> usize {
-    let n_colors = buffer[buffer_ptr];
-    buffer_ptr += 1;
-    if n_colors == 0 {
-        log::w(TAG, "ambgious request: set color array to size of 0 colors");
-        return 0;
-    }
+fn proto_handle_colors(keyboard: &mut keyboard::Keyboard, colors: &[klm_proto::Color]) -> ProtoResult {
+    proto_check_color_limit(keyboard, colors.len())?;
     keyboard.reset_colors();
-    for _color_num in 1..n_colors {
-        if buffer_ptr + 2 >= buffer.len() {
-            log::e(TAG, "bad request: expected color specification, got end of message");
-            return 0;
-        }
-        let r = buffer[buffer_ptr];
-        let g = buffer[buffer_ptr + 1];
-        let b = buffer[buffer_ptr + 2];
-        keyboard.add_color(color::RGB::new(r, g, b));
-        buffer_ptr += 3;
+    for color in colors {
+        keyboard.add_color(proto_to_rgb(color))?;
     }
-    buffer_ptr - 2
+    Ok(())
 }
 
-fn proto_handle_set_color(keyboard: &mut keyboard::Keyboard, buffer: &Vec<u8>, buffer_ptr: usize) -> usize {
-    if buffer_ptr + 2 >= buffer.len() {
-        log::e(TAG, "bad request: expected color specification, got end of message");
-        return 0;
-    }
-    let r = buffer[buffer_ptr];
-    let g = buffer[buffer_ptr + 1];
-    let b = buffer[buffer_ptr + 2];
-    keyboard.set_color(color::RGB::new(r, g, b));
-    buffer_ptr + 3
+fn proto_handle_add_color(keyboard: &mut keyboard::Keyboard, color: &klm_proto::Color) -> ProtoResult {
+    proto_check_color_limit(keyboard, keyboard.get_color_count() + 1)?;
+    keyboard.add_color(proto_to_rgb(color))?;
+    Ok(())
 }
 
-fn proto_handle_add_color(keyboard: &mut keyboard::Keyboard, buffer: &Vec<u8>, buffer_ptr: usize) -> usize {
-    if buffer_ptr + 2 >= buffer.len() {
-        log::e(TAG, "bad request: expected color specification, got end of message");
-        return 0;
-    }
-    let r = buffer[buffer_ptr];
-    let g = buffer[buffer_ptr + 1];
-    let b = buffer[buffer_ptr + 2];
-    keyboard.add_color(color::RGB::new(r, g, b));
-    buffer_ptr + 3
+fn proto_handle_set_brightness(keyboard: &mut keyboard::Keyboard, brightness: u8) -> ProtoResult {
+    proto_check_range("brightness", brightness, keyboard.get_capabilities().brightness_range)?;
+    keyboard.set_brightness(brightness)?;
+    Ok(())
 }
 
-fn proto_handle_set_brightness(keyboard: &mut keyboard::Keyboard, buffer: &Vec<u8>, buffer_ptr: usize) -> usize {
-    if buffer_ptr >= buffer.len() {
-        log::e(TAG, "bad request: expected brightness specification, got end of message");
-        return 0;
-    }
-    let b = buffer[buffer_ptr];
-    keyboard.set_brightness(b);
-    buffer_ptr + 1
+fn proto_handle_set_speed(keyboard: &mut keyboard::Keyboard, speed: u8) -> ProtoResult {
+    proto_check_range("speed", speed, keyboard.get_capabilities().speed_range)?;
+    keyboard.set_speed(speed)?;
+    Ok(())
 }
 
-fn proto_handle_set_speed(keyboard: &mut keyboard::Keyboard, buffer: &Vec<u8>, buffer_ptr: usize) -> usize {
-    if buffer_ptr >= buffer.len() {
-        log::e(TAG, "bad request: expected brightness specification, got end of message");
-        return 0;
+fn proto_check_mode(keyboard: &keyboard::Keyboard, mode: Mode) -> ProtoResult {
+    if mode != Mode::Off &&
+        !keyboard.get_color_modes().iter().any(|supported| supported.to_u8() == mode.to_u8()) {
+        return Err(ProtoError::new(ProtoErrorCode::NotSupported,
+                                   &format!("mode {} is not supported by keyboard", mode.to_u8())));
     }
-    let b = buffer[buffer_ptr];
-    keyboard.set_speed(b);
-    buffer_ptr + 1
+    Ok(())
 }
 
-fn proto_handle_set_mode(keyboard: &mut keyboard::Keyboard, buffer: &Vec<u8>, buffer_ptr: usize) -> usize {
-    if buffer_ptr >= buffer.len() {
-        log::e(TAG, "bad request: expected mode specification, got end of message");
-        return 0;
-    }
-    let b = buffer[buffer_ptr];
-    log::d(TAG, &format!("set_mode: {}", b));
-    if let Some(mode) = ProtoKeyboardMode::from_u8(b) {
-        keyboard.set_state(mode.to_state());
-    } else {
-        log::e(TAG, &format!("bad request: bad mode specifier {} at {}", b, buffer_ptr));
-        return 0;
-    }
-    buffer_ptr + 1
+fn proto_handle_set_mode(keyboard: &mut keyboard::Keyboard, mode: Mode) -> ProtoResult {
+    log::d(TAG, &format!("set_mode: {:?}", mode));
+    proto_check_mode(keyboard, mode)?;
+    keyboard.set_state(keyboard::KeyboardState::from(mode))?;
+    Ok(())
 }
 
-fn proto_handle_set_lock(keyboard: &mut keyboard::Keyboard, buffer: &Vec<u8>, buffer_ptr: usize) -> usize {
-    if buffer_ptr >= buffer.len() {
-        log::e(TAG, "bad request: expected lock specification, got end of message");
-        return 0;
-    }
-    let b = buffer[buffer_ptr];
-    if b == 0 {
-        keyboard.unlock_sync();
-    } else {
-        keyboard.lock_sync();
-    }
-    buffer_ptr + 1
+fn proto_handle_save_profile(keyboard: &keyboard::Keyboard, name: &str,
+                             changes: &mut Vec<ProfileChange>) -> ProtoResult {
+    profiles::profile_path(keyboard.get_profiles_dir(), name)?;
+    changes.push(ProfileChange::Save(name.to_string(), keyboard.encode_state_file()?));
+    Ok(())
 }
 
+//Settings are checked as if they were applied, but keyboard is not
+//touched. Power is stored as off, as profiles do not apply it.
+fn proto_handle_write_profile(keyboard: &keyboard::Keyboard, name: &str, mode: Mode, brightness: u8, speed: u8,
+                              colors: &[klm_proto::Color], changes: &mut Vec<ProfileChange>) -> ProtoResult {
+    profiles::profile_path(keyboard.get_profiles_dir(), name)?;
+    proto_check_mode(keyboard, mode)?;
+    let capabilities = keyboard.get_capabilities();
+    proto_check_range("brightness", brightness, capabilities.brightness_range)?;
+    proto_check_range("speed", speed, capabilities.speed_range)?;
+    proto_check_color_limit(keyboard, colors.len())?;
+    let snapshot = keyboard::KeyboardSnapshot::new(keyboard::KeyboardState::from(mode),
+                                                   colors.iter().map(proto_to_rgb).collect(),
+                                                   brightness, speed, false);
+    changes.push(ProfileChange::Save(name.to_string(), snapshot.encode_state_file()?));
+    Ok(())
+}
 
-fn proto_handle_set_power(keyboard: &mut keyboard::Keyboard, buffer: &Vec<u8>, buffer_ptr: usize) -> usize {
-    if buffer_ptr >= buffer.len() {
-        log::e(TAG, "bad request: expected power specification, got end of message");
-    }
-    let b = buffer[buffer_ptr];
-    if b == 0 {
-        keyboard.set_power(false);
-    } else {
-        keyboard.set_power(true);
+fn proto_handle_apply_profile(keyboard: &mut keyboard::Keyboard, name: &str) -> ProtoResult {
+    log::d(TAG, &format!("apply_profile: {}", name));
+    let buffer = profiles::read(keyboard.get_profiles_dir(), name)?;
+    keyboard.apply_profile(&buffer).map_err(|e| {
+        ProtoError::new(ProtoErrorCode::Internal, &format!("profile '{}' is corrupted: {}", name, e))
+    })
+}
+
+fn proto_handle_delete_profile(keyboard: &keyboard::Keyboard, name: &str,
+                               changes: &mut Vec<ProfileChange>) -> ProtoResult {
+    let saved = changes.iter().any(|change| matches!(change, ProfileChange::Save(saved, _) if saved == name));
+    if !saved && !profiles::exists(keyboard.get_profiles_dir(), name)? {
+        return Err(ProtoError::new(ProtoErrorCode::BadArgument, &format!("there is no profile '{}'", name)));
     }
-    buffer_ptr + 1
+    changes.push(ProfileChange::Delete(name.to_string()));
+    Ok(())
 }
 
+fn proto_commit_profiles(keyboard: &keyboard::Keyboard, changes: &[ProfileChange]) -> ProtoResult {
+    let dir = keyboard.get_profiles_dir();
+    for change in changes {
+        match change {
+            ProfileChange::Save(name, buffer) => profiles::write(dir, name, buffer)?,
+            ProfileChange::Delete(name) => profiles::delete(dir, name)?,
+        }
+    }
+    Ok(())
+}
 
-fn proto_handle_toggle_power(keyboard: &mut keyboard::Keyboard, buffer: &Vec<u8>,
-                             buffer_ptr: usize) -> usize {
-    if buffer_ptr > buffer.len() {
-        log::e(TAG, "bad request: buffer_ptr is out of range");
-        return 0;
+fn proto_handle_command(keyboard: &mut keyboard::Keyboard, command: &Command,
+                        response: &mut ProtoResponse, changes: &mut Vec<ProfileChange>) -> ProtoResult {
+    log::d(TAG, &format!("cmd={:?}", command));
+    match command {
+        Command::Colors(colors) => proto_handle_colors(keyboard, colors),
+        Command::SetColor(color) => Ok(keyboard.set_color(proto_to_rgb(color))?),
+        Command::AddColor(color) => proto_handle_add_color(keyboard, color),
+        Command::Brightness(brightness) => proto_handle_set_brightness(keyboard, *brightness),
+        Command::Speed(speed) => proto_handle_set_speed(keyboard, *speed),
+        Command::Mode(mode) => proto_handle_set_mode(keyboard, *mode),
+        //Sync is locked for whole request, so that it is applied at once.
+        //Command is accepted for old clients, but can not unlock it.
+        Command::SyncState(_) => {
+            log::w(TAG, "Ignoring obsolete sync lock command, requests are applied as a whole");
+            Ok(())
+        },
+        Command::Power(power) => {
+            keyboard.set_power(*power);
+            Ok(())
+        },
+        Command::Toggle => Ok(keyboard.toggle_power()?),
+        Command::RequestModes => {
+            response.add_response(Box::new(keyboard.get_color_modes()));
+            Ok(())
+        },
+        Command::RequestCapabilities => {
+            response.add_response(Box::new(keyboard.get_capabilities()));
+            Ok(())
+        },
+        Command::RequestState => {
+            response.add_response(Box::new(keyboard.get_status()));
+            Ok(())
+        },
+        Command::Subscribe => {
+            response.subscribed = true;
+            Ok(())
+        },
+        Command::SaveProfile(name) => proto_handle_save_profile(keyboard, name, changes),
+        Command::ApplyProfile(name) => proto_handle_apply_profile(keyboard, name),
+        Command::DeleteProfile(name) => proto_handle_delete_profile(keyboard, name, changes),
+        Command::RequestProfiles => {
+            response.add_response(Box::new(profiles::list(keyboard.get_profiles_dir())?));
+            Ok(())
+        },
+        Command::RequestProfile(name) => {
+            response.add_response(Box::new(profiles::status(keyboard.get_profiles_dir(), name)?));
+            Ok(())
+        },
+        Command::WriteProfile { name, mode, brightness, speed, colors } => {
+            proto_handle_write_profile(keyboard, name, *mode, *brightness, *speed, colors, changes)
+        },
     }
-    keyboard.toggle_power();
-    buffer_ptr
 }
 
-fn proto_handle_request_modes(keyboard: &keyboard::Keyboard, buffer: &Vec<u8>,
-                              buffer_ptr: usize, response: &mut ProtoResponse) -> usize {
-    if buffer_ptr > buffer.len() {
-        log::e(TAG, "bad request: buffer_ptr is out of range");
-        return 0;
+//Whole request is decoded before keyboard is touched. Commands are
+//applied with sync locked, so driver is not used until all of them
+//are checked against keyboard capabilities.
+fn proto_handle_transaction(keyboard: &mut keyboard::Keyboard, buffer: &[u8],
+                            response: &mut ProtoResponse, changes: &mut Vec<ProfileChange>) -> ProtoResult {
+    let mut commands = vec![];
+    let mut buffer_ptr = 0;
+    while buffer_ptr < buffer.len() {
+        let (command, next_ptr) = klm_proto::decode_command(buffer, buffer_ptr)?;
+        commands.push((buffer_ptr, command));
+        buffer_ptr = next_ptr;
     }
-    let modes = keyboard.get_color_modes();
-    response.add_response(Box::new(modes));
-    buffer_ptr
+    for (offset, command) in commands.iter() {
+        proto_handle_command(keyboard, command, response, changes).map_err(|e| e.at(*offset))?;
+    }
+    Ok(())
 }
 
-pub fn proto_handle_message(keyboard: &mut keyboard::Keyboard, buffer: &Vec<u8>) -> ProtoResponse {
+//Request is applied as a whole or not at all: on any failure keyboard
+//is rolled back to state it had before request. Profiles are written
+//after keyboard was synced, as files can not be rolled back.
+pub fn proto_handle_message(keyboard: &mut keyboard::Keyboard, buffer: &[u8]) -> ProtoResponse {
     let mut proto_response = ProtoResponse::from_state(ProtoResponseState::ResultError);
-    let mut buffer_ptr = 0;
-    if buffer.len() == 0 {
+    if buffer.is_empty() {
         log::e(TAG, "bad reqeust: empty buffer. This is a bug: must be handled earlier.");
-        return ProtoResponse::from_state(ProtoResponseState::ResultError);
+        return ProtoResponse::from_error(ProtoError::new(ProtoErrorCode::Internal, "empty request"));
     }
+    let snapshot = keyboard.snapshot();
     keyboard.lock_sync();
-    //log::d(TAG, &format!("Received buffer size of {"));
-    while buffer_ptr < buffer.len() {
-        let cmd_byte = buffer[buffer_ptr];
-        buffer_ptr += 1;
-        let cmd_wrapped = ProtoCmd::from_u8(cmd_byte);
-        if cmd_wrapped == None {
-            log::e(TAG, &format!("bad request: unknown command {} at pos {}", cmd_byte, buffer_ptr - 1));
-            return ProtoResponse::from_state(ProtoResponseState::ResultBadRequest);
-        }
-        let cmd = cmd_wrapped.unwrap();
-        log::d(TAG, &format!("cmd={}", cmd_byte));
-        if cmd == ProtoCmd::CmdColors {
-            buffer_ptr = proto_handle_colors(keyboard, buffer, buffer_ptr);
-        } else if cmd == ProtoCmd::CmdSetColor {
-            buffer_ptr = proto_handle_set_color(keyboard, buffer, buffer_ptr);
-        } else if cmd == ProtoCmd::CmdAddColor {
-            buffer_ptr = proto_handle_add_color(keyboard, buffer, buffer_ptr);
-        } else if cmd == ProtoCmd::CmdBrightness {
-            buffer_ptr = proto_handle_set_brightness(keyboard, buffer, buffer_ptr);
-        } else if cmd == ProtoCmd::CmdSpeed {
-            buffer_ptr = proto_handle_set_speed(keyboard, buffer, buffer_ptr);
-        } else if cmd == ProtoCmd::CmdMode {
-            buffer_ptr = proto_handle_set_mode(keyboard, buffer, buffer_ptr);
-        } else if cmd == ProtoCmd::CmdSyncState {
-            buffer_ptr = proto_handle_set_lock(keyboard, buffer, buffer_ptr);
-        } else if cmd == ProtoCmd::CmdPower {
-            buffer_ptr = proto_handle_set_power(keyboard, buffer, buffer_ptr);
-        } else if cmd == ProtoCmd::CmdToggle {
-            buffer_ptr = proto_handle_toggle_power(keyboard, buffer, buffer_ptr);
-        } else if cmd == ProtoCmd::CmdReqModesAvail {
-            buffer_ptr = proto_handle_request_modes(keyboard, buffer, buffer_ptr,
-                                                    &mut proto_response);
-        }
-        if buffer_ptr == 0 {
-            log::e(TAG, "proto_handle_message: parsing message failed.");
-            return ProtoResponse::from_state(ProtoResponseState::ResultBadRequest);
+    let mut changes = vec![];
+    let mut result = proto_handle_transaction(keyboard, buffer, &mut proto_response, &mut changes);
+    keyboard.unlock_sync();
+    if result.is_ok() {
+        result = keyboard.sync().map_err(|e| {
+            log::e(TAG, &format!("Unable to apply keyboard state: {}", e));
+            ProtoError::from(e).at(buffer.len())
+        });
+    }
+    if result.is_ok() {
+        result = proto_commit_profiles(keyboard, &changes).map_err(|e| {
+            log::e(TAG, &format!("Unable to store profiles: {}", e));
+            e.at(buffer.len())
+        });
+        if result.is_err() {
+            //Device already received new state, so it is synced back
+            keyboard.restore(snapshot.clone());
+            if let Err(e) = keyboard.sync() {
+                log::e(TAG, &format!("Unable to restore keyboard state: {}", e));
+            }
         }
     }
+    if let Err(e) = result {
+        log::e(TAG, &format!("bad request: {}, rolling back", e));
+        keyboard.restore(snapshot);
+        return ProtoResponse::from_error(e);
+    }
     if proto_response.state != ProtoResponseState::ResultData {
         log::d(TAG, "Response state not data, setting to state ok");
-        proto_response = ProtoResponse::from_state(ProtoResponseState::ResultOk);
+        proto_response.state = ProtoResponseState::ResultOk;
+    }
+    //Queries and requests setting what is already set do not touch disk
+    if keyboard.snapshot() != snapshot {
+        if let Err(e) = keyboard.save_state() {
+            log::w(TAG, &format!("Unable to save keyboard state: {}", e));
+        }
     }
-    keyboard.save_state();
-    keyboard.unlock_sync();
-    keyboard.sync();
     proto_response
 }
 
+#[cfg(test)]
+mod tests {
+    use super::proto_handle_message;
+    use crate::drivers::ms1563::MS1563;
+    use crate::drivers::transport::RecordingTransport;
+    use crate::keyboard::Keyboard;
+    use crate::util::u8::U8VecSerializable;
+
+    fn keyboard() -> (Keyboard, RecordingTransport) {
+        let transport = RecordingTransport::new();
+        let driver = Box::new(MS1563::with_transport(Box::new(transport.clone())));
+        (Keyboard::new(driver), transport)
+    }
+
+    //Error details as sent to framed clients: status, code, offset, message
+    fn error_details(buffer: &[u8], keyboard: &mut Keyboard) -> (u8, u8, u32, String) {
+        let payload = proto_handle_message(keyboard, buffer).to_payload();
+        let offset = u32::from_be_bytes([payload[2], payload[3], payload[4], payload[5]]);
+        let length = u16::from_be_bytes([payload[6], payload[7]]) as usize;
+        assert_eq!(payload.len(), 8 + length);
+        (payload[0], payload[1], offset, String::from_utf8(payload[8..].to_vec()).unwrap())
+    }
+
+    #[test]
+    fn valid_request_is_ok() {
+        let (mut keyboard, _) = keyboard();
+        let response = proto_handle_message(&mut keyboard, &[0x01, 1, 2, 3, 0x03, 5]);
+        assert_eq!(response.to_u8_vec(), vec![0x0]);
+        assert_eq!(response.to_payload(), vec![0x0]);
+    }
+
+    #[test]
+    fn color_vector_is_set_completely() {
+        let (mut keyboard, _) = keyboard();
+        let response = proto_handle_message(&mut keyboard, &[0x00, 2, 1, 2, 3, 4, 5, 6, 0x0b]);
+        assert_eq!(response.to_payload()[..], [0x3, 0x0, 0x0, 0x0, 0x0, 0x1, 0x2, 1, 2, 3, 4, 5, 6]);
+        let (state, code, offset, _) = error_details(&[0x00, 8, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17,
+                                                       18, 19, 20, 21, 22, 23, 24], &mut keyboard);
+        assert_eq!((state, code, offset), (0x2, 0x04, 0));
+    }
+
+    #[test]
+    fn unknown_command_is_reported_with_offset() {
+        let (mut keyboard, _) = keyboard();
+        let (state, code, offset, message) = error_details(&[0x03, 5, 0x42], &mut keyboard);
+        assert_eq!((state, code, offset), (0x2, 0x01, 2));
+        assert!(message.contains("66"));
+    }
+
+    #[test]
+    fn truncated_command_is_reported() {
+        let (mut keyboard, _) = keyboard();
+        let (state, code, offset, _) = error_details(&[0x08, 0x01, 1, 2], &mut keyboard);
+        assert_eq!((state, code, offset), (0x2, 0x02, 1));
+        let (state, code, offset, _) = error_details(&[0x07], &mut keyboard);
+        assert_eq!((state, code, offset), (0x2, 0x02, 0));
+    }
+
+    //Inputs which crashed earlier versions of the parser
+    #[test]
+    fn crashing_inputs_are_rejected() {
+        let (mut keyboard, _) = keyboard();
+        for buffer in [&[0x07][..], &[0x00], &[0x00, 1], &[0x00, 2, 1, 2, 3, 4], &[0x05], &[0x03, 1, 0x06]] {
+            let response = proto_handle_message(&mut keyboard, buffer);
+            assert_eq!(response.to_u8_vec(), vec![0x2]);
+        }
+        assert!(!keyboard.get_status().sync_locked);
+    }
+
+    #[test]
+    fn bad_mode_is_reported() {
+        let (mut keyboard, _) = keyboard();
+        let (state, code, offset, _) = error_details(&[0x01, 1, 2, 3, 0x05, 9], &mut keyboard);
+        assert_eq!((state, code, offset), (0x2, 0x03, 4));
+    }
+
+    #[test]
+    fn too_many_colors_are_reported() {
+        let (mut keyboard, _) = keyboard();
+        let mut buffer = vec![0x01, 0, 0, 0];
+        for i in 0..7 {
+            buffer.extend([0x02, i, i, i]);
+        }
+        let (state, code, offset, _) = error_details(&buffer, &mut keyboard);
+        assert_eq!((state, code, offset), (0x2, 0x04, 28));
+    }
+
+    #[test]
+    fn device_failure_is_error_not_bad_request() {
+        let (mut keyboard, transport) = keyboard();
+        transport.set_failing(true);
+        let (state, code, offset, _) = error_details(&[0x03, 5], &mut keyboard);
+        assert_eq!((state, code, offset), (0x1, 0x06, 2));
+        assert_eq!(proto_handle_message(&mut keyboard, &[0x03, 5]).to_u8_vec(), vec![0x1]);
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        let (mut keyboard, _) = keyboard();
+        let (state, code, offset, message) = error_details(&[0x03, 11], &mut keyboard);
+        assert_eq!((state, code, offset), (0x2, 0x03, 0));
+        assert!(message.contains("0..10"));
+        let (state, code, offset, _) = error_details(&[0x03, 1, 0x04, 3], &mut keyboard);
+        assert_eq!((state, code, offset), (0x2, 0x03, 2));
+    }
+
+    #[test]
+    fn failed_request_is_rolled_back() {
+        let (mut keyboard, transport) = keyboard();
+        let status = keyboard.get_status().to_u8_vec();
+        let response = proto_handle_message(&mut keyboard, &[0x07, 1, 0x01, 1, 2, 3, 0x05, 1, 0x05, 9]);
+        assert_eq!(response.to_u8_vec(), vec![0x2]);
+        let mut expected = status.clone();
+        expected[4] = 0;
+        assert_eq!(keyboard.get_status().to_u8_vec(), expected);
+        assert!(transport.feature_reports().is_empty());
+    }
+
+    #[test]
+    fn sync_is_unlocked_after_failed_request() {
+        let (mut keyboard, transport) = keyboard();
+        proto_handle_message(&mut keyboard, &[0x06, 1, 0x42]);
+        assert!(!keyboard.get_status().sync_locked);
+        proto_handle_message(&mut keyboard, &[0x07, 1, 0x01, 1, 2, 3, 0x05, 1]);
+        assert_eq!(transport.feature_reports().len(), 1);
+    }
+
+    #[test]
+    fn sync_lock_is_ignored() {
+        let (mut keyboard, transport) = keyboard();
+        assert_eq!(proto_handle_message(&mut keyboard, &[0x03, 7, 0x06, 0, 0x04, 1]).to_u8_vec(), vec![0x0]);
+        let status = keyboard.get_status();
+        assert_eq!((status.brightness, status.speed), (7, 1));
+        //Whole request is still sent at once
+        assert_eq!(transport.feature_reports().len(), 1);
+    }
+
+    #[test]
+    fn toggle_is_applied_with_rest_of_request() {
+        let (mut keyboard, transport) = keyboard();
+        let response = proto_handle_message(&mut keyboard, &[0x01, 1, 2, 3, 0x05, 1, 0x08, 0x05, 7]);
+        assert_eq!(response.to_u8_vec(), vec![0x2]);
+        assert!(transport.feature_reports().is_empty());
+        assert!(!keyboard.get_status().power);
+    }
+
+    #[test]
+    fn state_is_saved_only_when_changed() {
+        let (mut keyboard, _) = keyboard();
+        let path = std::env::temp_dir().join(format!("klmd-test-{}-proto-state", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        keyboard.set_state_file(&path);
+        assert_eq!(proto_handle_message(&mut keyboard, &[0x0B]).to_payload()[0], 0x3);
+        assert!(!path.exists());
+        assert_eq!(proto_handle_message(&mut keyboard, &[0x03, 7]).to_u8_vec(), vec![0x0]);
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(proto_handle_message(&mut keyboard, &[0x03, 7]).to_u8_vec(), vec![0x0]);
+        assert!(!path.exists());
+    }
+
+    //Keyboard with empty profiles directory of its own
+    fn keyboard_with_profiles(name: &str) -> (Keyboard, RecordingTransport, std::path::PathBuf) {
+        let (mut keyboard, transport) = keyboard();
+        let dir = std::env::temp_dir().join(format!("klmd-test-{}-proto-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        keyboard.set_profiles_dir(&dir);
+        (keyboard, transport, dir)
+    }
+
+    fn save_work_profile(keyboard: &mut Keyboard) {
+        let save = [&[0x01, 1, 2, 3, 0x03, 7, 0x07, 1, 0x0D, 4][..], b"work"].concat();
+        assert_eq!(proto_handle_message(keyboard, &save).to_u8_vec(), vec![0x0]);
+    }
+
+    #[test]
+    fn applied_profile_keeps_power() {
+        let (mut keyboard, transport, dir) = keyboard_with_profiles("apply");
+        save_work_profile(&mut keyboard);
+        proto_handle_message(&mut keyboard, &[0x01, 9, 9, 9, 0x03, 1, 0x07, 0]);
+        assert_eq!(proto_handle_message(&mut keyboard, &[&[0x0E, 4][..], b"work"].concat()).to_u8_vec(), vec![0x0]);
+        let status = keyboard.get_status();
+        assert_eq!((status.colors[0].to_u8_vec(), status.brightness, status.power), (vec![1, 2, 3], 7, false));
+        assert_eq!(transport.feature_reports().len(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stored_profiles_are_listed_and_read() {
+        let (mut keyboard, _, dir) = keyboard_with_profiles("list");
+        save_work_profile(&mut keyboard);
+        assert_eq!(proto_handle_message(&mut keyboard, &[0x10]).to_payload(), [&[0x3, 1, 4][..], b"work"].concat());
+        let response = proto_handle_message(&mut keyboard, &[&[0x11, 4][..], b"work"].concat());
+        assert_eq!(response.to_payload(), vec![0x3, 0x0, 1, 7, 0, 0, 1, 1, 2, 3]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_request_does_not_save_profile() {
+        let (mut keyboard, _, dir) = keyboard_with_profiles("failed");
+        let save = [&[0x0D, 5][..], b"night", &[0x05, 9]].concat();
+        assert_eq!(proto_handle_message(&mut keyboard, &save).to_u8_vec(), vec![0x2]);
+        assert!(!dir.join("night.state").exists());
+    }
 
+    #[test]
+    fn missing_or_badly_named_profiles_are_bad_arguments() {
+        let (mut keyboard, _, dir) = keyboard_with_profiles("missing");
+        let (state, code, offset, _) = error_details(&[&[0x0E, 5][..], b"night"].concat(), &mut keyboard);
+        assert_eq!((state, code, offset), (0x2, 0x03, 0));
+        let (state, code, _, _) = error_details(&[&[0x0D, 3][..], b"../"].concat(), &mut keyboard);
+        assert_eq!((state, code), (0x2, 0x03));
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn deleted_profile_is_not_listed() {
+        let (mut keyboard, _, dir) = keyboard_with_profiles("delete");
+        save_work_profile(&mut keyboard);
+        assert_eq!(proto_handle_message(&mut keyboard, &[&[0x0F, 4][..], b"work"].concat()).to_u8_vec(), vec![0x0]);
+        assert_eq!(proto_handle_message(&mut keyboard, &[0x10]).to_payload(), vec![0x3, 0]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn profile_is_written_without_touching_keyboard() {
+        let (mut keyboard, transport, dir) = keyboard_with_profiles("write");
+        let write = [&[0x12, 5][..], b"night", &[0x02, 3, 1, 1, 9, 8, 7]].concat();
+        assert_eq!(proto_handle_message(&mut keyboard, &write).to_u8_vec(), vec![0x0]);
+        assert!(transport.feature_reports().is_empty());
+        assert_eq!(keyboard.get_status().brightness, 0);
+        let response = proto_handle_message(&mut keyboard, &[&[0x11, 5][..], b"night"].concat());
+        assert_eq!(response.to_payload(), vec![0x3, 0x2, 0, 3, 1, 0, 1, 9, 8, 7]);
+        let (state, code, _, message) = error_details(&[&[0x12, 1][..], b"a", &[0x01, 11, 1, 1, 0, 0, 0]].concat(),
+                                                      &mut keyboard);
+        assert_eq!((state, code), (0x2, 0x03));
+        assert!(message.contains("brightness"));
+        assert!(!dir.join("a.state").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn device_failure_rolls_back_state() {
+        let (mut keyboard, transport) = keyboard();
+        proto_handle_message(&mut keyboard, &[0x07, 1, 0x01, 1, 2, 3, 0x05, 1]);
+        transport.set_failing(true);
+        let response = proto_handle_message(&mut keyboard, &[0x01, 4, 5, 6]);
+        assert_eq!(response.to_u8_vec(), vec![0x1]);
+        assert_eq!(keyboard.get_status().colors[0].to_u8_vec(), vec![1, 2, 3]);
+        transport.set_failing(false);
+        proto_handle_message(&mut keyboard, &[0x0b]);
+        let reports = transport.feature_reports();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[1][6..9], [1, 2, 3]);
+    }
+}
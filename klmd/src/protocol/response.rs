@@ -1,3 +1,5 @@
+use crate::protocol::error::ProtoError;
+use crate::protocol::event::ProtoEvent;
 use crate::protocol::response::ProtoResponseState::ResultData;
 use crate::util::log;
 use crate::util::u8::{U8Serializable, U8VecSerializable};
@@ -10,6 +12,7 @@ pub enum ProtoResponseState {
     ResultError,
     ResultBadRequest,
     ResultData,
+    ResultEvent,
 }
 
 impl U8Serializable for ProtoResponseState {
@@ -19,14 +22,20 @@ impl U8Serializable for ProtoResponseState {
             ProtoResponseState::ResultError => 0x1,
             ProtoResponseState::ResultBadRequest => 0x2,
             ProtoResponseState::ResultData => 0x3,
+            ProtoResponseState::ResultEvent => 0x4,
         }
     }
 }
 
 pub struct ProtoResponse {
-    result: Vec<u8>,
+    //Data of each query in request, in order of queries
+    result: Vec<Vec<u8>>,
     state_only: bool,
     pub(crate) state: ProtoResponseState,
+    //Connection turns into event stream after this response
+    pub(crate) subscribed: bool,
+    //Details of failure, only sent with framed protocol versions
+    error: Option<ProtoError>,
 }
 
 impl U8VecSerializable for ProtoResponse {
@@ -34,29 +43,70 @@ impl U8VecSerializable for ProtoResponse {
         if self.state_only {
             return self.state.to_u8_vec();
         }
-        let state = ProtoResponseState::ResultData.to_u8();
-        let size = self.result.len();
+        let state = self.state.to_u8();
+        let result = self.result.concat();
+        let size = result.len();
         if size > 255 {
-            log::panic(TAG, "Too big response");
+            log::e(TAG, &format!("Response of {} bytes does not fit into message, reporting error", size));
+            return ProtoResponseState::ResultError.to_u8_vec();
         }
         let size_u8: u8 = size as u8;
         let mut response: Vec<u8> = vec![state, size_u8];
-        response.extend(self.result.clone());
+        response.extend(result);
         response
     }
 }
 
 impl ProtoResponse {
+    //Response body for framed protocol versions, where frame
+    //header carries length and data size is not limited to a byte
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = vec![self.state.to_u8()];
+        if !self.state_only {
+            payload.extend(self.result.concat());
+        }
+        if let Some(error) = &self.error {
+            payload.extend(error.to_u8_vec());
+        }
+        payload
+    }
+
     pub fn add_response(&mut self, response: Box<dyn U8VecSerializable>) {
         self.state = ResultData;
         self.state_only = false;
-        self.result.extend(response.to_u8_vec());
+        self.result.push(response.to_u8_vec());
+    }
+
+    pub(crate) fn data(&self) -> &[Vec<u8>] {
+        &self.result
     }
     pub(crate) fn from_state(state: ProtoResponseState) -> ProtoResponse {
         ProtoResponse {
             result: vec![],
             state_only: true,
             state,
+            subscribed: false,
+            error: None,
+        }
+    }
+
+    pub(crate) fn from_error(error: ProtoError) -> ProtoResponse {
+        ProtoResponse {
+            result: vec![],
+            state_only: true,
+            state: error.code.to_state(),
+            subscribed: false,
+            error: Some(error),
+        }
+    }
+
+    pub(crate) fn from_event(event: &ProtoEvent) -> ProtoResponse {
+        ProtoResponse {
+            result: vec![event.to_u8_vec()],
+            state_only: false,
+            state: ProtoResponseState::ResultEvent,
+            subscribed: false,
+            error: None,
         }
     }
 }
\ No newline at end of file
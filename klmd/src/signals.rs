@@ -0,0 +1,75 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::error::KlmResult;
+use crate::listener::Event;
+use crate::util::log;
+
+use std::sync::mpsc;
+use std::thread;
+use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+const TAG: &'static str = "signals";
+
+fn event_for_signal(signal: i32) -> Option<Event> {
+    if signal == SIGTERM || signal == SIGINT {
+        Some(Event::Terminate)
+    } else if signal == SIGHUP {
+        Some(Event::Reload)
+    } else {
+        None
+    }
+}
+
+//Signals are not handled in signal context: they are forwarded
+//as events to thread owning keyboard, so it can finish current
+//request before saving state or reloading
+pub fn forward_signals(events: mpsc::Sender<Event>) -> KlmResult<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            log::i(TAG, &format!("Received signal {}", signal));
+            if let Some(event) = event_for_signal(signal) {
+                if events.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{event_for_signal, forward_signals};
+    use crate::listener::Event;
+    use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn signals_map_to_events() {
+        assert!(matches!(event_for_signal(SIGTERM), Some(Event::Terminate)));
+        assert!(matches!(event_for_signal(SIGINT), Some(Event::Terminate)));
+        assert!(matches!(event_for_signal(SIGHUP), Some(Event::Reload)));
+        assert!(event_for_signal(SIGUSR1).is_none());
+    }
+
+    #[test]
+    fn raised_signal_is_forwarded() {
+        let (events_sender, events) = mpsc::channel();
+        forward_signals(events_sender).unwrap();
+        signal_hook::low_level::raise(SIGHUP).unwrap();
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(event, Event::Reload));
+    }
+}
@@ -0,0 +1,158 @@
+/**
+ * This file is part of KLMd project.
+ *
+ *  Copyright 2023 by Polar <toddot@protonmail.com>
+ *
+ *  Licensed under GNU General Public License 3.0 or later.
+ *  Some rights reserved. See COPYING, AUTHORS.
+ *
+ * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+ */
+
+use crate::error::{KlmError, KlmResult};
+use crate::util::log;
+
+use std::env;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixListener};
+use std::time::Duration;
+
+const TAG: &'static str = "systemd";
+//First file descriptor passed by systemd, see sd_listen_fds(3)
+const LISTEN_FDS_START: RawFd = 3;
+
+//Returns descriptor passed by socket activation, if klmd was
+//started by systemd for this process
+fn listen_fd(listen_pid: Option<String>, listen_fds: Option<String>, pid: u32) -> KlmResult<Option<RawFd>> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(None),
+    };
+    if listen_pid.parse::<u32>().ok() != Some(pid) {
+        log::d(TAG, "LISTEN_FDS is set for another process, ignoring it");
+        return Ok(None);
+    }
+    let count = listen_fds.parse::<i32>()
+        .map_err(|_| KlmError::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                                                      format!("bad LISTEN_FDS value: {}", listen_fds))))?;
+    if count == 0 {
+        return Ok(None);
+    }
+    if count > 1 {
+        log::w(TAG, &format!("systemd passed {} sockets, using only the first one", count));
+    }
+    Ok(Some(LISTEN_FDS_START))
+}
+
+//Takes listening socket passed by systemd. Variables are removed,
+//so they are not inherited by child processes.
+pub fn activated_listener() -> KlmResult<Option<UnixListener>> {
+    let fd = listen_fd(env::var("LISTEN_PID").ok(), env::var("LISTEN_FDS").ok(), std::process::id())?;
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    match fd {
+        Some(fd) => {
+            log::i(TAG, "Using socket passed by systemd");
+            Ok(Some(unsafe { UnixListener::from_raw_fd(fd) }))
+        },
+        None => Ok(None),
+    }
+}
+
+fn notify_address(path: &str) -> KlmResult<SocketAddr> {
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        Ok(SocketAddr::from_abstract_name(name)?)
+    } else {
+        Ok(SocketAddr::from_pathname(path)?)
+    }
+}
+
+fn notify_socket(path: &str, state: &str) -> KlmResult<()> {
+    let sock = UnixDatagram::unbound()?;
+    sock.send_to_addr(state.as_bytes(), &notify_address(path)?)?;
+    Ok(())
+}
+
+//Sends state to service manager, see sd_notify(3). Does nothing
+//if klmd was not started by systemd.
+pub fn notify(state: &str) {
+    if let Ok(path) = env::var("NOTIFY_SOCKET") {
+        if let Err(e) = notify_socket(&path, state) {
+            log::w(TAG, &format!("Unable to notify systemd: {}", e));
+        }
+    }
+}
+
+fn watchdog_interval(watchdog_usec: Option<String>, watchdog_pid: Option<String>, pid: u32) -> Option<Duration> {
+    if let Some(watchdog_pid) = watchdog_pid {
+        if watchdog_pid.parse::<u32>().ok() != Some(pid) {
+            return None;
+        }
+    }
+    let usec = watchdog_usec?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    //Ping twice per timeout, as recommended by sd_watchdog_enabled(3)
+    Some(Duration::from_micros(usec / 2))
+}
+
+//Returns how often WATCHDOG=1 should be sent, if watchdog is enabled
+pub fn watchdog() -> Option<Duration> {
+    watchdog_interval(env::var("WATCHDOG_USEC").ok(), env::var("WATCHDOG_PID").ok(), std::process::id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{listen_fd, notify_socket, watchdog_interval, LISTEN_FDS_START};
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    fn s(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn listen_fd_requires_matching_pid() {
+        assert_eq!(listen_fd(s("42"), s("1"), 42).unwrap(), Some(LISTEN_FDS_START));
+        assert_eq!(listen_fd(s("43"), s("1"), 42).unwrap(), None);
+        assert_eq!(listen_fd(None, s("1"), 42).unwrap(), None);
+        assert_eq!(listen_fd(s("42"), s("0"), 42).unwrap(), None);
+        assert!(listen_fd(s("42"), s("many"), 42).is_err());
+    }
+
+    #[test]
+    fn watchdog_pings_twice_per_timeout() {
+        assert_eq!(watchdog_interval(s("10000000"), None, 42), Some(Duration::from_secs(5)));
+        assert_eq!(watchdog_interval(s("10000000"), s("42"), 42), Some(Duration::from_secs(5)));
+        assert_eq!(watchdog_interval(s("10000000"), s("43"), 42), None);
+        assert_eq!(watchdog_interval(s("0"), None, 42), None);
+        assert_eq!(watchdog_interval(None, None, 42), None);
+    }
+
+    #[test]
+    fn notification_is_delivered_to_socket() {
+        let path = std::env::temp_dir().join(format!("klmd-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+        notify_socket(path.to_str().unwrap(), "READY=1\nSTATUS=Serving MS1563").unwrap();
+        let mut buffer = [0u8; 64];
+        let size = manager.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"READY=1\nSTATUS=Serving MS1563");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn notification_is_delivered_to_abstract_socket() {
+        use std::os::linux::net::SocketAddrExt;
+        let name = format!("klmd-notify-{}", std::process::id());
+        let address = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let manager = UnixDatagram::bind_addr(&address).unwrap();
+        notify_socket(&format!("@{}", name), "WATCHDOG=1").unwrap();
+        let mut buffer = [0u8; 16];
+        let size = manager.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"WATCHDOG=1");
+    }
+}
@@ -10,5 +10,6 @@
  */
 
 pub mod color;
+pub mod crc32;
 pub mod log;
 pub mod u8;
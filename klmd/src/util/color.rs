@@ -11,6 +11,7 @@ use crate::util::u8::U8VecSerializable;
  * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
  */
 
+#[derive(Clone, PartialEq)]
 pub struct RGB{
     pub r: u8,
     pub g: u8,
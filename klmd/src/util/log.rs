@@ -9,24 +9,35 @@
  * @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
  */
 
-static LOGLVL: u8 = 0b11111000;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+//Mask of printed levels, changed by configuration
+static LOGLVL: AtomicU8 = AtomicU8::new(0b11111000);
 
 pub enum LogLevel {
     DEBUG,
     INFO,
     WARN,
     ERROR,
-    PANIC,
 }
 
 impl LogLevel {
+    pub fn from_name(name: &str) -> Option<LogLevel> {
+        match name.to_lowercase().as_str() {
+            "debug" => Some(LogLevel::DEBUG),
+            "info" => Some(LogLevel::INFO),
+            "warn" | "warning" => Some(LogLevel::WARN),
+            "error" => Some(LogLevel::ERROR),
+            _ => None,
+        }
+    }
+
     fn to_s(&self) -> &str {
         match *self {
             LogLevel::DEBUG => "D",
             LogLevel::INFO  => "I",
             LogLevel::WARN  => "W",
             LogLevel::ERROR => "E",
-            LogLevel::PANIC => "P",
         }
     }
 
@@ -36,13 +47,18 @@ impl LogLevel {
             LogLevel::INFO  => 0b01000000,
             LogLevel::WARN  => 0b00100000,
             LogLevel::ERROR => 0b00010000,
-            LogLevel::PANIC => 0b00001000,
         }
     }
 }
 
+//Prints messages of given level and all more severe ones
+pub fn set_level(level: LogLevel) {
+    let bit = level.to_u8();
+    LOGLVL.store(bit | (bit - 1), Ordering::Relaxed);
+}
+
 fn is_present(level: &LogLevel) -> bool{
-    if LOGLVL & level.to_u8() != 0 {
+    if LOGLVL.load(Ordering::Relaxed) & level.to_u8() != 0 {
         true
     } else {
         false
@@ -72,9 +88,5 @@ pub fn e(tag: &str, msg: &str){
     log_print(LogLevel::ERROR, tag, msg);
 }
 
-pub fn panic(tag: &str, msg: &str){
-    log_print(LogLevel::PANIC, tag, msg);
-    panic!("Program panicked because of module: {module}", module=tag);
-}
 
 
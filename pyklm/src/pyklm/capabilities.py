@@ -0,0 +1,72 @@
+# This file is part of pyklm project.
+#
+#  Copyright 2023 by Polar <toddot@protonmail.com>
+#
+#  Licensed under GNU General Public License 3.0 or later.
+#  Some rights reserved. See COPYING, AUTHORS.
+#
+# @license GPL-3.0+ <http://spdx.org/licenses/GPL-3.0+>
+
+from enum import Enum
+
+from pyklm.mode import KeyboardMode
+
+
+class KeyboardLayout(Enum):
+    """
+     Describes how keyboard lightning is split.
+
+     LAYOUT_ZONES = 0x0 keyboard has independently colored zones
+     LAYOUT_PER_KEY = 0x1 every key is colored separately
+    """
+    LAYOUT_ZONES = 0x00
+    LAYOUT_PER_KEY = 0x01
+
+
+class KLMCapabilities:
+    """
+     Stores keyboard capabilities reported by klmd
+    """
+
+    def __init__(self):
+        self.name = ""
+        self.vendor_id = 0
+        self.product_id = 0
+        self.modes = list()
+        self.brightness_range = (0, 0)
+        self.speed_range = (0, 0)
+        self.max_colors = 0
+        self.can_power_on = False
+        self.layout = KeyboardLayout.LAYOUT_ZONES
+        self.layout_dimensions = (0, 0)
+
+    def __repr__(self):
+        return f"<KLMCapabilities: {self.name} {self.vendor_id:04x}:{self.product_id:04x}>"
+
+    @classmethod
+    def from_bytes(cls, data: bytes):
+        """
+         Parses capabilities block from data of klmd response.
+
+         :param data: bytes: data of capabilities response
+         :return KLMCapabilities: parsed capabilities
+        """
+        capabilities = cls()
+        name_len = data[0]
+        ptr = 1
+        capabilities.name = bytes(data[ptr:ptr + name_len]).decode("utf-8", errors="replace")
+        ptr += name_len
+        capabilities.vendor_id = int.from_bytes(data[ptr:ptr + 2], "big")
+        capabilities.product_id = int.from_bytes(data[ptr + 2:ptr + 4], "big")
+        ptr += 4
+        n_modes = data[ptr]
+        ptr += 1
+        capabilities.modes = [KeyboardMode(mode) for mode in data[ptr:ptr + n_modes]]
+        ptr += n_modes
+        capabilities.brightness_range = (data[ptr], data[ptr + 1])
+        capabilities.speed_range = (data[ptr + 2], data[ptr + 3])
+        capabilities.max_colors = data[ptr + 4]
+        capabilities.can_power_on = data[ptr + 5] != 0
+        capabilities.layout = KeyboardLayout(data[ptr + 6])
+        capabilities.layout_dimensions = (data[ptr + 7], data[ptr + 8])
+        return capabilities
@@ -14,6 +14,7 @@ from enum import Enum
 from pyklm.rgb import RGB
 from pyklm.util import byteargs
 from pyklm.mode import KeyboardMode
+from pyklm.capabilities import KLMCapabilities
 
 
 class KLMError(Exception):
@@ -25,6 +26,7 @@ class KLMResultStatus(Enum):
     RESULT_ERROR = 0x1
     RESULT_BAD_REQUEST = 0x2
     RESULT_DATA = 0x3
+    RESULT_EVENT = 0x4
 
     @staticmethod
     @byteargs
@@ -37,6 +39,8 @@ class KLMResultStatus(Enum):
             return KLMResultStatus.RESULT_BAD_REQUEST
         elif byte == 0x3:
             return KLMResultStatus.RESULT_DATA
+        elif byte == 0x4:
+            return KLMResultStatus.RESULT_EVENT
         else:
             raise ValueError(f"Bad status code: {byte}")
 
@@ -57,7 +61,7 @@ class KLMResult:
     def receive_from(cls, sock):
         status_byte = sock.recv(1)[0]
         status = KLMResultStatus.from_byte(status_byte)
-        if status != KLMResultStatus.RESULT_DATA:
+        if status not in (KLMResultStatus.RESULT_DATA, KLMResultStatus.RESULT_EVENT):
             result = cls()
             result.status = status
             return result
@@ -66,19 +70,96 @@ class KLMResult:
             raise ValueError(f"Unexpected response size: {size_byte}")
         data = sock.recv(size_byte)
         result = cls()
-        result.status = KLMResultStatus.RESULT_DATA
+        result.status = status
         result.data = data
         return result
 
 
-class KLMConnection:
+class KLMKeyboardState:
     """
-     Stores data required to interact with klmd
+     Stores current keyboard state reported by klmd
     """
 
     def __init__(self):
+        self.mode = KeyboardMode.MODE_OFF
+        self.power = False
+        self.brightness = 0
+        self.speed = 0
+        self.sync_locked = False
+        self.colors = list()
+
+    def __repr__(self):
+        return f"<KLMKeyboardState: {self.mode}, power={self.power}, {len(self.colors)} colors>"
+
+    @classmethod
+    def from_bytes(cls, data: bytes):
+        """
+         Parses keyboard state from data of klmd response.
+
+         :param data: bytes: data of state response
+         :return KLMKeyboardState: parsed state
+        """
+        state = cls()
+        state.mode = KeyboardMode(data[0])
+        state.power = data[1] != 0
+        state.brightness = data[2]
+        state.speed = data[3]
+        state.sync_locked = data[4] != 0
+        state.colors = [RGB(data[6 + 3 * i], data[7 + 3 * i], data[8 + 3 * i]) for i in range(data[5])]
+        return state
+
+
+class KLMEventType(Enum):
+    EVENT_STATE_CHANGED = 0x0
+    EVENT_DEVICE_CONNECTED = 0x1
+    EVENT_DEVICE_REMOVED = 0x2
+
+
+class KLMEvent:
+    """
+     Stores event pushed by klmd to subscribed connection.
+     For state events payload is KLMKeyboardState, for connection
+     events it is KLMCapabilities, otherwise it is None.
+    """
+
+    def __init__(self):
+        self.type = KLMEventType.EVENT_STATE_CHANGED
+        self.payload = None
+
+    def __repr__(self):
+        return f"<KLMEvent: {self.type}, {self.payload}>"
+
+    @classmethod
+    def from_result(cls, result: KLMResult):
+        """
+         Parses event from klmd result.
+
+         :param result: KLMResult: result with event status
+         :return KLMEvent: parsed event
+        """
+        if result.status != KLMResultStatus.RESULT_EVENT:
+            raise KLMError(f"Expected event, got {result.status}")
+        event = cls()
+        event.type = KLMEventType(result.data[0])
+        if event.type == KLMEventType.EVENT_STATE_CHANGED:
+            event.payload = KLMKeyboardState.from_bytes(result.data[1:])
+        elif event.type == KLMEventType.EVENT_DEVICE_CONNECTED:
+            event.payload = KLMCapabilities.from_bytes(result.data[1:])
+        return event
+
+
+class KLMConnection:
+    """
+     Stores data required to interact with klmd.
+     With keep_alive connection to klmd is kept open between commits,
+     call close() when it is no longer needed.
+    """
+
+    def __init__(self, keep_alive: bool = False):
         self.staged = bytearray()
         self.size = 0
+        self.keep_alive = keep_alive
+        self.sock = None
 
     def set_color(self, color: RGB):
         """
@@ -144,6 +225,22 @@ class KLMConnection:
         self.staged += bytearray([0x09])
         self.size += 1
 
+    def get_capabilities(self):
+        """
+         Stages request of keyboard capabilities.
+         Use KLMCapabilities.from_bytes to parse result data.
+        """
+        self.staged += bytearray([0x0A])
+        self.size += 1
+
+    def get_state(self):
+        """
+         Stages request of current keyboard state.
+         Use KLMKeyboardState.from_bytes to parse result data.
+        """
+        self.staged += bytearray([0x0B])
+        self.size += 1
+
     def toggle(self):
         """
          Toggles power of keyboard.
@@ -157,20 +254,54 @@ class KLMConnection:
 
          :return: KLMResult: result of communication.
         """
-        if not os.path.exists("/var/run/klmd.sock"):
-            raise KLMError("No sock found. Is daemon running?")
         if self.size == 0:
             raise KLMError("No commands staged. If you have stage commands before this may be a bug.")
         if self.size > 255:
             raise KLMError(f"Size of requst {self.size} is too big. Try reducing amount of commands.")
-        sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
-        sock.connect("/var/run/klmd.sock")
+        sock = self.connect()
         sock.send(bytearray([self.size]))
         sock.send(self.staged)
         result = KLMResult.receive_from(sock)
-        sock.close()
+        if not self.keep_alive:
+            self.close()
         return result
 
+    def connect(self):
+        """
+         Opens connection to daemon, unless it is already open.
+
+         :return: socket: connected socket
+        """
+        if self.sock is None:
+            if not os.path.exists("/var/run/klmd.sock"):
+                raise KLMError("No sock found. Is daemon running?")
+            self.sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+            self.sock.connect("/var/run/klmd.sock")
+        return self.sock
+
+    def close(self):
+        """
+         Closes connection to daemon if it is open.
+        """
+        if self.sock is not None:
+            self.sock.close()
+            self.sock = None
+
+    def events(self):
+        """
+         Subscribes to klmd events. Connection can not be used for
+         sending commands afterwards.
+
+         :return: generator of KLMEvent, blocks until next event
+        """
+        sock = self.connect()
+        sock.send(bytearray([1, 0x0C]))
+        result = KLMResult.receive_from(sock)
+        if result.status != KLMResultStatus.RESULT_OK:
+            raise KLMError(f"Subscription failed: {result.status}")
+        while True:
+            yield KLMEvent.from_result(KLMResult.receive_from(sock))
+
     def reset(self):
         """
          Removes staged commands from connection.
@@ -0,0 +1,19 @@
+from pyklm.capabilities import KLMCapabilities, KeyboardLayout
+from pyklm.mode import KeyboardMode
+
+MS1563_CAPABILITIES = bytes([6]) + b"MS1563" + bytes([0x14, 0x62, 0x15, 0x63, 3, 1, 2, 3,
+                                                      0, 10, 0, 2, 7, 0, 0, 1, 0])
+
+def test_capabilities_from_bytes():
+    capabilities = KLMCapabilities.from_bytes(MS1563_CAPABILITIES)
+    assert capabilities.name == "MS1563"
+    assert capabilities.vendor_id == 0x1462
+    assert capabilities.product_id == 0x1563
+    assert capabilities.modes == [KeyboardMode.MODE_STEADY, KeyboardMode.MODE_BREATHING,
+                                  KeyboardMode.MODE_COLORSHIFT]
+    assert capabilities.brightness_range == (0, 10)
+    assert capabilities.speed_range == (0, 2)
+    assert capabilities.max_colors == 7
+    assert not capabilities.can_power_on
+    assert capabilities.layout == KeyboardLayout.LAYOUT_ZONES
+    assert capabilities.layout_dimensions == (1, 0)
@@ -0,0 +1,72 @@
+import socket
+
+from pyklm import connection
+from pyklm.connection import KLMConnection, KLMEventType, KLMResultStatus
+from pyklm.rgb import RGB
+
+
+class FakeSocket:
+    opened = []
+
+    def __init__(self, *args):
+        self.sent = bytearray()
+        self.closed = False
+        FakeSocket.opened.append(self)
+
+    def connect(self, path):
+        pass
+
+    def send(self, data):
+        self.sent += data
+
+    def recv(self, size):
+        return bytes([0x0])
+
+    def close(self):
+        self.closed = True
+
+
+def fake_socket(monkeypatch):
+    FakeSocket.opened = []
+    monkeypatch.setattr(socket, "socket", FakeSocket)
+    monkeypatch.setattr(connection.os.path, "exists", lambda path: True)
+
+
+def test_connection_is_closed_after_commit(monkeypatch):
+    fake_socket(monkeypatch)
+    klm = KLMConnection()
+    klm.toggle()
+    assert klm.commit().status == KLMResultStatus.RESULT_OK
+    klm.commit()
+    assert len(FakeSocket.opened) == 2
+    assert all(sock.closed for sock in FakeSocket.opened)
+
+
+def test_keep_alive_reuses_connection(monkeypatch):
+    fake_socket(monkeypatch)
+    klm = KLMConnection(keep_alive=True)
+    for value in range(3):
+        klm.reset()
+        klm.set_color(RGB(value, value, value))
+        assert klm.commit().status == KLMResultStatus.RESULT_OK
+    assert len(FakeSocket.opened) == 1
+    sock = FakeSocket.opened[0]
+    assert not sock.closed
+    assert sock.sent == bytearray([4, 0x01, 0, 0, 0, 4, 0x01, 1, 1, 1, 4, 0x01, 2, 2, 2])
+    klm.close()
+    assert sock.closed
+
+
+def test_events_are_parsed(monkeypatch):
+    fake_socket(monkeypatch)
+    responses = [bytes([0x0]), bytes([0x4]), bytes([10]), bytes([0x0, 2, 1, 7, 2, 0, 1, 1, 2, 3]),
+                 bytes([0x4]), bytes([1]), bytes([0x2])]
+    monkeypatch.setattr(FakeSocket, "recv", lambda self, size: responses.pop(0))
+    events = KLMConnection().events()
+    event = next(events)
+    assert event.type == KLMEventType.EVENT_STATE_CHANGED
+    assert event.payload.brightness == 7
+    event = next(events)
+    assert event.type == KLMEventType.EVENT_DEVICE_REMOVED
+    assert event.payload is None
+    assert FakeSocket.opened[0].sent == bytearray([1, 0x0C])
@@ -0,0 +1,11 @@
+from pyklm.connection import KLMKeyboardState
+from pyklm.mode import KeyboardMode
+
+def test_state_from_bytes():
+    state = KLMKeyboardState.from_bytes(bytes([0x02, 1, 7, 2, 0, 2, 1, 2, 3, 4, 5, 6]))
+    assert state.mode == KeyboardMode.MODE_BREATHING
+    assert state.power
+    assert state.brightness == 7
+    assert state.speed == 2
+    assert not state.sync_locked
+    assert [color.to_bytearray() for color in state.colors] == [bytearray([1, 2, 3]), bytearray([4, 5, 6])]